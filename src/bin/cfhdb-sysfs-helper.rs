@@ -0,0 +1,23 @@
+// Privileged half of the sysfs device operations, run through pkexec by libcfhdb::sysfs
+use libcfhdb::{
+    post_action::{run_pending_actions_helper, ADD_PENDING_ACTIONS, CLEAR_PENDING_ACTIONS},
+    sysfs::{parse_helper_args, run_sysfs_action, CfhdbSysfsError},
+};
+use std::process::exit;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // The pending post-actions are root-owned too, libcfhdb::post_action changes them through here
+    let result = match args.first().map(|x| x.as_str()) {
+        Some(ADD_PENDING_ACTIONS) | Some(CLEAR_PENDING_ACTIONS) => {
+            run_pending_actions_helper(&args).map_err(CfhdbSysfsError::from)
+        }
+        _ => parse_helper_args(&args)
+            .and_then(|(action, bus, busid, module)| run_sysfs_action(action, bus, busid, module)),
+    };
+    if let Err(e) = result {
+        println!("{}", serde_json::to_string(&e).unwrap());
+        eprintln!("cfhdb-sysfs-helper: {}", e);
+        exit(1);
+    }
+}
@@ -0,0 +1,101 @@
+use crate::output_func::*;
+use colored::Colorize;
+use libcfhdb::sysfs::*;
+use serde::Serialize;
+use users::get_current_username;
+
+#[derive(Serialize)]
+struct BlacklistedDevice {
+    bus: String,
+    busid: String,
+    entry: String,
+    // "stopped", "unbound" or "failed"
+    state: String,
+}
+
+// Run as root by cfhdb-unbind-blacklist.service at boot. The udev rules keep blacklisted devices
+// from binding from then on, this stops the ones a driver grabbed before udev ran.
+pub fn apply_blacklist(json: bool) {
+    if get_current_username().is_none_or(|x| x != "root") {
+        exit_with_translated_error("apply_blacklist_requires_root");
+    }
+    if is_blacklist_ignored() {
+        eprintln!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("blacklist_ignored")
+        );
+        if json {
+            print_json_result(Vec::<BlacklistedDevice>::new());
+        }
+        return;
+    }
+    if let Err(e) = write_blacklist_udev_rules() {
+        eprintln!("[{}] {}", t!("warn").bright_yellow(), e);
+    }
+    let mut devices = vec![];
+    for bus in [CfhdbSysfsBus::Pci, CfhdbSysfsBus::Usb] {
+        let blacklist = match resolve_blacklist(bus) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("[{}] {}", t!("warn").bright_yellow(), e);
+                continue;
+            }
+        };
+        for (entry, busids) in blacklist {
+            if busids.is_empty() {
+                eprintln!(
+                    "[{}] {}",
+                    t!("info").bright_green(),
+                    t!(
+                        "blacklist_entry_no_device",
+                        entry = entry,
+                        bus = bus.get_name()
+                    )
+                );
+                continue;
+            }
+            for busid in busids {
+                let state = match stop_blacklisted_device(bus, &busid) {
+                    Ok(true) => {
+                        eprintln!(
+                            "[{}] {}",
+                            t!("info").bright_green(),
+                            t!(
+                                "blacklist_device_stopped",
+                                bus = bus.get_name(),
+                                busid = busid
+                            )
+                        );
+                        "stopped"
+                    }
+                    Ok(false) => {
+                        eprintln!(
+                            "[{}] {}",
+                            t!("info").bright_green(),
+                            t!(
+                                "blacklist_device_unbound",
+                                bus = bus.get_name(),
+                                busid = busid
+                            )
+                        );
+                        "unbound"
+                    }
+                    Err(e) => {
+                        eprintln!("[{}] {}: {}", t!("warn").bright_yellow(), busid, e);
+                        "failed"
+                    }
+                };
+                devices.push(BlacklistedDevice {
+                    bus: bus.get_name().to_string(),
+                    busid,
+                    entry: entry.to_string(),
+                    state: state.to_string(),
+                });
+            }
+        }
+    }
+    if json {
+        print_json_result(devices);
+    }
+}
@@ -1,17 +1,48 @@
-use crate::{config::*, get_profile_url_config, run_in_lock_script};
+use crate::{
+    config::*, fix_cache_permissions, get_profile_cache_path, get_profile_url_config,
+    locale_func::get_profile_locale, output_func::*, profile_func::ProfileTransaction,
+};
 use cli_table::{Cell, Color, Style, Table};
 use colored::Colorize;
 use lazy_static::lazy_static;
-use libcfhdb::bt::*;
-use std::{collections::HashMap, fs, ops::Deref, path::Path, process::exit};
+use libcfhdb::{
+    bt::*,
+    profile::{get_local_profile_source, load_profile_source, parse_profile_database},
+    query::{filter_items, QueryExpr},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    ops::Deref,
+};
 
 lazy_static! {
     static ref BT_PROFILE_JSON_URL: String = get_profile_url_config().bt_json_url;
 }
 
+const BT_DEVICE_COLUMNS: [&str; 7] = [
+    "alias",
+    "name",
+    "address",
+    "paired",
+    "connected",
+    "trusted",
+    "blocked",
+];
+
 fn display_bt_devices_print_json(hashmap: HashMap<String, Vec<CfhdbBtDevice>>) {
-    let json_pretty = serde_json::to_string_pretty(&hashmap).unwrap();
-    println!("{}", json_pretty);
+    // Sorted by class so the output is stable between runs
+    print_json_result(hashmap.into_iter().collect::<BTreeMap<_, _>>());
+}
+fn display_bt_devices_print_records(mut devices: Vec<CfhdbBtDevice>) {
+    devices.sort_by(|a, b| (&a.class_id, &a.address).cmp(&(&b.class_id, &b.address)));
+    print_records(
+        devices
+            .iter()
+            .map(|x| serde_json::to_value(x).unwrap())
+            .collect(),
+        &BT_DEVICE_COLUMNS,
+    );
 }
 fn display_bt_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbBtDevice>>) {
     for (class, devices) in hashmap {
@@ -87,14 +118,7 @@ fn display_bt_profiles_print_cli_table(target: &CfhdbBtDevice) {
     let mut table_struct = vec![];
     let mut profiles = match target.available_profiles.0.lock().unwrap().clone() {
         Some(t) => t,
-        None => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_profiles_available_for_device")
-            );
-            exit(1);
-        }
+        None => exit_with_translated_error("no_profiles_available_for_device"),
     };
     profiles.sort_by_key(|k| k.priority);
     for profile in profiles {
@@ -107,7 +131,10 @@ fn display_bt_profiles_print_cli_table(target: &CfhdbBtDevice) {
                 Some((idx, _)) => profile.i18n_desc[..idx].to_string() + "...",
             }
             .cell(),
-            profile.license.cell(),
+            profile
+                .license
+                .unwrap_or_else(|| t!("unknown").to_string())
+                .cell(),
             profile.priority.cell(),
             if profile.experimental {
                 t!("enabled_yes").cell().foreground_color(Some(Color::Red))
@@ -141,186 +168,77 @@ fn display_bt_profiles_print_cli_table(target: &CfhdbBtDevice) {
     println!("{}\n{}", target.address.bright_green(), table_display);
 }
 
-pub fn display_bt_devices(json: bool) {
+pub fn display_bt_devices(json: bool, query: Option<&QueryExpr>, show_veiled: bool) {
     match CfhdbBtDevice::get_devices() {
         Some(devices) => {
-            let profiles = match get_bt_profiles_from_url() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
+            let profiles = get_visible_bt_profiles(show_veiled);
             for i in &devices {
                 CfhdbBtDevice::set_available_profiles(&profiles, &i);
             }
-            let hashmap = CfhdbBtDevice::create_class_hashmap(devices);
-            if json {
-                display_bt_devices_print_json(hashmap)
+            let devices = match query {
+                Some(query) => match filter_items(devices, query) {
+                    Ok(t) => t,
+                    Err(e) => exit_with_error("invalid_query", e),
+                },
+                None => devices,
+            };
+            if is_record_output() {
+                display_bt_devices_print_records(devices)
+            } else if json {
+                display_bt_devices_print_json(CfhdbBtDevice::create_class_hashmap(devices))
             } else {
-                display_bt_devices_print_cli_table(hashmap)
+                display_bt_devices_print_cli_table(CfhdbBtDevice::create_class_hashmap(devices))
             }
         }
-        None => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("failed_to_get_bt_devices")
-            );
-            exit(1);
-        }
+        None => exit_with_translated_error("failed_to_get_bt_devices"),
     }
 }
 
-pub fn display_bt_profiles(json: bool, target: &str) {
+pub fn display_bt_profiles(json: bool, target: &str, show_veiled: bool) {
     match CfhdbBtDevice::get_device_from_address(target) {
         Ok(target_device) => {
-            let profiles = match get_bt_profiles_from_url() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
+            let profiles = get_visible_bt_profiles(show_veiled);
             CfhdbBtDevice::set_available_profiles(&profiles, &target_device);
-            if json {
+            if json || is_record_output() {
                 let mut profile_arc =
                     match target_device.available_profiles.0.lock().unwrap().clone() {
                         Some(t) => t,
-                        None => {
-                            eprintln!(
-                                "[{}] {}",
-                                t!("error").red(),
-                                t!("no_profiles_available_for_device")
-                            );
-                            exit(1);
-                        }
+                        None => exit_with_translated_error("no_profiles_available_for_device"),
                     };
                 profile_arc.sort_by_key(|k| k.priority);
                 let profiles = profile_arc
                     .iter()
-                    .map(|s| s.codename.clone())
+                    .map(|s| profile_to_json(s.deref(), s.get_status()))
                     .collect::<Vec<_>>();
-                let json_pretty = serde_json::to_string_pretty(&profiles).unwrap();
-                println!("{}", json_pretty);
+                if is_record_output() {
+                    print_records(profiles, &PROFILE_COLUMNS);
+                } else {
+                    print_json_result(profiles);
+                }
             } else {
                 display_bt_profiles_print_cli_table(&target_device);
             }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_bt_device"),
     }
 }
 
-pub fn install_bt_profile(profile_codename: &str) {
-    let profiles = match get_bt_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    match CfhdbBtProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if target_profile.get_status() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_already_installed")
-                );
-            } else {
-                match target_profile.install_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_installer(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_installer(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
-            }
-        }
-        Err(_) => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
-            );
-            exit(1);
-        }
-    }
-}
-pub fn uninstall_bt_profile(profile_codename: &str) {
-    let profiles = match get_bt_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    match CfhdbBtProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if !target_profile.get_status() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_not_installed")
-                );
-            } else {
-                match target_profile.remove_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_uninstaller(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_uninstaller(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
-            }
-        }
-        Err(_) => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
-            );
-            exit(1);
-        }
-    }
+pub fn get_bt_profile_transaction(
+    profile_codename: &str,
+) -> Result<Option<ProfileTransaction>, std::io::Error> {
+    let profiles = get_bt_profiles_from_url()?;
+    Ok(
+        CfhdbBtProfile::get_profile_from_codename(profile_codename, profiles)
+            .ok()
+            .map(|x| ProfileTransaction {
+                installed: x.get_status(),
+                experimental: x.experimental,
+                removable: x.removable,
+                install_commands: get_install_commands(&x.packages, &x.install_script),
+                remove_commands: get_remove_commands(&x.packages, &x.remove_script),
+                post_actions: x.post_actions,
+            }),
+    )
 }
 
 pub fn pair_bt_device(target_sysfs_id: &str) {
@@ -328,16 +246,13 @@ pub fn pair_bt_device(target_sysfs_id: &str) {
         Ok(target_device) => {
             match target_device.pair_device() {
                 Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
+                Err(e) => exit_with_error("io_error", e),
             };
+            if is_json_mode() {
+                print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+            }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_bt_device"),
     }
 }
 pub fn connect_bt_device(target_sysfs_id: &str) {
@@ -345,16 +260,13 @@ pub fn connect_bt_device(target_sysfs_id: &str) {
         Ok(target_device) => {
             match target_device.connect_device() {
                 Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
+                Err(e) => exit_with_error("io_error", e),
             };
+            if is_json_mode() {
+                print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+            }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_bt_device"),
     }
 }
 
@@ -363,16 +275,13 @@ pub fn disconnect_bt_device(target_sysfs_id: &str) {
         Ok(target_device) => {
             match target_device.disconnect_device() {
                 Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
+                Err(e) => exit_with_error("io_error", e),
             };
+            if is_json_mode() {
+                print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+            }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_bt_device"),
     }
 }
 pub fn block_bt_device(target_sysfs_id: &str) {
@@ -380,16 +289,13 @@ pub fn block_bt_device(target_sysfs_id: &str) {
         Ok(target_device) => {
             match target_device.block_device() {
                 Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
+                Err(e) => exit_with_error("io_error", e),
             };
+            if is_json_mode() {
+                print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+            }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_bt_device"),
     }
 }
 
@@ -398,16 +304,13 @@ pub fn unblock_bt_device(target_sysfs_id: &str) {
         Ok(target_device) => {
             match target_device.unblock_device() {
                 Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
+                Err(e) => exit_with_error("io_error", e),
             };
+            if is_json_mode() {
+                print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+            }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_bt_device"),
     }
 }
 
@@ -416,16 +319,13 @@ pub fn trust_bt_device(target_sysfs_id: &str) {
         Ok(target_device) => {
             match target_device.trust_device() {
                 Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
+                Err(e) => exit_with_error("io_error", e),
             };
+            if is_json_mode() {
+                print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+            }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_bt_device"),
     }
 }
 pub fn untrust_bt_device(target_sysfs_id: &str) {
@@ -433,22 +333,19 @@ pub fn untrust_bt_device(target_sysfs_id: &str) {
         Ok(target_device) => {
             match target_device.untrust_device() {
                 Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
+                Err(e) => exit_with_error("io_error", e),
             };
+            if is_json_mode() {
+                print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+            }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_bt_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_bt_device"),
     }
 }
 
-fn get_bt_profiles_from_url() -> Result<Vec<CfhdbBtProfile>, std::io::Error> {
-    let cached_db_path = Path::new("/var/cache/cfhdb/bt.json");
-    println!(
+fn download_bt_profile_database() -> Result<String, std::io::Error> {
+    let cached_db_path = &get_profile_cache_path("bt");
+    eprintln!(
         "[{}] {}",
         t!("info").bright_green(),
         t!("bt_download_starting")
@@ -459,24 +356,25 @@ fn get_bt_profiles_from_url() -> Result<Vec<CfhdbBtProfile>, std::io::Error> {
         .unwrap();
     let data = match client.get(BT_PROFILE_JSON_URL.clone()).send() {
         Ok(t) => {
-            println!(
+            eprintln!(
                 "[{}] {}",
                 t!("info").bright_green(),
                 t!("bt_download_successful")
             );
             let cache = t.text().unwrap();
+            fix_cache_permissions();
             let _ = fs::File::create(cached_db_path);
             let _ = fs::write(cached_db_path, &cache);
             cache
         }
         Err(_) => {
-            println!(
+            eprintln!(
                 "[{}] {}",
                 t!("warn").bright_yellow(),
                 t!("bt_download_failed")
             );
             if cached_db_path.exists() {
-                println!(
+                eprintln!(
                     "[{}] {}",
                     t!("info").bright_green(),
                     t!("bt_download_cache_found")
@@ -495,174 +393,31 @@ fn get_bt_profiles_from_url() -> Result<Vec<CfhdbBtProfile>, std::io::Error> {
             }
         }
     };
-    let mut profiles_array = vec![];
-    let res: serde_json::Value = serde_json::from_str(&data).expect("Unable to parse");
-    if let serde_json::Value::Array(profiles) = &res["profiles"] {
-        for profile in profiles {
-            let codename = profile["codename"].as_str().unwrap_or_default().to_string();
-            let i18n_desc =
-                match profile[format!("i18n_desc[{}]", rust_i18n::locale().to_string())].as_str() {
-                    Some(t) => {
-                        if !t.is_empty() {
-                            t.to_string()
-                        } else {
-                            profile["i18n_desc"]
-                                .as_str()
-                                .unwrap_or_default()
-                                .to_string()
-                        }
-                    }
-                    None => profile["i18n_desc"]
-                        .as_str()
-                        .unwrap_or_default()
-                        .to_string(),
-                };
-            let icon_name = profile["icon_name"]
-                .as_str()
-                .unwrap_or("package-x-generic")
-                .to_string();
-            let license = profile["license"]
-                .as_str()
-                .unwrap_or(&t!("unknown"))
-                .to_string();
+    Ok(data)
+}
 
-            let class_ids: Vec<String> = match profile["class_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let bt_names: Vec<String> = match profile["bt_names"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let modalias_vendor_ids: Vec<String> = match profile["modalias_vendor_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let modalias_device_ids: Vec<String> = match profile["modalias_device_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let modalias_product_ids: Vec<String> = match profile["modalias_product_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let blacklisted_class_ids: Vec<String> =
-                match profile["blacklisted_class_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-            };
-            let blacklisted_bt_names: Vec<String> = match profile["blacklisted_bt_names"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let blacklisted_modalias_vendor_ids: Vec<String> =
-                match profile["blacklisted_modalias_vendor_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let blacklisted_modalias_device_ids: Vec<String> =
-                match profile["blacklisted_modalias_device_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let blacklisted_modalias_product_ids: Vec<String> =
-                match profile["blacklisted_modalias_product_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let packages: Option<Vec<String>> = match profile["packages"].as_str() {
-                Some(_) => None,
-                None => Some(
-                    profile["packages"]
-                        .as_array()
-                        .expect("invalid_bt_profile_json")
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                ),
-            };
-            let check_script = profile["check_script"]
-                .as_str()
-                .unwrap_or("false")
-                .to_string();
-            let install_script_value = profile["install_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let install_script = match install_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(install_script_value),
-            };
-            let remove_script_value = profile["remove_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let remove_script = match remove_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(remove_script_value),
-            };
-            let experimental = profile["experimental"].as_bool().unwrap_or_default();
-            let removable = profile["removable"].as_bool().unwrap_or_default();
-            let veiled = profile["veiled"].as_bool().unwrap_or_default();
-            let priority = profile["priority"].as_i64().unwrap_or_default();
-            // Parse into the Struct
-            let profile_struct = CfhdbBtProfile {
-                codename,
-                i18n_desc,
-                icon_name,
-                license,
-                class_ids,
-                bt_names,
-                modalias_vendor_ids,
-                modalias_device_ids,
-                modalias_product_ids,
-                blacklisted_class_ids,
-                blacklisted_bt_names,
-                blacklisted_modalias_vendor_ids,
-                blacklisted_modalias_device_ids,
-                blacklisted_modalias_product_ids,
-                packages,
-                check_script,
-                install_script,
-                remove_script,
-                experimental,
-                removable,
-                veiled,
-                priority: priority as i32,
-            };
-            profiles_array.push(profile_struct);
-            profiles_array.sort_by_key(|x| x.priority);
-        }
+// Veiled profiles are left out of matching unless asked for
+pub fn get_visible_bt_profiles(show_veiled: bool) -> Vec<CfhdbBtProfile> {
+    let mut profiles = match get_bt_profiles_from_url() {
+        Ok(t) => t,
+        Err(e) => exit_with_error("io_error", e),
+    };
+    if !show_veiled {
+        profiles.retain(|x| !x.veiled);
     }
+    profiles
+}
+
+pub fn get_bt_profiles_from_url() -> Result<Vec<CfhdbBtProfile>, std::io::Error> {
+    let profile_values = match get_local_profile_source(&BT_PROFILE_JSON_URL) {
+        Some(local_path) => load_profile_source(&local_path)?,
+        None => parse_profile_database(&download_bt_profile_database()?)?,
+    };
+    let locale = get_profile_locale();
+    let mut profiles_array: Vec<CfhdbBtProfile> = profile_values
+        .iter()
+        .map(|profile| CfhdbBtProfile::from_json(profile, &locale))
+        .collect();
+    profiles_array.sort_by_key(|x| x.priority);
     Ok(profiles_array)
 }
@@ -0,0 +1,710 @@
+use crate::{
+    locale_func::get_profile_locale,
+    output_func::{OutputFormat, JSON_ERROR_CODES, JSON_OUTPUT_VERSION},
+};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use clap_complete::{
+    engine::{ArgValueCandidates, CompletionCandidate},
+    env::Shells,
+};
+use libcfhdb::{
+    bt::CfhdbBtDevice,
+    profile::{get_i18n_desc, parse_profile_database},
+    query::{QueryExpr, QueryOperator},
+};
+use std::{fs, io, path::Path};
+
+const COMPLETION_SHELLS: [&str; 3] = ["bash", "zsh", "fish"];
+
+// Pre-subcommand flags, kept as aliases of the subcommand they map to
+const LEGACY_ACTIONS: &[(&str, &str, &[&str])] = &[
+    ("-cl", "--check-locales", &["locales", "check"]),
+    ("-cp", "--compile-profiles", &["profiles", "compile"]),
+    ("-rp", "--recommend", &["recommend"]),
+    ("-ai", "--auto-install", &["auto-install"]),
+    ("-er", "--export-report", &["report", "export"]),
+    ("-ir", "--import-report", &["report", "import"]),
+    ("-dr", "--diff", &["report", "diff"]),
+    ("-pa", "--pending", &["pending"]),
+    ("-ls", "--list-stopped", &["stopped"]),
+    ("-ab", "--apply-blacklist", &["apply-blacklist"]),
+    ("-mf", "--missing-firmware", &["firmware"]),
+    ("-m", "--monitor", &["monitor"]),
+    // PCI arguments
+    ("-lpd", "--list-pci-devices", &["pci", "list"]),
+    ("-lpp", "--list-pci-profiles", &["pci", "profiles"]),
+    ("-ipp", "--install-pci-profile", &["pci", "install"]),
+    ("-upp", "--uninstall-pci-profile", &["pci", "uninstall"]),
+    ("-epd", "--enable-pci-device", &["pci", "enable"]),
+    ("-dpd", "--disable-pci-device", &["pci", "disable"]),
+    ("-sspd", "--start-pci-device", &["pci", "start"]),
+    ("-srpd", "--stop-pci-device", &["pci", "stop"]),
+    ("-bpd", "--bind-pci-device", &["pci", "bind"]),
+    ("-lpdr", "--list-pci-drivers", &["pci", "drivers"]),
+    ("-lig", "--list-iommu-groups", &["pci", "iommu"]),
+    ("-vb", "--vfio-bind", &["pci", "vfio-bind"]),
+    ("-lpw", "--list-pci-power", &["pci", "power"]),
+    ("-rpm", "--runtime-pm", &["pci", "runtime-pm"]),
+    ("-rspd", "--reset-pci-device", &["pci", "reset"]),
+    ("-rmpd", "--remove-pci-device", &["pci", "remove"]),
+    ("-rsb", "--rescan-pci-bus", &["pci", "rescan"]),
+    ("-lsv", "--list-sriov", &["pci", "sriov"]),
+    ("-svf", "--set-vfs", &["pci", "set-vfs"]),
+    // USB arguments
+    ("-lud", "--list-usb-devices", &["usb", "list"]),
+    ("-lup", "--list-usb-profiles", &["usb", "profiles"]),
+    ("-iup", "--install-usb-profile", &["usb", "install"]),
+    ("-uup", "--uninstall-usb-profile", &["usb", "uninstall"]),
+    ("-eud", "--enable-usb-device", &["usb", "enable"]),
+    ("-dud", "--disable-usb-device", &["usb", "disable"]),
+    ("-ssud", "--start-usb-device", &["usb", "start"]),
+    ("-srud", "--stop-usb-device", &["usb", "stop"]),
+    ("-ludr", "--list-usb-drivers", &["usb", "drivers"]),
+    // DMI arguments
+    ("-ldi", "--list-dmi-info", &["dmi", "info"]),
+    ("-ldp", "--list-dmi-profiles", &["dmi", "profiles"]),
+    ("-idp", "--install-dmi-profile", &["dmi", "install"]),
+    ("-udp", "--uninstall-dmi-profile", &["dmi", "uninstall"]),
+    // BT arguments
+    ("-lbd", "--list-bt-devices", &["bt", "list"]),
+    ("-lbp", "--list-bt-profiles", &["bt", "profiles"]),
+    ("-ibp", "--install-bt-profile", &["bt", "install"]),
+    ("-ubp", "--uninstall-bt-profile", &["bt", "uninstall"]),
+    ("-pbd", "--pair-bt-device", &["bt", "pair"]),
+    ("-cbd", "--connect-bt-device", &["bt", "connect"]),
+    ("-dbd", "--disconnect-bt-device", &["bt", "disconnect"]),
+    ("-tbd", "--trust-bt-device", &["bt", "trust"]),
+    ("-utbd", "--untrust-bt-device", &["bt", "untrust"]),
+    ("-bbd", "--block-bt-device", &["bt", "block"]),
+    ("-ubbd", "--unblock-bt-device", &["bt", "unblock"]),
+];
+
+// Rewrites "cfhdb -lpp 0000:01:00.0" into "cfhdb pci profiles 0000:01:00.0", clap then validates the rest
+pub fn translate_legacy_args(args: Vec<String>) -> Result<Vec<String>, String> {
+    let mut action: Option<&[&str]> = None;
+    let mut remaining_arguments = vec![];
+    for arg in args.iter().skip(1) {
+        match LEGACY_ACTIONS
+            .iter()
+            .find(|(short, long, _)| arg == short || arg == long)
+        {
+            Some((_, _, subcommand)) => {
+                if action.is_some() {
+                    return Err(arg.clone());
+                }
+                action = Some(subcommand);
+            }
+            None => remaining_arguments.push(arg.clone()),
+        }
+    }
+    match action {
+        Some(subcommand) => {
+            let mut translated = args[..1].to_vec();
+            translated.extend(subcommand.iter().map(|x| x.to_string()));
+            translated.extend(remaining_arguments);
+            Ok(translated)
+        }
+        None => Ok(args),
+    }
+}
+
+fn get_sysfs_device_candidates(bus: &str) -> Vec<CompletionCandidate> {
+    let mut candidates = vec![];
+    if let Ok(entries) = fs::read_dir(format!("/sys/bus/{}/devices", bus)) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Skip USB interfaces ("3-1.2:1.0") and root hubs ("usb3")
+            if bus == "usb" && (name.contains(':') || !name.contains('-')) {
+                continue;
+            }
+            candidates.push(CompletionCandidate::new(name));
+        }
+    }
+    candidates.sort_by(|a, b| a.get_value().cmp(b.get_value()));
+    candidates
+}
+
+fn get_bt_device_candidates() -> Vec<CompletionCandidate> {
+    CfhdbBtDevice::get_devices()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|x| CompletionCandidate::new(x.address).help(Some(x.alias.into())))
+        .collect()
+}
+
+// Completion must not hit the network, codenames come from the last cached database
+fn get_profile_candidates(bus: &str) -> Vec<CompletionCandidate> {
+    let data = match fs::read_to_string(format!("/var/cache/cfhdb/{}.json", bus)) {
+        Ok(t) => t,
+        Err(_) => return vec![],
+    };
+    let locale = get_profile_locale();
+    parse_profile_database(&data)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|profile| {
+            profile["codename"].as_str().map(|codename| {
+                CompletionCandidate::new(codename)
+                    .help(Some(get_i18n_desc(profile, &locale).into()))
+            })
+        })
+        .collect()
+}
+
+fn device_arg(bus: &'static str) -> Arg {
+    let arg = Arg::new("device").required(true);
+    match bus {
+        "bt" => arg
+            .value_name("ADDRESS")
+            .help(t!("help_msg_arg_address").to_string())
+            .add(ArgValueCandidates::new(get_bt_device_candidates)),
+        _ => arg
+            .value_name("BUSID")
+            .help(t!("help_msg_arg_busid").to_string())
+            .add(ArgValueCandidates::new(move || {
+                get_sysfs_device_candidates(bus)
+            })),
+    }
+}
+
+fn profile_arg(bus: &'static str) -> Arg {
+    Arg::new("profile")
+        .required(true)
+        .value_name("CODENAME")
+        .help(t!("help_msg_arg_codename").to_string())
+        .add(ArgValueCandidates::new(move || get_profile_candidates(bus)))
+}
+
+fn flag_arg(name: &'static str, help_key: &str) -> Arg {
+    Arg::new(name)
+        .long(name)
+        .action(ArgAction::SetTrue)
+        .help(t!(help_key).to_string())
+}
+
+fn show_veiled_arg() -> Arg {
+    flag_arg("show-veiled", "help_msg_arg_show_veiled")
+}
+
+fn install_command(bus: &'static str) -> Command {
+    verb(
+        "install",
+        &format!("help_msg_action_install_{}_profile", bus),
+    )
+    .arg(profile_arg(bus))
+    .arg(flag_arg(
+        "allow-experimental",
+        "help_msg_arg_allow_experimental",
+    ))
+}
+
+fn uninstall_command(bus: &'static str) -> Command {
+    verb(
+        "uninstall",
+        &format!("help_msg_action_uninstall_{}_profile", bus),
+    )
+    .arg(profile_arg(bus))
+    .arg(flag_arg("force", "help_msg_arg_force_uninstall"))
+}
+
+fn value_arg(name: &'static str, value_name: &'static str, help_key: &str) -> Arg {
+    Arg::new(name)
+        .long(name)
+        .value_name(value_name)
+        .help(t!(help_key).to_string())
+}
+
+// BT devices have no kernel driver or sysfs state, so they only get the id and profile filters
+fn list_command(bus: &'static str) -> Command {
+    let mut command = verb("list", &format!("help_msg_action_list_{}_devices", bus))
+        .arg(value_arg("class", "CLASS", "help_msg_filter_class"))
+        .arg(value_arg("vendor", "VENDOR", "help_msg_filter_vendor"))
+        .arg(flag_arg("has-profiles", "help_msg_filter_has_profiles"))
+        .arg(value_arg("query", "EXPRESSION", "help_msg_filter_query"))
+        .arg(show_veiled_arg());
+    if bus != "bt" {
+        command = command
+            .arg(value_arg("driver", "DRIVER", "help_msg_filter_driver"))
+            .arg(flag_arg("unbound", "help_msg_filter_unbound"))
+            .arg(flag_arg("disabled", "help_msg_filter_disabled"))
+            .arg(flag_arg("stopped", "help_msg_filter_stopped"));
+    }
+    command
+}
+
+// Turns the filter flags of a list command into one query, None when no filter was given
+pub fn get_device_query(bus: &str, matches: &ArgMatches) -> Result<Option<QueryExpr>, io::Error> {
+    let (class_field, vendor_field) = match bus {
+        "pci" => ("class_id", "vendor_id"),
+        "usb" => ("class_code", "vendor_id"),
+        _ => ("class_id", "modalias_vendor_id"),
+    };
+    let get_value = |name: &str| matches.get_one::<String>(name).cloned();
+    let get_flag = |name: &str| matches.try_get_one::<bool>(name).ok().flatten() == Some(&true);
+    let mut exprs = vec![];
+    if let Some(class) = get_value("class") {
+        exprs.push(QueryExpr::Compare(
+            class_field.to_string(),
+            QueryOperator::Prefix,
+            class,
+        ));
+    }
+    if let Some(vendor) = get_value("vendor") {
+        exprs.push(QueryExpr::Compare(
+            vendor_field.to_string(),
+            QueryOperator::Equal,
+            vendor,
+        ));
+    }
+    if get_flag("has-profiles") {
+        exprs.push(QueryExpr::Truthy("available_profiles".to_string()));
+    }
+    if let Some(driver) = matches.try_get_one::<String>("driver").ok().flatten() {
+        exprs.push(QueryExpr::Compare(
+            "kernel_driver".to_string(),
+            QueryOperator::Equal,
+            driver.clone(),
+        ));
+    }
+    if get_flag("unbound") {
+        exprs.push(QueryExpr::Compare(
+            "kernel_driver".to_string(),
+            QueryOperator::Equal,
+            "Unknown".to_string(),
+        ));
+    }
+    if get_flag("disabled") {
+        exprs.push(QueryExpr::Not(Box::new(QueryExpr::Truthy(
+            "enabled".to_string(),
+        ))));
+    }
+    // Devices stopped by cfhdb have started false, other unbound devices have no started state
+    if get_flag("stopped") {
+        exprs.push(QueryExpr::Compare(
+            "started".to_string(),
+            QueryOperator::Equal,
+            "false".to_string(),
+        ));
+    }
+    if let Some(query) = get_value("query") {
+        exprs.push(QueryExpr::parse(&query)?);
+    }
+    Ok(exprs.into_iter().reduce(QueryExpr::and))
+}
+
+// "pci=./profiles/pci" replaces the configured pci profile source for one run
+fn parse_profile_source(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((bus, source)) if ["pci", "usb", "dmi", "bt"].contains(&bus) && !source.is_empty() => {
+            Ok((bus.to_string(), source.to_string()))
+        }
+        _ => Err(t!("invalid_profile_source", value = value).to_string()),
+    }
+}
+
+fn report_file_arg(name: &'static str) -> Arg {
+    Arg::new(name)
+        .required(true)
+        .value_name("FILE")
+        .value_hint(clap::ValueHint::FilePath)
+}
+
+fn verb(name: &'static str, about_key: &str) -> Command {
+    Command::new(name).about(t!(about_key).to_string())
+}
+
+fn sysfs_bus_command(bus: &'static str) -> Command {
+    Command::new(bus)
+        .about(t!(format!("help_msg_cmd_{}", bus)).to_string())
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(list_command(bus))
+        .subcommand(
+            verb(
+                "profiles",
+                &format!("help_msg_action_list_compatible_{}_profiles", bus),
+            )
+            .arg(device_arg(bus))
+            .arg(show_veiled_arg()),
+        )
+        .subcommand(install_command(bus))
+        .subcommand(uninstall_command(bus))
+        .subcommand(
+            verb("enable", &format!("help_msg_action_enable_{}_device", bus)).arg(device_arg(bus)),
+        )
+        .subcommand(
+            verb(
+                "disable",
+                &format!("help_msg_action_disable_{}_device", bus),
+            )
+            .arg(device_arg(bus)),
+        )
+        .subcommand(
+            verb("start", &format!("help_msg_action_start_{}_device", bus)).arg(device_arg(bus)),
+        )
+        .subcommand(
+            verb("stop", &format!("help_msg_action_stop_{}_device", bus)).arg(device_arg(bus)),
+        )
+        .subcommand(
+            verb("drivers", &format!("help_msg_action_list_{}_drivers", bus)).arg(device_arg(bus)),
+        )
+}
+
+// driver_override, IOMMU groups, power controls, resets and SR-IOV only exist for PCI devices
+fn pci_command() -> Command {
+    sysfs_bus_command("pci")
+        .subcommand(
+            verb("bind", "help_msg_action_bind_pci_device")
+                .arg(device_arg("pci"))
+                .arg(
+                    Arg::new("driver")
+                        .value_name("DRIVER")
+                        .help(t!("help_msg_arg_driver").to_string()),
+                )
+                .arg(flag_arg("persistent", "help_msg_arg_persistent_driver")),
+        )
+        .subcommand(verb("iommu", "help_msg_action_list_iommu_groups"))
+        .subcommand(
+            verb("vfio-bind", "help_msg_action_vfio_bind")
+                .arg(device_arg("pci"))
+                .arg(flag_arg("persistent", "help_msg_arg_persistent_vfio")),
+        )
+        .subcommand(
+            verb("power", "help_msg_action_list_pci_power").arg(device_arg("pci").required(false)),
+        )
+        .subcommand(
+            verb("runtime-pm", "help_msg_action_runtime_pm")
+                .arg(device_arg("pci"))
+                .arg(
+                    Arg::new("control")
+                        .value_name("CONTROL")
+                        .required(true)
+                        .value_parser(["auto", "on"])
+                        .help(t!("help_msg_arg_runtime_pm_control").to_string()),
+                )
+                .arg(flag_arg("persistent", "help_msg_arg_persistent_runtime_pm")),
+        )
+        .subcommand(
+            verb("reset", "help_msg_action_reset_pci_device")
+                .arg(device_arg("pci"))
+                .arg(value_arg("method", "METHOD", "help_msg_arg_reset_method")),
+        )
+        .subcommand(verb("remove", "help_msg_action_remove_pci_device").arg(device_arg("pci")))
+        .subcommand(
+            verb("rescan", "help_msg_action_rescan_pci_bus").arg(
+                Arg::new("bridge")
+                    .value_name("BUSID")
+                    .help(t!("help_msg_arg_rescan_bridge").to_string())
+                    .add(ArgValueCandidates::new(|| {
+                        get_sysfs_device_candidates("pci")
+                    })),
+            ),
+        )
+        .subcommand(verb("sriov", "help_msg_action_list_sriov"))
+        .subcommand(
+            verb("set-vfs", "help_msg_action_set_vfs")
+                .arg(device_arg("pci"))
+                .arg(
+                    Arg::new("count")
+                        .value_name("COUNT")
+                        .required(true)
+                        .value_parser(clap::value_parser!(u32))
+                        .help(t!("help_msg_arg_vf_count").to_string()),
+                ),
+        )
+}
+
+fn dmi_command() -> Command {
+    Command::new("dmi")
+        .about(t!("help_msg_cmd_dmi").to_string())
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(verb("info", "help_msg_action_list_dmi_info").arg(show_veiled_arg()))
+        .subcommand(
+            verb("profiles", "help_msg_action_list_compatible_dmi_profiles").arg(show_veiled_arg()),
+        )
+        .subcommand(install_command("dmi"))
+        .subcommand(uninstall_command("dmi"))
+}
+
+fn bt_command() -> Command {
+    let mut command = Command::new("bt")
+        .about(t!("help_msg_cmd_bt").to_string())
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(list_command("bt"))
+        .subcommand(
+            verb("profiles", "help_msg_action_list_compatible_bt_profiles")
+                .arg(device_arg("bt"))
+                .arg(show_veiled_arg()),
+        )
+        .subcommand(install_command("bt"))
+        .subcommand(uninstall_command("bt"));
+    for name in [
+        "pair",
+        "connect",
+        "disconnect",
+        "trust",
+        "untrust",
+        "block",
+        "unblock",
+    ] {
+        command = command.subcommand(
+            verb(name, &format!("help_msg_action_{}_bt_device", name)).arg(device_arg("bt")),
+        );
+    }
+    command
+}
+
+pub fn build_command() -> Command {
+    Command::new("cfhdb")
+        .about(t!("help_msg_title0").to_string())
+        .version(crate::VERSION)
+        .disable_version_flag(true)
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .after_long_help(
+            t!(
+                "help_msg_json_output",
+                version = JSON_OUTPUT_VERSION,
+                codes = JSON_ERROR_CODES.join(", ")
+            )
+            .to_string(),
+        )
+        .arg(
+            Arg::new("version")
+                .short('v')
+                .long("version")
+                .action(ArgAction::Version)
+                .help(t!("help_msg_action_version").to_string()),
+        )
+        .arg(
+            Arg::new("json")
+                .short('j')
+                .long("json")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help(t!("help_msg_action_json").to_string())
+                .long_help(t!("help_msg_action_json_long").to_string()),
+        )
+        .arg(
+            Arg::new("format")
+                .short('f')
+                .long("format")
+                .global(true)
+                .value_name("FORMAT")
+                .value_parser(OutputFormat::NAMES)
+                .default_value("table")
+                .help(t!("help_msg_action_format").to_string()),
+        )
+        .arg(
+            Arg::new("columns")
+                .long("columns")
+                .global(true)
+                .value_name("COLUMNS")
+                .value_delimiter(',')
+                .help(t!("help_msg_action_columns").to_string()),
+        )
+        // Read by get_requested_locale before the command is built, so the help text is translated
+        .arg(
+            Arg::new("locale")
+                .short('l')
+                .long("locale")
+                .global(true)
+                .value_name("LOCALE")
+                .help(t!("help_msg_action_locale").to_string()),
+        )
+        .subcommand(pci_command())
+        .subcommand(sysfs_bus_command("usb"))
+        .subcommand(dmi_command())
+        .subcommand(bt_command())
+        .subcommand(
+            Command::new("profiles")
+                .about(t!("help_msg_cmd_profiles").to_string())
+                .subcommand_required(true)
+                .arg_required_else_help(true)
+                .subcommand(
+                    verb("compile", "help_msg_action_compile_profiles")
+                        .arg(
+                            Arg::new("source")
+                                .required(true)
+                                .value_name("SOURCE")
+                                .value_hint(clap::ValueHint::AnyPath),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .value_name("OUTPUT")
+                                .value_hint(clap::ValueHint::FilePath),
+                        ),
+                ),
+        )
+        .subcommand(Command::new("recommend").about(t!("help_msg_action_recommend").to_string()))
+        .subcommand(
+            Command::new("auto-install").about(t!("help_msg_action_auto_install").to_string()),
+        )
+        .subcommand(
+            Command::new("pending")
+                .about(t!("help_msg_action_pending").to_string())
+                .arg(flag_arg("clear", "help_msg_arg_clear_pending")),
+        )
+        .subcommand(Command::new("stopped").about(t!("help_msg_action_stopped").to_string()))
+        .subcommand(
+            Command::new("apply-blacklist")
+                .about(t!("help_msg_action_apply_blacklist").to_string()),
+        )
+        .subcommand(Command::new("firmware").about(t!("help_msg_action_firmware").to_string()))
+        .subcommand(
+            Command::new("dbus-service").about(t!("help_msg_action_dbus_service").to_string()),
+        )
+        .subcommand(
+            Command::new("monitor")
+                .about(t!("help_msg_action_monitor").to_string())
+                .arg(flag_arg("notify", "help_msg_arg_monitor_notify")),
+        )
+        .subcommand(
+            Command::new("report")
+                .about(t!("help_msg_cmd_report").to_string())
+                .subcommand_required(true)
+                .arg_required_else_help(true)
+                .subcommand(
+                    verb("export", "help_msg_action_export_report").arg(report_file_arg("file")),
+                )
+                .subcommand(
+                    verb("import", "help_msg_action_import_report")
+                        .arg(report_file_arg("file"))
+                        .arg(
+                            Arg::new("profile-source")
+                                .long("profile-source")
+                                .value_name("BUS=SOURCE")
+                                .action(ArgAction::Append)
+                                .value_parser(parse_profile_source)
+                                .help(t!("help_msg_arg_profile_source").to_string()),
+                        ),
+                )
+                .subcommand(
+                    verb("diff", "help_msg_action_diff_reports")
+                        .arg(report_file_arg("before"))
+                        .arg(report_file_arg("after")),
+                ),
+        )
+        .subcommand(
+            Command::new("locales")
+                .about(t!("help_msg_cmd_locales").to_string())
+                .subcommand_required(true)
+                .arg_required_else_help(true)
+                .subcommand(
+                    verb("check", "help_msg_action_check_locales").arg(
+                        Arg::new("directory")
+                            .value_name("DIR")
+                            .required(true)
+                            .value_hint(clap::ValueHint::DirPath),
+                    ),
+                ),
+        )
+        .subcommand(
+            Command::new("completions")
+                .about(t!("help_msg_action_completions").to_string())
+                .arg(
+                    Arg::new("shell")
+                        .required(true)
+                        .value_name("SHELL")
+                        .value_parser(COMPLETION_SHELLS),
+                ),
+        )
+        .subcommand(
+            Command::new("man")
+                .about(t!("help_msg_action_man").to_string())
+                .arg(
+                    Arg::new("directory")
+                        .required(true)
+                        .value_name("DIR")
+                        .value_hint(clap::ValueHint::DirPath),
+                ),
+        )
+}
+
+// The registration script calls back into cfhdb, so bus IDs and codenames are completed live
+pub fn write_completions(shell: &str, buf: &mut dyn io::Write) -> Result<(), io::Error> {
+    match Shells::builtins().completer(shell) {
+        Some(completer) => completer.write_registration("COMPLETE", "cfhdb", "cfhdb", "cfhdb", buf),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported shell: {}", shell),
+        )),
+    }
+}
+
+fn without_help(command: Command) -> Command {
+    command
+        .disable_help_flag(true)
+        .disable_help_subcommand(true)
+        .disable_version_flag(true)
+        .mut_subcommands(without_help)
+}
+
+// Parsed with the untranslated command, which knows where option values and "--" are.
+// Help is left out so "-l de --help" still finds the locale, errors are reported by the real parse.
+pub fn get_requested_locale(args: &[String]) -> Option<String> {
+    let args = translate_legacy_args(args.to_vec()).unwrap_or_else(|_| args.to_vec());
+    let matches = without_help(build_command())
+        .ignore_errors(true)
+        .try_get_matches_from(args)
+        .ok()?;
+    let mut matches = &matches;
+    loop {
+        if let Some(t) = matches.get_one::<String>("locale") {
+            return Some(t.clone());
+        }
+        matches = matches.subcommand()?.1;
+    }
+}
+
+pub fn generate_man_pages(directory: &Path) -> Result<(), io::Error> {
+    fs::create_dir_all(directory)?;
+    clap_mangen::generate_to(build_command(), directory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libcfhdb::sysfs::get_started_state;
+
+    fn get_list_query(args: &[&str]) -> QueryExpr {
+        let matches = build_command().get_matches_from(args);
+        let (_, bus_matches) = matches.subcommand().unwrap();
+        let (_, list_matches) = bus_matches.subcommand().unwrap();
+        get_device_query("pci", list_matches).unwrap().unwrap()
+    }
+
+    #[test]
+    fn stopped_filter_matches_stopped_device() {
+        let query = get_list_query(&["cfhdb", "pci", "list", "--stopped"]);
+        // pci stop unbinds the driver, the recorded state is what marks the device as stopped
+        let stopped = serde_json::json!({ "started": get_started_state(None, true) });
+        let unbound = serde_json::json!({ "started": get_started_state(None, false) });
+        let started = serde_json::json!({ "started": get_started_state(Some(true), false) });
+        assert!(query.matches_value(&stopped));
+        assert!(!query.matches_value(&unbound));
+        assert!(!query.matches_value(&started));
+    }
+
+    fn get_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn requested_locale_is_parsed_by_clap() {
+        let locale = |args: &[&str]| get_requested_locale(&get_args(args));
+        assert_eq!(
+            locale(&["cfhdb", "-l", "de", "--help"]).as_deref(),
+            Some("de")
+        );
+        assert_eq!(
+            locale(&["cfhdb", "pci", "list", "--locale=de_AT"]).as_deref(),
+            Some("de_AT")
+        );
+        // Neither a value of another option nor anything after "--" is a locale
+        assert_eq!(locale(&["cfhdb", "pci", "list", "--query", "-l"]), None);
+        assert_eq!(locale(&["cfhdb", "locales", "check", "--", "-l"]), None);
+    }
+}
@@ -5,3 +5,44 @@ pub fn distro_packages_installer(package_list: &str) -> String {
 pub fn distro_packages_uninstaller(package_list: &str) -> String {
     format!("sudo dnf remove {}", package_list)
 }
+
+// Packages go first, so the profile's script can rely on them
+fn get_transaction_commands(
+    packages_command: Option<String>,
+    script: &Option<String>,
+) -> Option<String> {
+    let commands: Vec<String> = packages_command.into_iter().chain(script.clone()).collect();
+    if commands.is_empty() {
+        None
+    } else {
+        Some(commands.join("\n"))
+    }
+}
+
+pub fn get_install_commands(
+    packages: &Option<Vec<String>>,
+    install_script: &Option<String>,
+) -> Option<String> {
+    get_transaction_commands(
+        packages.as_ref().map(|x| distro_packages_installer(&x.join(" "))),
+        install_script,
+    )
+}
+
+pub fn get_remove_commands(
+    packages: &Option<Vec<String>>,
+    remove_script: &Option<String>,
+) -> Option<String> {
+    get_transaction_commands(
+        packages.as_ref().map(|x| distro_packages_uninstaller(&x.join(" "))),
+        remove_script,
+    )
+}
+
+// Prints the names of the repository packages that ship files matching the glob
+pub fn distro_packages_providing(file_glob: &str) -> String {
+    format!(
+        "dnf repoquery --quiet --queryformat '%{{name}}\\n' --whatprovides '{}'",
+        file_glob
+    )
+}
@@ -0,0 +1,446 @@
+use crate::{
+    bt_func::{get_bt_profile_transaction, get_bt_profiles_from_url},
+    dmi_func::{get_dmi_profile_transaction, get_dmi_profiles_from_url},
+    output_func::exit_with_error,
+    pci_func::{get_pci_profile_transaction, get_pci_profiles_from_url},
+    pending_func::record_post_actions,
+    profile_func::{get_transaction_refusal, GetProfileTransaction, ProfileTransaction},
+    usb_func::{get_usb_profile_transaction, get_usb_profiles_from_url},
+};
+use colored::Colorize;
+use dbus::{
+    arg::Variant,
+    channel::{MatchingReceiver, Sender},
+    message::MatchRule,
+    nonblock::{Proxy, SyncConnection},
+    Message,
+};
+use dbus_crossroads::{Crossroads, IfaceBuilder, MethodErr};
+use libcfhdb::{bt::*, dmi::*, pci::*, post_action::CfhdbPostAction, usb::*};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+pub const DBUS_NAME: &str = "org.nobaraproject.Cfhdb1";
+const DBUS_PATH: &str = "/org/nobaraproject/Cfhdb1";
+// Every method checks its own action, e.g. "org.nobaraproject.cfhdb1.install-profile"
+const POLKIT_ACTION_PREFIX: &str = "org.nobaraproject.cfhdb1";
+const POLKIT_ALLOW_USER_INTERACTION: u32 = 1;
+// Long enough for the user to type their password
+const POLKIT_TIMEOUT: Duration = Duration::from_secs(300);
+const ERROR_NOT_AUTHORIZED: &str = "org.nobaraproject.Cfhdb1.Error.NotAuthorized";
+const ERROR_NOT_FOUND: &str = "org.nobaraproject.Cfhdb1.Error.NotFound";
+const ERROR_REFUSED: &str = "org.nobaraproject.Cfhdb1.Error.Refused";
+
+// Transactions must not overlap, so the service does one piece of work at a time
+static WORK_LOCK: Mutex<()> = Mutex::new(());
+
+// Same fields as the "install" and "uninstall" commands print with --json
+#[derive(Serialize)]
+struct ProfileTransactionResult {
+    codename: String,
+    installed: bool,
+    changed: bool,
+    post_actions: Vec<CfhdbPostAction>,
+}
+
+fn not_found(message: impl ToString) -> MethodErr {
+    (ERROR_NOT_FOUND, message.to_string()).into()
+}
+
+fn to_json<T: Serialize>(data: &T) -> Result<String, MethodErr> {
+    serde_json::to_string(data).map_err(|e| MethodErr::failed(&e))
+}
+
+async fn run_blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, MethodErr> + Send + 'static,
+) -> Result<T, MethodErr> {
+    tokio::task::spawn_blocking(move || {
+        let _lock = WORK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        work()
+    })
+    .await
+    .map_err(|e| MethodErr::failed(&e))?
+}
+
+async fn check_authorization(
+    conn: &Arc<SyncConnection>,
+    sender: &str,
+    action: &str,
+) -> Result<(), MethodErr> {
+    let proxy = Proxy::new(
+        "org.freedesktop.PolicyKit1",
+        "/org/freedesktop/PolicyKit1/Authority",
+        POLKIT_TIMEOUT,
+        conn.clone(),
+    );
+    let subject = (
+        "system-bus-name",
+        HashMap::from([("name", Variant(sender.to_string()))]),
+    );
+    let reply = proxy.method_call(
+        "org.freedesktop.PolicyKit1.Authority",
+        "CheckAuthorization",
+        (
+            subject,
+            format!("{}.{}", POLKIT_ACTION_PREFIX, action),
+            HashMap::<String, String>::new(),
+            POLKIT_ALLOW_USER_INTERACTION,
+            String::new(),
+        ),
+    );
+    let ((authorized, _, _),): ((bool, bool, HashMap<String, String>),) = reply.await?;
+    if authorized {
+        Ok(())
+    } else {
+        Err((ERROR_NOT_AUTHORIZED, t!("dbus_not_authorized").to_string()).into())
+    }
+}
+
+fn get_device_list(bus: &str) -> Result<String, MethodErr> {
+    let failed = |key: &str| MethodErr::failed(&t!(key));
+    match bus {
+        "pci" => {
+            let devices =
+                CfhdbPciDevice::get_devices().ok_or_else(|| failed("failed_to_get_pci_devices"))?;
+            let mut profiles = get_pci_profiles_from_url().map_err(|e| MethodErr::failed(&e))?;
+            profiles.retain(|x| !x.veiled);
+            for i in &devices {
+                CfhdbPciDevice::set_available_profiles(&profiles, i);
+            }
+            to_json(&devices)
+        }
+        "usb" => {
+            let devices =
+                CfhdbUsbDevice::get_devices().ok_or_else(|| failed("failed_to_get_usb_devices"))?;
+            let mut profiles = get_usb_profiles_from_url().map_err(|e| MethodErr::failed(&e))?;
+            profiles.retain(|x| !x.veiled);
+            for i in &devices {
+                CfhdbUsbDevice::set_available_profiles(&profiles, i);
+            }
+            to_json(&devices)
+        }
+        "bt" => {
+            let devices =
+                CfhdbBtDevice::get_devices().ok_or_else(|| failed("failed_to_get_bt_devices"))?;
+            let mut profiles = get_bt_profiles_from_url().map_err(|e| MethodErr::failed(&e))?;
+            profiles.retain(|x| !x.veiled);
+            for i in &devices {
+                CfhdbBtDevice::set_available_profiles(&profiles, i);
+            }
+            to_json(&devices)
+        }
+        // The system itself is the only dmi "device"
+        "dmi" => {
+            let dmi_info = CfhdbDmiInfo::get_dmi();
+            let mut profiles = get_dmi_profiles_from_url().map_err(|e| MethodErr::failed(&e))?;
+            profiles.retain(|x| !x.veiled);
+            CfhdbDmiInfo::set_available_profiles(&profiles, &dmi_info);
+            to_json(&dmi_info)
+        }
+        _ => Err(MethodErr::invalid_arg(bus)),
+    }
+}
+
+fn get_profiles_json<T: Serialize>(
+    profiles: Option<Vec<Arc<T>>>,
+    get_status: fn(&T) -> bool,
+) -> Result<String, MethodErr> {
+    let mut values = vec![];
+    for profile in profiles.unwrap_or_default() {
+        let mut value = serde_json::to_value(&*profile).map_err(|e| MethodErr::failed(&e))?;
+        value["installed"] = get_status(&profile).into();
+        values.push(value);
+    }
+    to_json(&values)
+}
+
+// The dmi profiles always match against this system, device is ignored for them
+fn get_profile_list(bus: &str, device: &str) -> Result<String, MethodErr> {
+    match bus {
+        "pci" => {
+            let device = CfhdbPciDevice::get_device_from_busid(device)
+                .map_err(|_| not_found(t!("no_matching_pci_device")))?;
+            let mut profiles = get_pci_profiles_from_url().map_err(|e| MethodErr::failed(&e))?;
+            profiles.retain(|x| !x.veiled);
+            CfhdbPciDevice::set_available_profiles(&profiles, &device);
+            let matched = device.available_profiles.0.lock().unwrap().clone();
+            get_profiles_json(matched, CfhdbPciProfile::get_status)
+        }
+        "usb" => {
+            let device = CfhdbUsbDevice::get_device_from_busid(device)
+                .map_err(|_| not_found(t!("no_matching_usb_device")))?;
+            let mut profiles = get_usb_profiles_from_url().map_err(|e| MethodErr::failed(&e))?;
+            profiles.retain(|x| !x.veiled);
+            CfhdbUsbDevice::set_available_profiles(&profiles, &device);
+            let matched = device.available_profiles.0.lock().unwrap().clone();
+            get_profiles_json(matched, CfhdbUsbProfile::get_status)
+        }
+        "bt" => {
+            let device = CfhdbBtDevice::get_device_from_address(device)
+                .map_err(|_| not_found(t!("no_matching_bt_device")))?;
+            let mut profiles = get_bt_profiles_from_url().map_err(|e| MethodErr::failed(&e))?;
+            profiles.retain(|x| !x.veiled);
+            CfhdbBtDevice::set_available_profiles(&profiles, &device);
+            let matched = device.available_profiles.0.lock().unwrap().clone();
+            get_profiles_json(matched, CfhdbBtProfile::get_status)
+        }
+        "dmi" => {
+            let dmi_info = CfhdbDmiInfo::get_dmi();
+            let mut profiles = get_dmi_profiles_from_url().map_err(|e| MethodErr::failed(&e))?;
+            profiles.retain(|x| !x.veiled);
+            CfhdbDmiInfo::set_available_profiles(&profiles, &dmi_info);
+            let matched = dmi_info.available_profiles.0.lock().unwrap().clone();
+            get_profiles_json(matched, CfhdbDmiProfile::get_status)
+        }
+        _ => Err(MethodErr::invalid_arg(bus)),
+    }
+}
+
+fn get_profile_transaction(bus: &str, codename: &str) -> Result<ProfileTransaction, MethodErr> {
+    let get_transaction: GetProfileTransaction = match bus {
+        "pci" => get_pci_profile_transaction,
+        "usb" => get_usb_profile_transaction,
+        "dmi" => get_dmi_profile_transaction,
+        "bt" => get_bt_profile_transaction,
+        _ => return Err(MethodErr::invalid_arg(bus)),
+    };
+    get_transaction(codename)
+        .map_err(|e| MethodErr::failed(&e))?
+        .ok_or_else(|| not_found(t!("no_matching_profile_codename")))
+}
+
+// The service already runs as root, so the commands run directly instead of through run_in_lock_script.
+// Every output line is passed on as a ProfileProgress signal.
+fn run_transaction_commands(
+    conn: &SyncConnection,
+    bus: &str,
+    codename: &str,
+    commands: &str,
+) -> Result<(), io::Error> {
+    let reader = duct::cmd!("bash", "-c", format!("set -e\n{}", commands))
+        .stdin_null()
+        .stderr_to_stdout()
+        .reader()?;
+    for line in BufReader::new(&reader).lines() {
+        let line = line?;
+        eprintln!("{}", line);
+        let _ = conn.send(
+            Message::signal(
+                &DBUS_PATH.into(),
+                &DBUS_NAME.into(),
+                &"ProfileProgress".into(),
+            )
+            .append3(bus, codename, line),
+        );
+    }
+    Ok(())
+}
+
+fn run_profile_transaction(
+    conn: &SyncConnection,
+    bus: &str,
+    codename: &str,
+    install: bool,
+    allow: bool,
+) -> Result<String, MethodErr> {
+    let transaction = get_profile_transaction(bus, codename)?;
+    let changed = transaction.installed != install;
+    if let Some((_, message)) = get_transaction_refusal(codename, &transaction, install, allow) {
+        return Err((ERROR_REFUSED, message).into());
+    }
+    let commands = if install {
+        transaction.install_commands
+    } else {
+        transaction.remove_commands
+    };
+    if changed {
+        let result = match commands {
+            Some(t) => run_transaction_commands(conn, bus, codename, &t),
+            None => Ok(()),
+        };
+        let _ = conn.send(
+            Message::signal(
+                &DBUS_PATH.into(),
+                &DBUS_NAME.into(),
+                &"ProfileFinished".into(),
+            )
+            .append3(bus, codename, result.is_ok()),
+        );
+        result.map_err(|e| MethodErr::failed(&e))?;
+        record_post_actions(bus, codename, &transaction.post_actions);
+    }
+    to_json(&ProfileTransactionResult {
+        codename: codename.to_string(),
+        installed: install,
+        changed,
+        post_actions: if changed {
+            transaction.post_actions
+        } else {
+            vec![]
+        },
+    })
+}
+
+fn run_device_action(bus: &str, device: &str, action: &str) -> Result<(), MethodErr> {
+    let result = match bus {
+        "pci" => {
+            let device = CfhdbPciDevice::get_device_from_busid(device)
+                .map_err(|_| not_found(t!("no_matching_pci_device")))?;
+            match action {
+                "start-device" => device.start_device(),
+                "stop-device" => device.stop_device(),
+                "enable-device" => device.enable_device(),
+                _ => device.disable_device(),
+            }
+        }
+        "usb" => {
+            let device = CfhdbUsbDevice::get_device_from_busid(device)
+                .map_err(|_| not_found(t!("no_matching_usb_device")))?;
+            match action {
+                "start-device" => device.start_device(),
+                "stop-device" => device.stop_device(),
+                "enable-device" => device.enable_device(),
+                _ => device.disable_device(),
+            }
+        }
+        _ => return Err(MethodErr::invalid_arg(bus)),
+    };
+    result.map_err(|e| MethodErr::failed(&e))
+}
+
+fn register_interface(b: &mut IfaceBuilder<()>, conn: Arc<SyncConnection>) {
+    b.signal::<(String, String, String), _>("ProfileProgress", ("bus", "codename", "line"));
+    b.signal::<(String, String, bool), _>("ProfileFinished", ("bus", "codename", "success"));
+    // The service loads profiles and runs their check scripts as root, so even listing is checked.
+    // The policy allows active local sessions without a prompt.
+    let list_conn = conn.clone();
+    b.method_with_cr_async(
+        "ListDevices",
+        ("bus",),
+        ("devices",),
+        move |mut ctx, _, (bus,): (String,)| {
+            let conn = list_conn.clone();
+            let sender = ctx.message().sender().map(|x| x.to_string());
+            async move {
+                let result = async {
+                    check_authorization(&conn, &sender.unwrap_or_default(), "list-devices").await?;
+                    run_blocking(move || get_device_list(&bus)).await
+                };
+                ctx.reply(result.await.map(|x| (x,)))
+            }
+        },
+    );
+    let list_conn = conn.clone();
+    b.method_with_cr_async(
+        "ListProfiles",
+        ("bus", "device"),
+        ("profiles",),
+        move |mut ctx, _, (bus, device): (String, String)| {
+            let conn = list_conn.clone();
+            let sender = ctx.message().sender().map(|x| x.to_string());
+            async move {
+                let result = async {
+                    check_authorization(&conn, &sender.unwrap_or_default(), "list-profiles")
+                        .await?;
+                    run_blocking(move || get_profile_list(&bus, &device)).await
+                };
+                ctx.reply(result.await.map(|x| (x,)))
+            }
+        },
+    );
+    // allow is allow_experimental for installs and force for removals
+    for (method, action, install) in [
+        ("InstallProfile", "install-profile", true),
+        ("UninstallProfile", "uninstall-profile", false),
+    ] {
+        let conn = conn.clone();
+        b.method_with_cr_async(
+            method,
+            ("bus", "codename", "allow"),
+            ("result",),
+            move |mut ctx, _, (bus, codename, allow): (String, String, bool)| {
+                let conn = conn.clone();
+                let sender = ctx.message().sender().map(|x| x.to_string());
+                async move {
+                    let result = async {
+                        check_authorization(&conn, &sender.unwrap_or_default(), action).await?;
+                        run_blocking(move || {
+                            run_profile_transaction(&conn, &bus, &codename, install, allow)
+                        })
+                        .await
+                    };
+                    ctx.reply(result.await.map(|x| (x,)))
+                }
+            },
+        );
+    }
+    for (method, action) in [
+        ("StartDevice", "start-device"),
+        ("StopDevice", "stop-device"),
+        ("EnableDevice", "enable-device"),
+        ("DisableDevice", "disable-device"),
+    ] {
+        let conn = conn.clone();
+        b.method_with_cr_async(
+            method,
+            ("bus", "device"),
+            (),
+            move |mut ctx, _, (bus, device): (String, String)| {
+                let conn = conn.clone();
+                let sender = ctx.message().sender().map(|x| x.to_string());
+                async move {
+                    let result = async {
+                        check_authorization(&conn, &sender.unwrap_or_default(), action).await?;
+                        run_blocking(move || run_device_action(&bus, &device, action)).await
+                    };
+                    ctx.reply(result.await)
+                }
+            },
+        );
+    }
+}
+
+async fn serve() -> Result<(), Box<dyn std::error::Error>> {
+    let (resource, conn) = dbus_tokio::connection::new_system_sync()?;
+    let _handle = tokio::spawn(async {
+        let e = resource.await;
+        exit_with_error("dbus_error", e);
+    });
+    let mut cr = Crossroads::new();
+    cr.set_async_support(Some((
+        conn.clone(),
+        Box::new(|x| {
+            tokio::spawn(x);
+        }),
+    )));
+    let interface_conn = conn.clone();
+    let token = cr.register(DBUS_NAME, move |b| register_interface(b, interface_conn));
+    cr.insert(DBUS_PATH, &[token], ());
+    conn.request_name(DBUS_NAME, false, true, false).await?;
+    conn.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |msg, conn| {
+            let _ = cr.handle_message(msg, conn);
+            true
+        }),
+    );
+    eprintln!(
+        "[{}] {}",
+        t!("info").bright_green(),
+        t!("dbus_service_started", name = DBUS_NAME)
+    );
+    futures::future::pending::<()>().await;
+    Ok(())
+}
+
+pub fn run_dbus_service() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    if let Err(e) = rt.block_on(serve()) {
+        exit_with_error("dbus_error", e);
+    }
+}
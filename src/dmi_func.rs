@@ -1,17 +1,31 @@
-use crate::{config::*, get_profile_url_config, run_in_lock_script};
+use crate::{
+    config::*, fix_cache_permissions, get_profile_cache_path, get_profile_url_config,
+    locale_func::get_profile_locale, output_func::*, profile_func::ProfileTransaction,
+};
 use cli_table::{Cell, Color, Style, Table};
 use colored::Colorize;
 use lazy_static::lazy_static;
-use libcfhdb::dmi::*;
-use std::{fs, ops::Deref, path::Path, process::exit, sync::Arc};
+use libcfhdb::{
+    dmi::*,
+    profile::{get_local_profile_source, load_profile_source, parse_profile_database},
+};
+use std::{fs, ops::Deref};
 
 lazy_static! {
     static ref DMI_PROFILE_JSON_URL: String = get_profile_url_config().dmi_json_url;
 }
 
+const DMI_INFO_COLUMNS: [&str; 6] = [
+    "sys_vendor",
+    "product_family",
+    "product_name",
+    "board_name",
+    "bios_vendor",
+    "bios_version",
+];
+
 fn display_dmi_info_print_json(dmi: &CfhdbDmiInfo) {
-    let json_pretty = serde_json::to_string_pretty(&dmi).unwrap();
-    println!("{}", json_pretty);
+    print_json_result(dmi);
 }
 fn display_dmi_info_print_cli_table(dmi: &CfhdbDmiInfo) {
     let mut table_struct = vec![];
@@ -63,14 +77,7 @@ fn display_dmi_profiles_print_cli_table(target: &CfhdbDmiInfo) {
     let mut table_struct = vec![];
     let mut profiles = match target.available_profiles.0.lock().unwrap().clone() {
         Some(t) => t,
-        None => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_profiles_available_for_info")
-            );
-            exit(1);
-        }
+        None => exit_with_translated_error("no_profiles_available_for_info"),
     };
     profiles.sort_by_key(|k| k.priority);
     for profile in profiles {
@@ -83,7 +90,10 @@ fn display_dmi_profiles_print_cli_table(target: &CfhdbDmiInfo) {
                 Some((idx, _)) => profile.i18n_desc[..idx].to_string() + "...",
             }
             .cell(),
-            profile.license.cell(),
+            profile
+                .license
+                .unwrap_or_else(|| t!("unknown").to_string())
+                .cell(),
             profile.priority.cell(),
             if profile.experimental {
                 t!("enabled_yes").cell().foreground_color(Some(Color::Red))
@@ -117,169 +127,64 @@ fn display_dmi_profiles_print_cli_table(target: &CfhdbDmiInfo) {
     println!("{}", table_display);
 }
 
-pub fn display_dmi_info(json: bool) {
+pub fn display_dmi_info(json: bool, show_veiled: bool) {
     let dmi = CfhdbDmiInfo::get_dmi();
-    let profiles = match get_dmi_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
+    let profiles = get_visible_dmi_profiles(show_veiled);
     CfhdbDmiInfo::set_available_profiles(&profiles, &dmi);
-    if json {
+    if is_record_output() {
+        print_records(vec![serde_json::to_value(&dmi).unwrap()], &DMI_INFO_COLUMNS)
+    } else if json {
         display_dmi_info_print_json(&dmi)
     } else {
         display_dmi_info_print_cli_table(&dmi)
     }
 }
 
-pub fn display_dmi_profiles(json: bool) {
+pub fn display_dmi_profiles(json: bool, show_veiled: bool) {
     let dmi_info = CfhdbDmiInfo::get_dmi();
-    let profiles = match get_dmi_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
+    let profiles = get_visible_dmi_profiles(show_veiled);
     CfhdbDmiInfo::set_available_profiles(&profiles, &dmi_info);
-    if json {
+    if json || is_record_output() {
         let mut profile_arc = match dmi_info.available_profiles.0.lock().unwrap().clone() {
             Some(t) => t,
-            None => {
-                eprintln!(
-                    "[{}] {}",
-                    t!("error").red(),
-                    t!("no_profiles_available_for_info")
-                );
-                exit(1);
-            }
+            None => exit_with_translated_error("no_profiles_available_for_info"),
         };
         profile_arc.sort_by_key(|k| k.priority);
         let profiles = profile_arc
             .iter()
-            .map(|s| s.codename.clone())
+            .map(|s| profile_to_json(s.deref(), s.get_status()))
             .collect::<Vec<_>>();
-        let json_pretty = serde_json::to_string_pretty(&profiles).unwrap();
-        println!("{}", json_pretty);
+        if is_record_output() {
+            print_records(profiles, &PROFILE_COLUMNS);
+        } else {
+            print_json_result(profiles);
+        }
     } else {
         display_dmi_profiles_print_cli_table(&dmi_info);
     }
 }
 
-pub fn install_dmi_profile(profile_codename: &str) {
-    let profiles = match get_dmi_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    match CfhdbDmiProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if target_profile.get_status() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_already_installed")
-                );
-            } else {
-                match target_profile.install_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_installer(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_installer(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
-            }
-        }
-        Err(_) => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
-            );
-            exit(1);
-        }
-    }
-}
-pub fn uninstall_dmi_profile(profile_codename: &str) {
-    let profiles = match get_dmi_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    match CfhdbDmiProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if !target_profile.get_status() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_not_installed")
-                );
-            } else {
-                match target_profile.remove_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_uninstaller(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_uninstaller(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
-            }
-        }
-        Err(_) => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
-            );
-            exit(1);
-        }
-    }
+pub fn get_dmi_profile_transaction(
+    profile_codename: &str,
+) -> Result<Option<ProfileTransaction>, std::io::Error> {
+    let profiles = get_dmi_profiles_from_url()?;
+    Ok(
+        CfhdbDmiProfile::get_profile_from_codename(profile_codename, profiles)
+            .ok()
+            .map(|x| ProfileTransaction {
+                installed: x.get_status(),
+                experimental: x.experimental,
+                removable: x.removable,
+                install_commands: get_install_commands(&x.packages, &x.install_script),
+                remove_commands: get_remove_commands(&x.packages, &x.remove_script),
+                post_actions: x.post_actions,
+            }),
+    )
 }
 
-fn get_dmi_profiles_from_url() -> Result<Vec<CfhdbDmiProfile>, std::io::Error> {
-    let cached_db_path = Path::new("/var/cache/cfhdb/dmi.json");
-    println!(
+fn download_dmi_profile_database() -> Result<String, std::io::Error> {
+    let cached_db_path = &get_profile_cache_path("dmi");
+    eprintln!(
         "[{}] {}",
         t!("info").bright_green(),
         t!("dmi_download_starting")
@@ -290,24 +195,25 @@ fn get_dmi_profiles_from_url() -> Result<Vec<CfhdbDmiProfile>, std::io::Error> {
         .unwrap();
     let data = match client.get(DMI_PROFILE_JSON_URL.clone()).send() {
         Ok(t) => {
-            println!(
+            eprintln!(
                 "[{}] {}",
                 t!("info").bright_green(),
                 t!("dmi_download_successful")
             );
             let cache = t.text().unwrap();
+            fix_cache_permissions();
             let _ = fs::File::create(cached_db_path);
             let _ = fs::write(cached_db_path, &cache);
             cache
         }
         Err(_) => {
-            println!(
+            eprintln!(
                 "[{}] {}",
                 t!("warn").bright_yellow(),
                 t!("dmi_download_failed")
             );
             if cached_db_path.exists() {
-                println!(
+                eprintln!(
                     "[{}] {}",
                     t!("info").bright_green(),
                     t!("dmi_download_cache_found")
@@ -326,133 +232,31 @@ fn get_dmi_profiles_from_url() -> Result<Vec<CfhdbDmiProfile>, std::io::Error> {
             }
         }
     };
-    let mut profiles_array = vec![];
-    let res: serde_json::Value = serde_json::from_str(&data).expect("Unable to parse");
-    if let serde_json::Value::Array(profiles) = &res["profiles"] {
-        for profile in profiles {
-            let codename = profile["codename"].as_str().unwrap_or_default().to_string();
-            let i18n_desc =
-                match profile[format!("i18n_desc[{}]", rust_i18n::locale().to_string())].as_str() {
-                    Some(t) => {
-                        if !t.is_empty() {
-                            t.to_string()
-                        } else {
-                            profile["i18n_desc"]
-                                .as_str()
-                                .unwrap_or_default()
-                                .to_string()
-                        }
-                    }
-                    None => profile["i18n_desc"]
-                        .as_str()
-                        .unwrap_or_default()
-                        .to_string(),
-                };
-            let icon_name = profile["icon_name"]
-                .as_str()
-                .unwrap_or("package-x-generic")
-                .to_string();
-            let license = profile["license"]
-                .as_str()
-                .unwrap_or(&t!("unknown"))
-                .to_string();
-            let mut dmi_strings_vec = Vec::new();
-            for dmi_string in [
-                "bios_vendors",
-                "board_asset_tags",
-                "board_names",
-                "board_vendors",
-                "product_families",
-                "product_names",
-                "product_skus",
-                "sys_vendors",
-                "blacklisted_bios_vendors",
-                "blacklisted_board_asset_tags",
-                "blacklisted_board_names",
-                "blacklisted_board_vendors",
-                "blacklisted_product_families",
-                "blacklisted_product_names",
-                "blacklisted_product_skus",
-                "blacklisted_sys_vendors",
-            ] {
-                let final_map: Vec<String> = match profile[dmi_string].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-                dmi_strings_vec.push(Arc::new(final_map));
-            }
-            let packages: Option<Vec<String>> = match profile["packages"].as_str() {
-                Some(_) => None,
-                None => Some(
-                    profile["packages"]
-                        .as_array()
-                        .expect("invalid_dmi_profile_json")
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                ),
-            };
-            let check_script = profile["check_script"]
-                .as_str()
-                .unwrap_or("false")
-                .to_string();
-            let install_script_value = profile["install_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let install_script = match install_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(install_script_value),
-            };
-            let remove_script_value = profile["remove_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let remove_script = match remove_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(remove_script_value),
-            };
-            let experimental = profile["experimental"].as_bool().unwrap_or_default();
-            let removable = profile["removable"].as_bool().unwrap_or_default();
-            let veiled = profile["veiled"].as_bool().unwrap_or_default();
-            let priority = profile["priority"].as_i64().unwrap_or_default();
-            // Parse into the Struct
-            let profile_struct = CfhdbDmiProfile {
-                codename,
-                i18n_desc,
-                icon_name,
-                license,
-                bios_vendors: dmi_strings_vec[0].to_vec(),
-                board_asset_tags: dmi_strings_vec[1].to_vec(),
-                board_names: dmi_strings_vec[2].to_vec(),
-                board_vendors: dmi_strings_vec[3].to_vec(),
-                product_families: dmi_strings_vec[4].to_vec(),
-                product_names: dmi_strings_vec[5].to_vec(),
-                product_skus: dmi_strings_vec[6].to_vec(),
-                sys_vendors: dmi_strings_vec[7].to_vec(),
-                blacklisted_bios_vendors: dmi_strings_vec[8].to_vec(),
-                blacklisted_board_asset_tags: dmi_strings_vec[9].to_vec(),
-                blacklisted_board_names: dmi_strings_vec[10].to_vec(),
-                blacklisted_board_vendors: dmi_strings_vec[11].to_vec(),
-                blacklisted_product_families: dmi_strings_vec[12].to_vec(),
-                blacklisted_product_names: dmi_strings_vec[13].to_vec(),
-                blacklisted_product_skus: dmi_strings_vec[14].to_vec(),
-                blacklisted_sys_vendors: dmi_strings_vec[15].to_vec(),
-                packages,
-                check_script,
-                install_script,
-                remove_script,
-                experimental,
-                removable,
-                veiled,
-                priority: priority as i32,
-            };
-            profiles_array.push(profile_struct);
-            profiles_array.sort_by_key(|x| x.priority);
-        }
+    Ok(data)
+}
+
+// Veiled profiles are left out of matching unless asked for
+fn get_visible_dmi_profiles(show_veiled: bool) -> Vec<CfhdbDmiProfile> {
+    let mut profiles = match get_dmi_profiles_from_url() {
+        Ok(t) => t,
+        Err(e) => exit_with_error("io_error", e),
+    };
+    if !show_veiled {
+        profiles.retain(|x| !x.veiled);
     }
+    profiles
+}
+
+pub fn get_dmi_profiles_from_url() -> Result<Vec<CfhdbDmiProfile>, std::io::Error> {
+    let profile_values = match get_local_profile_source(&DMI_PROFILE_JSON_URL) {
+        Some(local_path) => load_profile_source(&local_path)?,
+        None => parse_profile_database(&download_dmi_profile_database()?)?,
+    };
+    let locale = get_profile_locale();
+    let mut profiles_array: Vec<CfhdbDmiProfile> = profile_values
+        .iter()
+        .map(|profile| CfhdbDmiProfile::from_json(profile, &locale))
+        .collect();
+    profiles_array.sort_by_key(|x| x.priority);
     Ok(profiles_array)
 }
@@ -0,0 +1,149 @@
+use crate::{
+    config::distro_packages_providing, output_func::*, pci_func::get_visible_pci_profiles,
+    usb_func::get_visible_usb_profiles,
+};
+use colored::Colorize;
+use libcfhdb::{
+    firmware::{get_firmware_load_failures, get_missing_firmware, CfhdbMissingFirmware},
+    pci::CfhdbPciDevice,
+    usb::CfhdbUsbDevice,
+};
+use std::{collections::HashMap, sync::Arc};
+
+const MISSING_FIRMWARE_COLUMNS: [&str; 7] = [
+    "bus", "busid", "name", "module", "firmware", "source", "profiles",
+];
+
+struct FirmwareDevice {
+    bus: &'static str,
+    busid: String,
+    name: String,
+    missing: Vec<CfhdbMissingFirmware>,
+    // Codenames and packages of the profiles matching the device
+    profiles: Vec<(String, Vec<String>)>,
+}
+
+fn get_profile_packages<T>(
+    profiles: Option<Vec<Arc<T>>>,
+    get_packages: fn(&T) -> (String, Vec<String>),
+) -> Vec<(String, Vec<String>)> {
+    profiles
+        .unwrap_or_default()
+        .iter()
+        .map(|x| get_packages(x))
+        .filter(|(_, packages)| !packages.is_empty())
+        .collect()
+}
+
+// Profiles are only loaded once a device is known to miss firmware, most systems have none
+fn get_firmware_devices() -> Vec<FirmwareDevice> {
+    let failures = get_firmware_load_failures();
+    let mut devices = vec![];
+    let mut pci_profiles = None;
+    for device in CfhdbPciDevice::get_devices().unwrap_or_default() {
+        let modules = device.get_candidate_modules().unwrap_or_default();
+        let missing = get_missing_firmware(&device.sysfs_busid, &modules, &failures);
+        if missing.is_empty() {
+            continue;
+        }
+        let profiles = pci_profiles.get_or_insert_with(|| get_visible_pci_profiles(false));
+        CfhdbPciDevice::set_available_profiles(profiles, &device);
+        devices.push(FirmwareDevice {
+            bus: "pci",
+            name: format!("{} {}", device.vendor_name, device.device_name),
+            missing,
+            profiles: get_profile_packages(
+                device.available_profiles.0.lock().unwrap().clone(),
+                |x| (x.codename.clone(), x.packages.clone().unwrap_or_default()),
+            ),
+            busid: device.sysfs_busid,
+        });
+    }
+    let mut usb_profiles = None;
+    for device in CfhdbUsbDevice::get_devices().unwrap_or_default() {
+        let modules = device.get_candidate_modules().unwrap_or_default();
+        let missing = get_missing_firmware(&device.sysfs_busid, &modules, &failures);
+        if missing.is_empty() {
+            continue;
+        }
+        let profiles = usb_profiles.get_or_insert_with(|| get_visible_usb_profiles(false));
+        CfhdbUsbDevice::set_available_profiles(profiles, &device);
+        devices.push(FirmwareDevice {
+            bus: "usb",
+            name: format!(
+                "{} {}",
+                device.manufacturer_string_index, device.product_string_index
+            ),
+            missing,
+            profiles: get_profile_packages(
+                device.available_profiles.0.lock().unwrap().clone(),
+                |x| (x.codename.clone(), x.packages.clone().unwrap_or_default()),
+            ),
+            busid: device.sysfs_busid,
+        });
+    }
+    devices
+}
+
+// Packages are looked up in the repositories, so firmware from packages that aren't installed yet
+// is found as well
+fn get_providing_packages(firmware: &str) -> Vec<String> {
+    // The name ends up in a quoted shell word
+    if firmware.contains('\'') {
+        return vec![];
+    }
+    let command = distro_packages_providing(&format!("/usr/lib/firmware/{}*", firmware));
+    duct::cmd!("sh", "-c", command)
+        .stderr_null()
+        .read()
+        .unwrap_or_default()
+        .lines()
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+pub fn display_missing_firmware(json: bool) {
+    let devices = get_firmware_devices();
+    let mut providing_packages: HashMap<String, Vec<String>> = HashMap::new();
+    let mut records = vec![];
+    for device in &devices {
+        for missing in &device.missing {
+            // Devices without profiles that install packages have nothing to suggest
+            let profiles: Vec<&str> = match device.profiles.is_empty() {
+                true => vec![],
+                false => {
+                    let packages = providing_packages
+                        .entry(missing.firmware.clone())
+                        .or_insert_with(|| get_providing_packages(&missing.firmware));
+                    device
+                        .profiles
+                        .iter()
+                        .filter(|(_, x)| x.iter().any(|x| packages.contains(x)))
+                        .map(|(codename, _)| codename.as_str())
+                        .collect()
+                }
+            };
+            records.push(serde_json::json!({
+                "bus": device.bus,
+                "busid": device.busid,
+                "name": device.name,
+                "module": missing.module,
+                "firmware": missing.firmware,
+                "source": missing.source,
+                "profiles": profiles,
+            }));
+        }
+    }
+    if json && !is_record_output() {
+        print_json_result(records);
+    } else if records.is_empty() && !is_record_output() {
+        eprintln!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("no_missing_firmware")
+        );
+    } else {
+        print_records(records, &MISSING_FIRMWARE_COLUMNS);
+    }
+}
@@ -1,9 +1,12 @@
-use serde::{Serialize, Serializer};
+use crate::{
+    post_action::{get_post_actions, CfhdbPostAction},
+    profile::*,
+};
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 use std::{
     collections::HashMap,
-    fs,
-    io::{self, ErrorKind, Write},
-    os::unix::fs::PermissionsExt,
+    io::{self, ErrorKind},
     sync::{Arc, Mutex},
 };
 use tokio::runtime::Runtime;
@@ -32,7 +35,18 @@ impl Serialize for ProfileWrapper {
     }
 }
 
-#[derive(Serialize, Debug, Clone)]
+// Codenames can't be turned back into profiles, loaded devices start unmatched and are matched again
+impl<'de> Deserialize<'de> for ProfileWrapper {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        IgnoredAny::deserialize(deserializer)?;
+        Ok(ProfileWrapper(Arc::default()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CfhdbBtDevice {
     // String identification
     pub alias: String,
@@ -53,12 +67,22 @@ pub struct CfhdbBtDevice {
     pub battery_level: u8,
     // Cfhdb Extras
     pub available_profiles: ProfileWrapper,
-    // Bluer
-    #[serde(skip_serializing)]
-    bluer_device: bluer::Device
+    // Bluer, None for devices loaded from a report
+    #[serde(skip)]
+    bluer_device: Option<bluer::Device>,
 }
 
 impl CfhdbBtDevice {
+    fn get_bluer_device(&self) -> Result<&bluer::Device, io::Error> {
+        match &self.bluer_device {
+            Some(t) => Ok(t),
+            None => Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "bt device is not present on this system",
+            )),
+        }
+    }
+
     pub fn set_available_profiles(profile_data: &[CfhdbBtProfile], device: &Self) {
         let mut available_profiles: Vec<Arc<CfhdbBtProfile>> = vec![];
         for profile in profile_data.iter() {
@@ -119,8 +143,8 @@ impl CfhdbBtDevice {
     }
 
     pub fn disconnect_device(&self) -> Result<(), io::Error> {
+        let bluer_device = self.get_bluer_device()?;
         let bluer_future = async {
-            let bluer_device = &self.bluer_device;
             bluer_device.disconnect().await
         };
         let rt = Runtime::new()?;
@@ -131,8 +155,8 @@ impl CfhdbBtDevice {
     }
 
     pub fn connect_device(&self) -> Result<(), io::Error> {
+        let bluer_device = self.get_bluer_device()?;
         let bluer_future = async {
-            let bluer_device = &self.bluer_device;
             bluer_device.connect().await
         };
         let rt = Runtime::new()?;
@@ -143,8 +167,8 @@ impl CfhdbBtDevice {
     }
 
     pub fn block_device(&self) -> Result<(), io::Error> {
+        let bluer_device = self.get_bluer_device()?;
         let bluer_future = async {
-            let bluer_device = &self.bluer_device;
             bluer_device.set_blocked(true).await
         };
         let rt = Runtime::new()?;
@@ -155,8 +179,8 @@ impl CfhdbBtDevice {
     }
 
     pub fn unblock_device(&self) -> Result<(), io::Error> {
+        let bluer_device = self.get_bluer_device()?;
         let bluer_future = async {
-            let bluer_device = &self.bluer_device;
             bluer_device.set_blocked(false).await
         };
         let rt = Runtime::new()?;
@@ -167,8 +191,8 @@ impl CfhdbBtDevice {
     }
 
     pub fn trust_device(&self) -> Result<(), io::Error> {
+        let bluer_device = self.get_bluer_device()?;
         let bluer_future = async {
-            let bluer_device = &self.bluer_device;
             bluer_device.set_trusted(true).await
         };
         let rt = Runtime::new()?;
@@ -179,8 +203,8 @@ impl CfhdbBtDevice {
     }
 
     pub fn untrust_device(&self) -> Result<(), io::Error> {
+        let bluer_device = self.get_bluer_device()?;
         let bluer_future = async {
-            let bluer_device = &self.bluer_device;
             bluer_device.set_trusted(false).await
         };
         let rt = Runtime::new()?;
@@ -191,8 +215,8 @@ impl CfhdbBtDevice {
     }
 
     pub fn pair_device(&self) -> Result<(), io::Error> {
+        let bluer_device = self.get_bluer_device()?;
         let bluer_future = async {
-            let bluer_device = &self.bluer_device;
             bluer_device.pair().await
         };
         let rt = Runtime::new()?;
@@ -278,7 +302,7 @@ impl CfhdbBtDevice {
                     blocked: device.is_blocked().await.unwrap_or_default(),
                     battery_level: device.battery_percentage().await.unwrap_or_default().unwrap_or_default(),
                     address: Self::format_bt_address(addr.0),
-                    bluer_device: device,
+                    bluer_device: Some(device),
                     available_profiles: ProfileWrapper(Arc::default()),
                 });
             }
@@ -314,7 +338,8 @@ pub struct CfhdbBtProfile {
     pub codename: String,
     pub i18n_desc: String,
     pub icon_name: String,
-    pub license: String,
+    // None when the database leaves it out, the CLI shows it as unknown in the user's language
+    pub license: Option<String>,
     pub class_ids: Vec<String>,
     pub bt_names: Vec<String>,
     pub modalias_vendor_ids: Vec<String>,
@@ -333,9 +358,42 @@ pub struct CfhdbBtProfile {
     pub removable: bool,
     pub veiled: bool,
     pub priority: i32,
+    // Codenames that must not be installed together with this profile
+    pub conflicts: Vec<String>,
+    // Needed after installing or removing the profile before it takes full effect
+    pub post_actions: Vec<CfhdbPostAction>,
 }
 
 impl CfhdbBtProfile {
+    pub fn from_json(profile: &Value, locale: &str) -> Self {
+        Self {
+            codename: get_string(profile, "codename", ""),
+            i18n_desc: get_i18n_desc(profile, locale),
+            icon_name: get_string(profile, "icon_name", "package-x-generic"),
+            license: get_optional_string(profile, "license"),
+            class_ids: get_string_vec(profile, "class_ids"),
+            bt_names: get_string_vec(profile, "bt_names"),
+            modalias_vendor_ids: get_string_vec(profile, "modalias_vendor_ids"),
+            modalias_device_ids: get_string_vec(profile, "modalias_device_ids"),
+            modalias_product_ids: get_string_vec(profile, "modalias_product_ids"),
+            blacklisted_class_ids: get_string_vec(profile, "blacklisted_class_ids"),
+            blacklisted_bt_names: get_string_vec(profile, "blacklisted_bt_names"),
+            blacklisted_modalias_vendor_ids: get_string_vec(profile, "blacklisted_modalias_vendor_ids"),
+            blacklisted_modalias_device_ids: get_string_vec(profile, "blacklisted_modalias_device_ids"),
+            blacklisted_modalias_product_ids: get_string_vec(profile, "blacklisted_modalias_product_ids"),
+            packages: get_optional_string_vec(profile, "packages"),
+            check_script: get_string(profile, "check_script", "false"),
+            install_script: get_optional_string(profile, "install_script"),
+            remove_script: get_optional_string(profile, "remove_script"),
+            experimental: profile["experimental"].as_bool().unwrap_or_default(),
+            removable: profile["removable"].as_bool().unwrap_or_default(),
+            veiled: profile["veiled"].as_bool().unwrap_or_default(),
+            priority: profile["priority"].as_i64().unwrap_or_default() as i32,
+            conflicts: get_string_vec(profile, "conflicts"),
+            post_actions: get_post_actions(profile),
+        }
+    }
+
     pub fn get_profile_from_codename(
         codename: &str,
         profiles: Vec<CfhdbBtProfile>,
@@ -350,28 +408,6 @@ impl CfhdbBtProfile {
     }
 
     pub fn get_status(&self) -> bool {
-        let file_path = "/var/cache/cfhdb/check_cmd.sh";
-        {
-            let mut file = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(file_path)
-                .expect(&(file_path.to_string() + "cannot be read"));
-            file.write_all(format!("#! /bin/bash\nset -e\n{}", self.check_script).as_bytes())
-                .expect(&(file_path.to_string() + "cannot be written to"));
-            let mut perms = file
-                .metadata()
-                .expect(&(file_path.to_string() + "cannot be read"))
-                .permissions();
-            perms.set_mode(0o777);
-            fs::set_permissions(file_path, perms)
-                .expect(&(file_path.to_string() + "cannot be written to"));
-        }
-        duct::cmd!("bash", "-c", file_path)
-            .stderr_to_stdout()
-            .stdout_null()
-            .run()
-            .is_ok()
+        run_check_script(&self.check_script)
     }
 }
@@ -1,8 +1,12 @@
-use serde::{Serialize, Serializer};
+use crate::{
+    post_action::{get_post_actions, CfhdbPostAction},
+    profile::*,
+};
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 use std::{
     fs::{self},
-    io::{self, ErrorKind, Write},
-    os::unix::fs::PermissionsExt,
+    io::{self, ErrorKind},
     sync::{Arc, Mutex},
 };
 
@@ -30,7 +34,18 @@ impl Serialize for ProfileWrapper {
     }
 }
 
-#[derive(Serialize, Debug, Clone)]
+// Codenames can't be turned back into profiles, loaded devices start unmatched and are matched again
+impl<'de> Deserialize<'de> for ProfileWrapper {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        IgnoredAny::deserialize(deserializer)?;
+        Ok(ProfileWrapper(Arc::default()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CfhdbDmiInfo {
     // BIOS
     pub bios_date: String,
@@ -159,7 +174,8 @@ pub struct CfhdbDmiProfile {
     pub codename: String,
     pub i18n_desc: String,
     pub icon_name: String,
-    pub license: String,
+    // None when the database leaves it out, the CLI shows it as unknown in the user's language
+    pub license: Option<String>,
     // BIOS
     pub bios_vendors: Vec<String>,
     // BOARD
@@ -194,9 +210,48 @@ pub struct CfhdbDmiProfile {
     pub removable: bool,
     pub veiled: bool,
     pub priority: i32,
+    // Codenames that must not be installed together with this profile
+    pub conflicts: Vec<String>,
+    // Needed after installing or removing the profile before it takes full effect
+    pub post_actions: Vec<CfhdbPostAction>,
 }
 
 impl CfhdbDmiProfile {
+    pub fn from_json(profile: &Value, locale: &str) -> Self {
+        Self {
+            codename: get_string(profile, "codename", ""),
+            i18n_desc: get_i18n_desc(profile, locale),
+            icon_name: get_string(profile, "icon_name", "package-x-generic"),
+            license: get_optional_string(profile, "license"),
+            bios_vendors: get_string_vec(profile, "bios_vendors"),
+            board_asset_tags: get_string_vec(profile, "board_asset_tags"),
+            board_names: get_string_vec(profile, "board_names"),
+            board_vendors: get_string_vec(profile, "board_vendors"),
+            product_families: get_string_vec(profile, "product_families"),
+            product_names: get_string_vec(profile, "product_names"),
+            product_skus: get_string_vec(profile, "product_skus"),
+            sys_vendors: get_string_vec(profile, "sys_vendors"),
+            blacklisted_bios_vendors: get_string_vec(profile, "blacklisted_bios_vendors"),
+            blacklisted_board_asset_tags: get_string_vec(profile, "blacklisted_board_asset_tags"),
+            blacklisted_board_names: get_string_vec(profile, "blacklisted_board_names"),
+            blacklisted_board_vendors: get_string_vec(profile, "blacklisted_board_vendors"),
+            blacklisted_product_families: get_string_vec(profile, "blacklisted_product_families"),
+            blacklisted_product_names: get_string_vec(profile, "blacklisted_product_names"),
+            blacklisted_product_skus: get_string_vec(profile, "blacklisted_product_skus"),
+            blacklisted_sys_vendors: get_string_vec(profile, "blacklisted_sys_vendors"),
+            packages: get_optional_string_vec(profile, "packages"),
+            check_script: get_string(profile, "check_script", "false"),
+            install_script: get_optional_string(profile, "install_script"),
+            remove_script: get_optional_string(profile, "remove_script"),
+            experimental: profile["experimental"].as_bool().unwrap_or_default(),
+            removable: profile["removable"].as_bool().unwrap_or_default(),
+            veiled: profile["veiled"].as_bool().unwrap_or_default(),
+            priority: profile["priority"].as_i64().unwrap_or_default() as i32,
+            conflicts: get_string_vec(profile, "conflicts"),
+            post_actions: get_post_actions(profile),
+        }
+    }
+
     pub fn get_profile_from_codename(
         codename: &str,
         profiles: Vec<CfhdbDmiProfile>,
@@ -211,28 +266,6 @@ impl CfhdbDmiProfile {
     }
 
     pub fn get_status(&self) -> bool {
-        let file_path = "/var/cache/cfhdb/check_cmd.sh";
-        {
-            let mut file = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(file_path)
-                .expect(&(file_path.to_string() + "cannot be read"));
-            file.write_all(format!("#! /bin/bash\nset -e\n{}", self.check_script).as_bytes())
-                .expect(&(file_path.to_string() + "cannot be written to"));
-            let mut perms = file
-                .metadata()
-                .expect(&(file_path.to_string() + "cannot be read"))
-                .permissions();
-            perms.set_mode(0o777);
-            fs::set_permissions(file_path, perms)
-                .expect(&(file_path.to_string() + "cannot be written to"));
-        }
-        duct::cmd!("bash", "-c", file_path)
-            .stderr_to_stdout()
-            .stdout_null()
-            .run()
-            .is_ok()
+        run_check_script(&self.check_script)
     }
 }
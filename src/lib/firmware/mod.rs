@@ -0,0 +1,155 @@
+use crate::modules::{glob_match, CfhdbKernelModule};
+use serde::Serialize;
+use std::{fs, path::Path};
+
+// Compressed firmware is only found by kernels built with the matching decompressor, the files are
+// still what a package installs
+const FIRMWARE_SUFFIXES: [&str; 3] = ["", ".xz", ".zst"];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CfhdbFirmwareSource {
+    // Listed by a candidate module and not installed
+    Module,
+    // The kernel already failed to load it for the device
+    KernelLog,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CfhdbMissingFirmware {
+    pub module: Option<String>,
+    pub firmware: String,
+    pub source: CfhdbFirmwareSource,
+}
+
+#[derive(Debug, Clone)]
+pub struct CfhdbFirmwareLoadFailure {
+    pub driver: Option<String>,
+    // Kernel name of the device the driver logged the failure for, a USB interface for USB drivers
+    pub device: String,
+    pub firmware: String,
+}
+
+impl CfhdbFirmwareLoadFailure {
+    pub fn is_for_device(&self, busid: &str) -> bool {
+        self.device == busid || self.device.starts_with(&format!("{}:", busid))
+    }
+}
+
+// The directories the kernel searches, in its order
+fn get_firmware_dirs() -> Vec<String> {
+    let release = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+    let release = release.trim();
+    let mut dirs = vec![];
+    if let Ok(custom_path) = fs::read_to_string("/sys/module/firmware_class/parameters/path") {
+        if !custom_path.trim().is_empty() {
+            dirs.push(custom_path.trim().to_string());
+        }
+    }
+    dirs.push(format!("/lib/firmware/updates/{}", release));
+    dirs.push("/lib/firmware/updates".to_string());
+    dirs.push(format!("/lib/firmware/{}", release));
+    dirs.push("/lib/firmware".to_string());
+    dirs
+}
+
+// A few modules list wildcard names, those are present when any file in the directory matches
+fn is_firmware_in_dir(dir: &Path, name: &str) -> bool {
+    if !name.contains(['*', '?', '[']) {
+        return FIRMWARE_SUFFIXES
+            .iter()
+            .any(|suffix| dir.join(format!("{}{}", name, suffix)).exists());
+    }
+    let (subdir, pattern) = match name.rsplit_once('/') {
+        Some((subdir, pattern)) => (dir.join(subdir), pattern),
+        None => (dir.to_path_buf(), name),
+    };
+    fs::read_dir(subdir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .any(|entry| {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            FIRMWARE_SUFFIXES
+                .iter()
+                .any(|suffix| match file_name.strip_suffix(suffix) {
+                    Some(t) => glob_match(pattern.as_bytes(), t.as_bytes()),
+                    None => false,
+                })
+        })
+}
+
+pub fn is_firmware_present(name: &str) -> bool {
+    get_firmware_dirs()
+        .iter()
+        .any(|dir| is_firmware_in_dir(Path::new(dir), name))
+}
+
+// "<driver> <device>: Direct firmware load for <name> failed with error -2"
+fn parse_firmware_load_failure(line: &str) -> Option<CfhdbFirmwareLoadFailure> {
+    let (prefix, message) = line.split_once(": Direct firmware load for ")?;
+    let (firmware, _) = message.split_once(" failed")?;
+    let mut words = prefix.split_whitespace().rev();
+    Some(CfhdbFirmwareLoadFailure {
+        device: words.next()?.to_string(),
+        driver: words.next().map(|x| x.to_string()),
+        firmware: firmware.to_string(),
+    })
+}
+
+// dmesg is restricted to root on some kernels, the journal keeps the kernel log of this boot too
+pub fn get_firmware_load_failures() -> Vec<CfhdbFirmwareLoadFailure> {
+    let log = match duct::cmd!("dmesg").stderr_null().read() {
+        Ok(t) => t,
+        Err(_) => duct::cmd!("journalctl", "--dmesg", "--boot", "--output=cat", "--quiet")
+            .stderr_null()
+            .read()
+            .unwrap_or_default(),
+    };
+    let mut failures: Vec<CfhdbFirmwareLoadFailure> = vec![];
+    for failure in log.lines().filter_map(parse_firmware_load_failure) {
+        if !failures
+            .iter()
+            .any(|x| x.device == failure.device && x.firmware == failure.firmware)
+        {
+            failures.push(failure);
+        }
+    }
+    failures
+}
+
+// Once a module is bound the firmware of the other candidates does not matter. Modules list the
+// firmware of every chip they drive, so this can flag files the device itself never loads, the
+// kernel log entries are the ones it actually failed on.
+pub fn get_missing_firmware(
+    busid: &str,
+    modules: &[CfhdbKernelModule],
+    failures: &[CfhdbFirmwareLoadFailure],
+) -> Vec<CfhdbMissingFirmware> {
+    let mut missing: Vec<CfhdbMissingFirmware> = failures
+        .iter()
+        .filter(|x| x.is_for_device(busid) && !is_firmware_present(&x.firmware))
+        .map(|x| CfhdbMissingFirmware {
+            module: x.driver.clone(),
+            firmware: x.firmware.clone(),
+            source: CfhdbFirmwareSource::KernelLog,
+        })
+        .collect();
+    let bound: Vec<&CfhdbKernelModule> = modules.iter().filter(|x| x.bound).collect();
+    let modules: Vec<&CfhdbKernelModule> = match bound.is_empty() {
+        true => modules.iter().collect(),
+        false => bound,
+    };
+    for module in modules {
+        for firmware in &module.firmware {
+            if !missing.iter().any(|x| x.firmware == *firmware) && !is_firmware_present(firmware) {
+                missing.push(CfhdbMissingFirmware {
+                    module: Some(module.name.clone()),
+                    firmware: firmware.clone(),
+                    source: CfhdbFirmwareSource::Module,
+                });
+            }
+        }
+    }
+    missing
+}
@@ -1,4 +1,13 @@
 pub mod bt;
 pub mod dmi;
+pub mod firmware;
+pub mod modules;
+pub mod monitor;
 pub mod pci;
+pub mod post_action;
+pub mod profile;
+pub mod query;
+pub mod recommend;
+pub mod report;
+pub mod sysfs;
 pub mod usb;
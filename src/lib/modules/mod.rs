@@ -0,0 +1,490 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+// /lib/modules/<release> of the running kernel
+pub fn get_kernel_modules_path() -> Result<PathBuf, io::Error> {
+    let release = fs::read_to_string("/proc/sys/kernel/osrelease")?;
+    Ok(Path::new("/lib/modules").join(release.trim()))
+}
+
+// Matches a "[...]" class at the start of pattern, returns whether it matched and its length.
+// A "[" without a closing "]" is a plain character.
+fn match_class(pattern: &[u8], c: u8) -> (bool, usize) {
+    let end = match pattern.iter().skip(2).position(|x| *x == b']') {
+        Some(n) => n + 2,
+        None => return (c == b'[', 1),
+    };
+    let (negated, class) = match pattern[1] {
+        b'!' | b'^' => (true, &pattern[2..end]),
+        _ => (false, &pattern[1..end]),
+    };
+    let mut matched = false;
+    let mut n = 0;
+    while n < class.len() {
+        if n + 2 < class.len() && class[n + 1] == b'-' {
+            matched |= class[n] <= c && c <= class[n + 2];
+            n += 3;
+        } else {
+            matched |= class[n] == c;
+            n += 1;
+        }
+    }
+    (matched != negated, end + 1)
+}
+
+// The fnmatch subset modules.alias uses, "*" only ever backtracks to the last star
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => match match_class(&pattern[p..], text[t]) {
+                (true, len) => Some(len),
+                (false, _) => None,
+            },
+            Some(c) if *c == text[t] => Some(1),
+            _ => None,
+        };
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                t += 1;
+            }
+            (None, Some((star_p, star_t))) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|x| *x == b'*')
+}
+
+// modules.alias.bin is a kmod index, a trie keyed by the alias patterns
+const INDEX_MAGIC: u32 = 0xB007F457;
+const INDEX_VERSION_MAJOR: u32 = 0x0002;
+const INDEX_NODE_PREFIX: u32 = 0x80000000;
+const INDEX_NODE_VALUES: u32 = 0x40000000;
+const INDEX_NODE_CHILDS: u32 = 0x20000000;
+const INDEX_NODE_MASK: u32 = 0x0FFFFFFF;
+
+struct IndexReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl IndexReader<'_> {
+    fn read_u8(&mut self) -> Option<u8> {
+        let value = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(value)
+    }
+
+    fn read_u32(&mut self) -> Option<u32> {
+        let bytes = self.data.get(self.pos..self.pos + 4)?;
+        self.pos += 4;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?))
+    }
+
+    fn read_string(&mut self) -> Option<String> {
+        let len = self.data.get(self.pos..)?.iter().position(|x| *x == 0)?;
+        let value = String::from_utf8_lossy(&self.data[self.pos..self.pos + len]).into_owned();
+        self.pos += len + 1;
+        Some(value)
+    }
+}
+
+// Walks the whole trie, a node's key is the path of prefixes and child characters leading to it
+fn read_index_node(
+    data: &[u8],
+    offset: u32,
+    mut key: Vec<u8>,
+    aliases: &mut Vec<(String, String)>,
+) -> Option<()> {
+    let mut reader = IndexReader {
+        data,
+        pos: (offset & INDEX_NODE_MASK) as usize,
+    };
+    if offset & INDEX_NODE_PREFIX != 0 {
+        key.extend(reader.read_string()?.into_bytes());
+    }
+    let mut children = vec![];
+    if offset & INDEX_NODE_CHILDS != 0 {
+        let first = reader.read_u8()?;
+        let last = reader.read_u8()?;
+        for c in first..=last {
+            children.push((c, reader.read_u32()?));
+        }
+    }
+    if offset & INDEX_NODE_VALUES != 0 {
+        let count = reader.read_u32()?;
+        for _ in 0..count {
+            // Priority, the order of the values is already the order of modules.alias
+            reader.read_u32()?;
+            let module = reader.read_string()?;
+            aliases.push((String::from_utf8_lossy(&key).into_owned(), module));
+        }
+    }
+    for (c, child_offset) in children {
+        if child_offset != 0 {
+            let mut child_key = key.clone();
+            child_key.push(c);
+            read_index_node(data, child_offset, child_key, aliases)?;
+        }
+    }
+    Some(())
+}
+
+fn read_alias_index(data: &[u8]) -> Option<Vec<(String, String)>> {
+    let mut reader = IndexReader { data, pos: 0 };
+    if reader.read_u32()? != INDEX_MAGIC || reader.read_u32()? >> 16 != INDEX_VERSION_MAJOR {
+        return None;
+    }
+    let root = reader.read_u32()?;
+    let mut aliases = vec![];
+    read_index_node(data, root, vec![], &mut aliases)?;
+    Some(aliases)
+}
+
+// Alias patterns and the modules they belong to, for the running kernel
+#[derive(Default)]
+pub struct CfhdbModuleAliases(Vec<(String, String)>);
+
+// Every device lookup lists all devices, the D-Bus service and the monitor do that many times.
+// Keyed by the modification time, so a module installed for the running kernel shows up.
+static MODULE_ALIASES: Mutex<Option<(SystemTime, Arc<CfhdbModuleAliases>)>> = Mutex::new(None);
+
+impl CfhdbModuleAliases {
+    // Parsed on first use and again only after depmod rewrote the files
+    pub fn get() -> Result<Arc<Self>, io::Error> {
+        let modules_path = get_kernel_modules_path()?;
+        let modified = ["modules.alias", "modules.alias.bin"].iter().find_map(|x| {
+            fs::metadata(modules_path.join(x))
+                .and_then(|x| x.modified())
+                .ok()
+        });
+        let mut cached = MODULE_ALIASES.lock().unwrap();
+        if let (Some(modified), Some((time, aliases))) = (modified, cached.as_ref()) {
+            if modified == *time {
+                return Ok(aliases.clone());
+            }
+        }
+        let aliases = Arc::new(Self::load()?);
+        *cached = modified.map(|x| (x, aliases.clone()));
+        Ok(aliases)
+    }
+
+    // modules.alias is the text version of modules.alias.bin, which is only read when it is missing
+    fn load() -> Result<Self, io::Error> {
+        let modules_path = get_kernel_modules_path()?;
+        match fs::read_to_string(modules_path.join("modules.alias")) {
+            Ok(aliases) => Ok(Self(
+                aliases
+                    .lines()
+                    .filter_map(|line| {
+                        let mut words = line.split_whitespace();
+                        match (words.next(), words.next(), words.next()) {
+                            (Some("alias"), Some(pattern), Some(module)) => {
+                                Some((pattern.to_string(), module.to_string()))
+                            }
+                            _ => None,
+                        }
+                    })
+                    .collect(),
+            )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let data = fs::read(modules_path.join("modules.alias.bin"))?;
+                match read_alias_index(&data) {
+                    Some(t) => Ok(Self(t)),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "modules.alias.bin is not a kmod index",
+                    )),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Modules whose alias patterns match a device modalias, in the order modprobe would try them
+    pub fn get_modules(&self, modalias: &str) -> Vec<String> {
+        let modalias = modalias.trim().as_bytes();
+        let mut modules: Vec<String> = vec![];
+        for (pattern, module) in &self.0 {
+            if glob_match(pattern.as_bytes(), modalias) && !modules.contains(module) {
+                modules.push(module.clone());
+            }
+        }
+        modules
+    }
+}
+
+pub fn get_modalias_modules(modalias: &str) -> Result<Vec<String>, io::Error> {
+    Ok(CfhdbModuleAliases::get()?.get_modules(modalias))
+}
+
+// Module names treat "-" and "_" the same
+fn normalize_module_name(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+// "blacklist" lines of modprobe.d, a file in an earlier directory replaces one of the same name in
+// a later one, plus modprobe.blacklist= from the kernel command line
+pub fn get_blacklisted_modules() -> Vec<String> {
+    let mut files = BTreeMap::new();
+    for dir in [
+        "/etc/modprobe.d",
+        "/run/modprobe.d",
+        "/usr/local/lib/modprobe.d",
+        "/usr/lib/modprobe.d",
+        "/lib/modprobe.d",
+    ] {
+        for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".conf") {
+                files.entry(name).or_insert(entry.path());
+            }
+        }
+    }
+    let mut modules = vec![];
+    for path in files.values() {
+        for line in fs::read_to_string(path).unwrap_or_default().lines() {
+            let mut words = line.split_whitespace();
+            if let (Some("blacklist"), Some(module)) = (words.next(), words.next()) {
+                modules.push(normalize_module_name(module));
+            }
+        }
+    }
+    for arg in fs::read_to_string("/proc/cmdline")
+        .unwrap_or_default()
+        .split_whitespace()
+    {
+        if let Some(list) = arg.strip_prefix("modprobe.blacklist=") {
+            modules.extend(list.split(',').map(normalize_module_name));
+        }
+    }
+    modules
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CfhdbKernelModule {
+    pub name: String,
+    pub version: Option<String>,
+    pub license: Option<String>,
+    pub firmware: Vec<String>,
+    // The module of the driver the device is bound to
+    pub bound: bool,
+    pub blacklisted: bool,
+}
+
+impl CfhdbKernelModule {
+    // modinfo also knows about builtin modules, those have no file to read the fields from
+    fn get_module(name: &str, bound: bool, blacklisted: bool) -> Self {
+        let mut module = Self {
+            name: name.to_string(),
+            version: None,
+            license: None,
+            firmware: vec![],
+            bound,
+            blacklisted,
+        };
+        let stdout = duct::cmd!("modinfo", "--", name)
+            .stderr_null()
+            .unchecked()
+            .read()
+            .unwrap_or_default();
+        for line in stdout.lines() {
+            match line.split_once(':').map(|(k, v)| (k, v.trim().to_string())) {
+                Some(("version", v)) => module.version = Some(v),
+                Some(("license", v)) => module.license = Some(v),
+                Some(("firmware", v)) => module.firmware.push(v),
+                _ => {}
+            }
+        }
+        module
+    }
+}
+
+// Candidate modules of a device modalias, bound_module being the module of its current driver
+pub fn get_candidate_modules(
+    modalias: &str,
+    bound_module: Option<&str>,
+) -> Result<Vec<CfhdbKernelModule>, io::Error> {
+    let blacklisted = get_blacklisted_modules();
+    let bound_module = bound_module.map(normalize_module_name);
+    Ok(get_modalias_modules(modalias)?
+        .iter()
+        .map(|name| {
+            let normalized = normalize_module_name(name);
+            CfhdbKernelModule::get_module(
+                name,
+                bound_module.as_ref() == Some(&normalized),
+                blacklisted.contains(&normalized),
+            )
+        })
+        .collect())
+}
+
+// Name of the module that provides the driver bound to a sysfs device, None for builtin drivers
+pub fn get_bound_module(device_path: &Path) -> Option<String> {
+    let module_path = fs::read_link(device_path.join("driver").join("module")).ok()?;
+    Some(module_path.file_name()?.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_match(pattern: &str, text: &str) -> bool {
+        glob_match(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn class_matches_characters_and_ranges() {
+        assert_eq!(match_class(b"[abc]", b'b'), (true, 5));
+        assert_eq!(match_class(b"[abc]", b'd'), (false, 5));
+        assert_eq!(match_class(b"[0-9A-F]x", b'C'), (true, 8));
+        assert_eq!(match_class(b"[0-9A-F]x", b'c'), (false, 8));
+        // "]" right after the bracket is part of the class
+        assert_eq!(match_class(b"[]a]", b']'), (true, 4));
+    }
+
+    #[test]
+    fn class_can_be_negated() {
+        assert_eq!(match_class(b"[!0-9]", b'a'), (true, 6));
+        assert_eq!(match_class(b"[!0-9]", b'5'), (false, 6));
+        assert_eq!(match_class(b"[^a]", b'a'), (false, 4));
+    }
+
+    #[test]
+    fn unclosed_bracket_is_a_plain_character() {
+        assert_eq!(match_class(b"[ab", b'['), (true, 1));
+        assert_eq!(match_class(b"[ab", b'a'), (false, 1));
+        assert!(is_match("a[b", "a[b"));
+    }
+
+    #[test]
+    fn wildcards_are_matched() {
+        assert!(is_match("usb:v046Dp*", "usb:v046DpC52B"));
+        assert!(is_match("usb:v046Dp*", "usb:v046Dp"));
+        assert!(is_match("a?c", "abc"));
+        assert!(!is_match("a?c", "ac"));
+        assert!(!is_match("abc", "abcd"));
+        assert!(is_match("***", ""));
+        assert!(is_match("pci:v0000[18]0DEd*", "pci:v000010DEd1C82"));
+        assert!(!is_match("pci:v0000[!1]0DEd*", "pci:v000010DEd1C82"));
+    }
+
+    #[test]
+    fn star_backtracks() {
+        assert!(is_match("a*b*c", "aXbYbZc"));
+        assert!(is_match("*bc", "abcbc"));
+        assert!(!is_match("a*b", "aXbc"));
+        assert!(is_match(
+            "pci:v000010DEd*sv*sd*bc03sc*i*",
+            "pci:v000010DEd00001C82sv00001043sd000085F4bc03sc00i00"
+        ));
+        assert!(!is_match(
+            "pci:v000010DEd*sv*sd*bc03sc*i*",
+            "pci:v000010DEd00001C82sv00001043sd000085F4bc04sc03i00"
+        ));
+    }
+
+    // Appends a node and returns its offset with the flags for what it has
+    fn push_node(data: &mut Vec<u8>, prefix: &str, children: &[(u8, u32)], values: &[&str]) -> u32 {
+        let mut offset = data.len() as u32;
+        if !prefix.is_empty() {
+            offset |= INDEX_NODE_PREFIX;
+            data.extend(prefix.as_bytes());
+            data.push(0);
+        }
+        if let (Some(first), Some(last)) = (children.first(), children.last()) {
+            offset |= INDEX_NODE_CHILDS;
+            data.extend([first.0, last.0]);
+            for c in first.0..=last.0 {
+                let child = children.iter().find(|x| x.0 == c).map_or(0, |x| x.1);
+                data.extend(child.to_be_bytes());
+            }
+        }
+        if !values.is_empty() {
+            offset |= INDEX_NODE_VALUES;
+            data.extend((values.len() as u32).to_be_bytes());
+            for (priority, value) in values.iter().enumerate() {
+                data.extend((priority as u32).to_be_bytes());
+                data.extend(value.as_bytes());
+                data.push(0);
+            }
+        }
+        offset
+    }
+
+    fn get_alias_index() -> Vec<u8> {
+        let mut data = vec![];
+        data.extend(INDEX_MAGIC.to_be_bytes());
+        data.extend(((INDEX_VERSION_MAJOR << 16) | 1).to_be_bytes());
+        data.extend(0u32.to_be_bytes());
+        let nvidia = push_node(&mut data, "0DEd*", &[], &["nvidia", "nouveau"]);
+        let intel = push_node(&mut data, "086d*", &[], &["i915"]);
+        let pci = push_node(&mut data, "ci:v0000", &[(b'1', nvidia), (b'8', intel)], &[]);
+        let usb = push_node(&mut data, "sb:v046Dp*", &[], &["usbhid"]);
+        let root = push_node(&mut data, "", &[(b'p', pci), (b'u', usb)], &[]);
+        data[8..12].copy_from_slice(&root.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn alias_index_is_read() {
+        let aliases = read_alias_index(&get_alias_index()).unwrap();
+        assert_eq!(
+            aliases,
+            [
+                ("pci:v000010DEd*", "nvidia"),
+                ("pci:v000010DEd*", "nouveau"),
+                ("pci:v00008086d*", "i915"),
+                ("usb:v046Dp*", "usbhid"),
+            ]
+            .map(|(pattern, module)| (pattern.to_string(), module.to_string()))
+        );
+    }
+
+    #[test]
+    fn broken_alias_index_is_rejected() {
+        let data = get_alias_index();
+        let mut wrong_magic = data.clone();
+        wrong_magic[0] = 0;
+        assert_eq!(read_alias_index(&wrong_magic), None);
+        let mut wrong_version = data.clone();
+        wrong_version[5] = 3;
+        assert_eq!(read_alias_index(&wrong_version), None);
+        assert_eq!(read_alias_index(&data[..data.len() - 4]), None);
+    }
+
+    #[test]
+    fn modules_are_listed_once_in_alias_order() {
+        let aliases = CfhdbModuleAliases(
+            read_alias_index(&get_alias_index())
+                .unwrap()
+                .into_iter()
+                .chain([("pci:v*".to_string(), "nvidia".to_string())])
+                .collect(),
+        );
+        assert_eq!(
+            aliases.get_modules("pci:v000010DEd00001C82\n"),
+            ["nvidia", "nouveau"]
+        );
+        assert_eq!(aliases.get_modules("usb:v1234p5678"), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,269 @@
+use futures::{stream::LocalBoxStream, StreamExt};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+use tokio::runtime::Runtime;
+
+// udev re-broadcasts kernel uevents on this group once its rules ran, so device nodes and sysfs
+// attributes are in place by the time a device is looked up
+const UDEV_MONITOR_GROUP: u32 = 2;
+const UEVENT_BUFFER_SIZE: usize = 16384;
+
+// A device that appeared on a bus cfhdb has profiles for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CfhdbHotplugEvent {
+    // sysfs busid, e.g. "0000:01:00.0"
+    Pci(String),
+    // sysfs busid, e.g. "3-1.2"
+    Usb(String),
+    // Device address, e.g. "AA:BB:CC:DD:EE:FF"
+    Bt(String),
+}
+
+pub struct CfhdbDeviceMonitor {
+    receiver: Receiver<CfhdbHotplugEvent>,
+}
+
+impl CfhdbDeviceMonitor {
+    // Failing to listen for uevents is an error, BlueZ not running only means no bt events
+    pub fn new(watch_bt: bool) -> Result<Self, io::Error> {
+        let socket = open_uevent_socket()?;
+        let (sender, receiver) = mpsc::channel();
+        let uevent_sender = sender.clone();
+        thread::spawn(move || watch_uevents(socket, uevent_sender));
+        if watch_bt {
+            thread::spawn(move || {
+                let rt = Runtime::new().unwrap();
+                let _ = rt.block_on(watch_bt_adapters(sender));
+            });
+        }
+        Ok(Self { receiver })
+    }
+
+    // Blocks until the next device shows up, None once every watcher stopped
+    pub fn recv(&self) -> Option<CfhdbHotplugEvent> {
+        self.receiver.recv().ok()
+    }
+}
+
+fn open_uevent_socket() -> Result<OwnedFd, io::Error> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_KOBJECT_UEVENT,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    address.nl_groups = UDEV_MONITOR_GROUP;
+    let result = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+fn watch_uevents(socket: OwnedFd, sender: Sender<CfhdbHotplugEvent>) {
+    let mut buffer = vec![0u8; UEVENT_BUFFER_SIZE];
+    loop {
+        let length = unsafe {
+            libc::recv(
+                socket.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+            )
+        };
+        if length < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return;
+        }
+        let event = match parse_uevent(&buffer[..length as usize]) {
+            Some(t) => t,
+            None => continue,
+        };
+        if sender.send(event).is_err() {
+            return;
+        }
+    }
+}
+
+// udev messages carry a "libudev" header pointing at the properties, kernel ones start with "action@devpath"
+fn get_uevent_properties(data: &[u8]) -> HashMap<String, String> {
+    let properties = if data.starts_with(b"libudev\0") && data.len() >= 24 {
+        let offset = u32::from_ne_bytes(data[16..20].try_into().unwrap()) as usize;
+        let length = u32::from_ne_bytes(data[20..24].try_into().unwrap()) as usize;
+        data.get(offset..offset + length).unwrap_or_default()
+    } else {
+        data
+    };
+    properties
+        .split(|x| *x == 0)
+        .filter_map(|x| std::str::from_utf8(x).ok())
+        .filter_map(|x| x.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+pub fn parse_uevent(data: &[u8]) -> Option<CfhdbHotplugEvent> {
+    let properties = get_uevent_properties(data);
+    if properties.get("ACTION").map(|x| x.as_str()) != Some("add") {
+        return None;
+    }
+    match properties.get("SUBSYSTEM").map(|x| x.as_str()) {
+        Some("pci") => Some(CfhdbHotplugEvent::Pci(
+            properties.get("PCI_SLOT_NAME")?.clone(),
+        )),
+        // Interfaces get their own events, only the device itself is matched against profiles
+        Some("usb") if properties.get("DEVTYPE").map(|x| x.as_str()) == Some("usb_device") => {
+            let busid = properties.get("DEVPATH")?.rsplit('/').next()?;
+            Some(CfhdbHotplugEvent::Usb(busid.to_string()))
+        }
+        _ => None,
+    }
+}
+
+// What the bt watcher reacts to, adapter and device streams are merged into one
+enum BtWatchEvent {
+    AdapterAdded(String),
+    AdapterRemoved(String),
+    DeviceAdded(String, bluer::Address),
+    DeviceRemoved(bluer::Address),
+    // The device got paired or connected
+    DeviceReady(bluer::Address),
+}
+
+type BtWatchStream = LocalBoxStream<'static, BtWatchEvent>;
+
+// Ends when the adapter is removed
+async fn get_adapter_events(adapter: &bluer::Adapter) -> bluer::Result<BtWatchStream> {
+    let name = adapter.name().to_string();
+    let events = adapter.events().await?.filter_map(move |event| {
+        let name = name.clone();
+        async move {
+            match event {
+                bluer::AdapterEvent::DeviceAdded(t) => Some(BtWatchEvent::DeviceAdded(name, t)),
+                bluer::AdapterEvent::DeviceRemoved(t) => Some(BtWatchEvent::DeviceRemoved(t)),
+                _ => None,
+            }
+        }
+    });
+    Ok(events.boxed_local())
+}
+
+// Ends when the device is removed
+async fn get_device_events(device: &bluer::Device) -> bluer::Result<BtWatchStream> {
+    let address = device.address();
+    let events = device.events().await?.filter_map(move |event| async move {
+        match event {
+            bluer::DeviceEvent::PropertyChanged(
+                bluer::DeviceProperty::Paired(true) | bluer::DeviceProperty::Connected(true),
+            ) => Some(BtWatchEvent::DeviceReady(address)),
+            _ => None,
+        }
+    });
+    Ok(events.boxed_local())
+}
+
+async fn is_bt_device_ready(device: &bluer::Device) -> bool {
+    device.is_paired().await.unwrap_or(false) || device.is_connected().await.unwrap_or(false)
+}
+
+// Devices the adapter already knows are only suggested once they get paired or connected
+async fn get_adapter_streams(adapter: &bluer::Adapter) -> bluer::Result<Vec<BtWatchStream>> {
+    let mut streams = vec![get_adapter_events(adapter).await?];
+    for address in adapter.device_addresses().await? {
+        let device = adapter.device(address)?;
+        if !is_bt_device_ready(&device).await {
+            streams.push(get_device_events(&device).await?);
+        }
+    }
+    Ok(streams)
+}
+
+// Devices found by a scan are only suggested once they get paired or connected
+async fn watch_bt_adapters(sender: Sender<CfhdbHotplugEvent>) -> Result<(), bluer::Error> {
+    let session = bluer::Session::new().await?;
+    let mut events = futures::stream::SelectAll::new();
+    // Subscribed before listing the adapters, so one plugged in meanwhile is not missed
+    let session_events = session.events().await?.filter_map(|event| async move {
+        match event {
+            bluer::SessionEvent::AdapterAdded(t) => Some(BtWatchEvent::AdapterAdded(t)),
+            bluer::SessionEvent::AdapterRemoved(t) => Some(BtWatchEvent::AdapterRemoved(t)),
+        }
+    });
+    events.push(session_events.boxed_local());
+    let adapter_names = session.adapter_names().await?;
+    events.push(
+        futures::stream::iter(adapter_names.into_iter().map(BtWatchEvent::AdapterAdded))
+            .boxed_local(),
+    );
+    let mut adapters = HashSet::new();
+    let mut suggested = HashSet::new();
+    while let Some(event) = events.next().await {
+        let address = match event {
+            BtWatchEvent::AdapterAdded(name) => {
+                if adapters.insert(name.clone()) {
+                    // An adapter that is already gone again is skipped
+                    let streams = match session.adapter(&name) {
+                        Ok(t) => get_adapter_streams(&t).await.unwrap_or_default(),
+                        Err(_) => vec![],
+                    };
+                    events.extend(streams);
+                }
+                continue;
+            }
+            BtWatchEvent::AdapterRemoved(name) => {
+                adapters.remove(&name);
+                continue;
+            }
+            BtWatchEvent::DeviceAdded(name, address) => {
+                let device = match session.adapter(&name).and_then(|x| x.device(address)) {
+                    Ok(t) => t,
+                    Err(_) => continue,
+                };
+                // Subscribed before checking, so pairing in between is not missed
+                if let Ok(t) = get_device_events(&device).await {
+                    events.push(t);
+                }
+                if !is_bt_device_ready(&device).await {
+                    continue;
+                }
+                address
+            }
+            BtWatchEvent::DeviceRemoved(address) => {
+                suggested.remove(&address);
+                continue;
+            }
+            BtWatchEvent::DeviceReady(address) => address,
+        };
+        // Connecting again later is not a new device
+        if !suggested.insert(address) {
+            continue;
+        }
+        if sender
+            .send(CfhdbHotplugEvent::Bt(address.to_string()))
+            .is_err()
+        {
+            break;
+        }
+    }
+    Ok(())
+}
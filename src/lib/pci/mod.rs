@@ -1,13 +1,22 @@
-use regex::Regex;
-use serde::{Serialize, Serializer};
+use crate::{
+    modules::{get_bound_module, get_candidate_modules, CfhdbKernelModule, CfhdbModuleAliases},
+    post_action::{get_post_actions, CfhdbPostAction},
+    profile::*,
+    sysfs::{
+        get_reset_methods, get_started_state, get_stopped_busids, is_blacklisted, run_sysfs_helper,
+        CfhdbRuntimePmControl, CfhdbSysfsAction, CfhdbSysfsBus, CfhdbSysfsError,
+        BLACKLIST_DRIVER_OVERRIDE, RESCAN_ALL_BUSES,
+    },
+};
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 use std::{
-    collections::HashMap,
-    fs::{self, File},
-    io::{self, BufRead, ErrorKind, Write},
-    os::unix::fs::PermissionsExt,
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, ErrorKind},
+    path::PathBuf,
     sync::{Arc, Mutex},
 };
-use users::get_current_username;
 
 // Implement Serialize for Arc<Mutex<Option<Vec<Arc<CfhdbPciProfile>>>>>
 
@@ -33,7 +42,66 @@ impl Serialize for ProfileWrapper {
     }
 }
 
+// Codenames can't be turned back into profiles, loaded devices start unmatched and are matched again
+impl<'de> Deserialize<'de> for ProfileWrapper {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        IgnoredAny::deserialize(deserializer)?;
+        Ok(ProfileWrapper(Arc::default()))
+    }
+}
+
 #[derive(Serialize, Debug, Clone)]
+pub struct CfhdbIommuGroup {
+    pub id: String,
+    pub devices: Vec<String>,
+}
+
+fn get_iommu_group_devices(group: &str) -> Result<Vec<String>, io::Error> {
+    let mut devices = vec![];
+    for entry in fs::read_dir(format!("/sys/kernel/iommu_groups/{}/devices", group))? {
+        devices.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    devices.sort();
+    Ok(devices)
+}
+
+// Empty when the IOMMU is disabled or missing
+pub fn get_iommu_groups() -> Result<Vec<CfhdbIommuGroup>, io::Error> {
+    let entries = match fs::read_dir("/sys/kernel/iommu_groups") {
+        Ok(t) => t,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut groups = vec![];
+    for entry in entries {
+        let id = entry?.file_name().to_string_lossy().into_owned();
+        groups.push(CfhdbIommuGroup {
+            devices: get_iommu_group_devices(&id)?,
+            id,
+        });
+    }
+    groups.sort_by_key(|x| x.id.parse::<u32>().unwrap_or(u32::MAX));
+    Ok(groups)
+}
+
+// Fields are None when the kernel does not expose them, e.g. runtime PM without CONFIG_PM
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CfhdbPciPowerState {
+    // power/control, "auto" or "on"
+    pub control: Option<String>,
+    // power/runtime_status, "active" or "suspended" among others
+    pub runtime_status: Option<String>,
+    // D0, D3hot, D3cold...
+    pub d_state: Option<String>,
+    // The *_aspm and *_pcipm files of the link directory and whether each is enabled, empty when
+    // the kernel doesn't let ASPM be controlled for the link
+    pub aspm: BTreeMap<String, bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CfhdbPciDevice {
     // String identification
     pub class_name: String,
@@ -49,6 +117,28 @@ pub struct CfhdbPciDevice {
     pub sysfs_busid: String,
     pub sysfs_id: String,
     pub kernel_driver: String,
+    // None without an IOMMU, and in reports from older versions
+    #[serde(default)]
+    pub iommu_group: Option<String>,
+    #[serde(default)]
+    pub power: CfhdbPciPowerState,
+    // Methods the kernel tries in order when the device is reset, e.g. flr, pm or bus
+    #[serde(default)]
+    pub reset_methods: Vec<String>,
+    // SR-IOV, None for devices without the capability
+    #[serde(default)]
+    pub sriov_totalvfs: Option<u32>,
+    #[serde(default)]
+    pub sriov_numvfs: Option<u32>,
+    // Bus ID of the physical function, for a virtual function
+    #[serde(default)]
+    pub physfn: Option<String>,
+    // Bus IDs of the enabled virtual functions, for a physical function
+    #[serde(default)]
+    pub virtfns: Vec<String>,
+    // Modules whose modules.alias entries match the device, in the order modprobe tries them
+    #[serde(default)]
+    pub candidate_modules: Vec<String>,
     // Cfhdb Extras
     pub available_profiles: ProfileWrapper,
 }
@@ -71,6 +161,73 @@ impl CfhdbPciDevice {
         return None;
     }
 
+    fn get_sriov_count(busid: &str, name: &str) -> Option<u32> {
+        fs::read_to_string(Self::get_device_path(busid).join(name))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    fn get_physfn(busid: &str) -> Option<String> {
+        let physfn_path = fs::read_link(Self::get_device_path(busid).join("physfn")).ok()?;
+        Some(physfn_path.file_name()?.to_string_lossy().into_owned())
+    }
+
+    // virtfn0, virtfn1... link to the VFs, sorted by their index
+    fn get_virtfns(busid: &str) -> Vec<String> {
+        let mut virtfns = vec![];
+        for entry in fs::read_dir(Self::get_device_path(busid))
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let index = match name.strip_prefix("virtfn").map(|x| x.parse::<u32>()) {
+                Some(Ok(t)) => t,
+                _ => continue,
+            };
+            if let Some(vf) = fs::read_link(entry.path())
+                .ok()
+                .and_then(|x| Some(x.file_name()?.to_string_lossy().into_owned()))
+            {
+                virtfns.push((index, vf));
+            }
+        }
+        virtfns.sort();
+        virtfns.into_iter().map(|(_, vf)| vf).collect()
+    }
+
+    fn get_iommu_group(busid: &str) -> Option<String> {
+        let group_path = fs::read_link(format!("/sys/bus/pci/devices/{}/iommu_group", busid)).ok()?;
+        Some(group_path.file_name()?.to_string_lossy().into_owned())
+    }
+
+    // Reading these never wakes a suspended device
+    fn get_power_state(busid: &str) -> CfhdbPciPowerState {
+        let device_path = Self::get_device_path(busid);
+        let read_value = |name: &str| {
+            fs::read_to_string(device_path.join(name))
+                .ok()
+                .map(|x| x.trim().to_string())
+        };
+        let mut aspm = BTreeMap::new();
+        for entry in fs::read_dir(device_path.join("link")).into_iter().flatten().flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.ends_with("_aspm") || name.ends_with("_pcipm") {
+                if let Ok(value) = fs::read_to_string(entry.path()) {
+                    aspm.insert(name, value.trim() == "1");
+                }
+            }
+        }
+        CfhdbPciPowerState {
+            control: read_value("power/control"),
+            runtime_status: read_value("power/runtime_status"),
+            d_state: read_value("power_state"),
+            aspm,
+        }
+    }
+
     pub fn set_available_profiles(profile_data: &[CfhdbPciProfile], device: &Self) {
         let mut available_profiles: Vec<Arc<CfhdbPciProfile>> = vec![];
         for profile in profile_data.iter() {
@@ -112,126 +269,178 @@ impl CfhdbPciDevice {
     }
 
     fn get_enabled(busid: &str) -> bool {
-        let pci_busid_blacklist_path = "/etc/cfhdb/pci_blacklist";
-        match File::open(&pci_busid_blacklist_path) {
-            Ok(file) => {
-                let reader = io::BufReader::new(file);
-                for line in reader.lines() {
-                    match line {
-                        Ok(t) => {
-                            if t.trim() == busid {
-                                return false;
-                            }
-                        }
-                        Err(_) => {}
-                    };
-                }
-            }
-            Err(_) => {}
+        !is_blacklisted(CfhdbSysfsBus::Pci, busid)
+    }
+
+    fn get_device_path(busid: &str) -> PathBuf {
+        PathBuf::from(format!("/sys/bus/pci/devices/{}", busid))
+    }
+
+    fn get_candidate_module_names(aliases: &CfhdbModuleAliases, busid: &str) -> Vec<String> {
+        match fs::read_to_string(Self::get_device_path(busid).join("modalias")) {
+            Ok(modalias) => aliases.get_modules(&modalias),
+            Err(_) => vec![],
         }
-        return true;
-    }
-
-    fn get_modinfo_name(busid: &str) -> Result<String, io::Error> {
-        let modalias = fs::read_to_string(format!("/sys/bus/pci/devices/{}/modalias", busid))?;
-        let modinfo_cmd = duct::cmd!("modinfo", modalias);
-        let stdout = modinfo_cmd.read()?;
-        let re = Regex::new(r"name:\s+(\w+)").unwrap();
-        for line in stdout.lines() {
-            if let Some(captures) = re.captures(line) {
-                // Extract the module name from the capture group
-                if let Some(module_name) = captures.get(1) {
-                    return Ok(module_name.as_str().to_string());
-                }
-            }
+    }
+
+    pub fn stop_device(&self) -> Result<(), CfhdbSysfsError> {
+        run_sysfs_helper(
+            CfhdbSysfsAction::StopDevice,
+            CfhdbSysfsBus::Pci,
+            &self.sysfs_busid,
+            None,
+        )
+    }
+
+    pub fn start_device(&self) -> Result<(), CfhdbSysfsError> {
+        run_sysfs_helper(
+            CfhdbSysfsAction::StartDevice,
+            CfhdbSysfsBus::Pci,
+            &self.sysfs_busid,
+            self.candidate_modules.first().map(|x| x.as_str()),
+        )
+    }
+
+    pub fn enable_device(&self) -> Result<(), CfhdbSysfsError> {
+        run_sysfs_helper(
+            CfhdbSysfsAction::EnableDevice,
+            CfhdbSysfsBus::Pci,
+            &self.sysfs_busid,
+            None,
+        )
+    }
+
+    pub fn disable_device(&self) -> Result<(), CfhdbSysfsError> {
+        run_sysfs_helper(
+            CfhdbSysfsAction::DisableDevice,
+            CfhdbSysfsBus::Pci,
+            &self.sysfs_busid,
+            None,
+        )
+    }
+
+    // Without a driver the override is cleared and the kernel picks the driver again. A persistent
+    // binding is also applied at every boot.
+    pub fn bind_device(
+        &self,
+        driver: Option<&str>,
+        persistent: bool,
+    ) -> Result<(), CfhdbSysfsError> {
+        run_sysfs_helper(
+            if persistent {
+                CfhdbSysfsAction::BindDevicePersistent
+            } else {
+                CfhdbSysfsAction::BindDevice
+            },
+            CfhdbSysfsBus::Pci,
+            &self.sysfs_busid,
+            driver,
+        )
+    }
+
+    // Passing a device through to a VM needs every device of its IOMMU group except bridges bound
+    // to vfio-pci
+    pub fn bind_iommu_group(&self, driver: &str, persistent: bool) -> Result<(), CfhdbSysfsError> {
+        run_sysfs_helper(
+            if persistent {
+                CfhdbSysfsAction::BindIommuGroupPersistent
+            } else {
+                CfhdbSysfsAction::BindIommuGroup
+            },
+            CfhdbSysfsBus::Pci,
+            &self.sysfs_busid,
+            Some(driver),
+        )
+    }
+
+    // A persistent setting is also applied at every boot
+    pub fn set_runtime_pm(
+        &self,
+        control: CfhdbRuntimePmControl,
+        persistent: bool,
+    ) -> Result<(), CfhdbSysfsError> {
+        run_sysfs_helper(
+            if persistent {
+                CfhdbSysfsAction::SetRuntimePmPersistent
+            } else {
+                CfhdbSysfsAction::SetRuntimePm
+            },
+            CfhdbSysfsBus::Pci,
+            &self.sysfs_busid,
+            Some(control.get_name()),
+        )
+    }
+
+    // With a method only that one is tried, it has to be one of reset_methods
+    pub fn reset_device(&self, method: Option<&str>) -> Result<(), CfhdbSysfsError> {
+        run_sysfs_helper(
+            CfhdbSysfsAction::ResetDevice,
+            CfhdbSysfsBus::Pci,
+            &self.sysfs_busid,
+            method,
+        )
+    }
+
+    // The device and its driver are gone until rescan_bus finds it again
+    pub fn remove_device(&self) -> Result<(), CfhdbSysfsError> {
+        run_sysfs_helper(
+            CfhdbSysfsAction::RemoveDevice,
+            CfhdbSysfsBus::Pci,
+            &self.sysfs_busid,
+            None,
+        )
+    }
+
+    // Rescans the bus below a bridge, or every PCI bus without one
+    pub fn rescan_bus(bridge: Option<&str>) -> Result<(), CfhdbSysfsError> {
+        run_sysfs_helper(
+            CfhdbSysfsAction::RescanBus,
+            CfhdbSysfsBus::Pci,
+            bridge.unwrap_or(RESCAN_ALL_BUSES),
+            None,
+        )
+    }
+
+    // VFs are removed and created again when the count changes, 0 removes them all
+    pub fn set_vf_count(&self, count: u32) -> Result<(), CfhdbSysfsError> {
+        run_sysfs_helper(
+            CfhdbSysfsAction::SetVfCount,
+            CfhdbSysfsBus::Pci,
+            &self.sysfs_busid,
+            Some(&count.to_string()),
+        )
+    }
+
+    // Bus IDs of the other devices in the same IOMMU group, bridges included
+    pub fn get_iommu_group_members(&self) -> Vec<String> {
+        match &self.iommu_group {
+            Some(group) => get_iommu_group_devices(group)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|x| *x != self.sysfs_busid)
+                .collect(),
+            None => vec![],
         }
-        Err(io::Error::new(ErrorKind::NotFound, "not found"))
     }
 
-    pub fn stop_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "stop_device",
-                "pci",
-                &self.sysfs_busid
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "stop_device",
-                "pci",
-                &self.sysfs_busid
-            )
-        };
-        cmd.run()?;
-        Ok(())
-    }
-
-    pub fn start_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "start_device",
-                "pci",
-                &self.sysfs_busid,
-                Self::get_modinfo_name(&self.sysfs_busid).unwrap_or("".to_string())
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "start_device",
-                "pci",
-                &self.sysfs_busid,
-                Self::get_modinfo_name(&self.sysfs_busid).unwrap_or("".to_string())
-            )
-        };
-        cmd.run()?;
-        Ok(())
-    }
-
-    pub fn enable_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "enable_device",
-                "pci",
-                &self.sysfs_busid
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "enable_device",
-                "pci",
-                &self.sysfs_busid
-            )
-        };
-        cmd.run()?;
-        Ok(())
-    }
-
-    pub fn disable_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "disable_device",
-                "pci",
-                &self.sysfs_busid
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "disable_device",
-                "pci",
-                &self.sysfs_busid
-            )
-        };
-        cmd.run()?;
-        Ok(())
+    pub fn get_driver_override(&self) -> Option<String> {
+        let driver_override = fs::read_to_string(format!(
+            "/sys/bus/pci/devices/{}/driver_override",
+            self.sysfs_busid
+        ))
+        .ok()?;
+        // The blacklist's override keeps the device unbound, it is not a driver anyone picked
+        match driver_override.trim() {
+            "" | "(null)" | BLACKLIST_DRIVER_OVERRIDE => None,
+            t => Some(t.to_string()),
+        }
+    }
+
+    // Candidate modules with their modinfo fields, each can be passed to bind_device
+    pub fn get_candidate_modules(&self) -> Result<Vec<CfhdbKernelModule>, io::Error> {
+        let device_path = Self::get_device_path(&self.sysfs_busid);
+        let modalias = fs::read_to_string(device_path.join("modalias"))?;
+        get_candidate_modules(&modalias, get_bound_module(&device_path).as_deref())
     }
 
     pub fn get_device_from_busid(busid: &str) -> Result<CfhdbPciDevice, io::Error> {
@@ -263,6 +472,9 @@ impl CfhdbPciDevice {
         // Get hardware devices
         let pci_devices = pacc.devices()?;
         let mut devices = vec![];
+        // Without modules.alias the devices are still listed, just without candidates
+        let aliases = CfhdbModuleAliases::get().unwrap_or_default();
+        let stopped_busids = get_stopped_busids(CfhdbSysfsBus::Pci);
 
         for mut iter in pci_devices.iter_mut() {
             // fill in header info we need
@@ -286,6 +498,15 @@ impl CfhdbPciDevice {
             let item_sysfs_id = "".to_owned();
             let item_kernel_driver =
                 Self::get_kernel_driver(&item_sysfs_busid).unwrap_or("Unknown".to_string());
+            let item_iommu_group = Self::get_iommu_group(&item_sysfs_busid);
+            let item_candidate_modules =
+                Self::get_candidate_module_names(&aliases, &item_sysfs_busid);
+            let item_power = Self::get_power_state(&item_sysfs_busid);
+            let item_reset_methods = get_reset_methods(&item_sysfs_busid);
+            let item_sriov_totalvfs = Self::get_sriov_count(&item_sysfs_busid, "sriov_totalvfs");
+            let item_sriov_numvfs = Self::get_sriov_count(&item_sysfs_busid, "sriov_numvfs");
+            let item_physfn = Self::get_physfn(&item_sysfs_busid);
+            let item_virtfns = Self::get_virtfns(&item_sysfs_busid);
 
             devices.push(Self {
                 class_name: item_class,
@@ -294,20 +515,31 @@ impl CfhdbPciDevice {
                 class_id: item_class_id,
                 device_id: item_device_id,
                 vendor_id: item_vendor_id,
-                started: match item_started {
-                    Ok(t) => {
-                        if item_kernel_driver != "Unknown" {
-                            Some(t)
-                        } else {
-                            None
+                started: get_started_state(
+                    match item_started {
+                        Ok(t) => {
+                            if item_kernel_driver != "Unknown" {
+                                Some(t)
+                            } else {
+                                None
+                            }
                         }
-                    }
-                    Err(_) => None,
-                },
+                        Err(_) => None,
+                    },
+                    stopped_busids.contains(&item_sysfs_busid),
+                ),
                 enabled: item_enabled,
                 sysfs_busid: item_sysfs_busid,
                 sysfs_id: item_sysfs_id,
                 kernel_driver: item_kernel_driver,
+                iommu_group: item_iommu_group,
+                power: item_power,
+                reset_methods: item_reset_methods,
+                sriov_totalvfs: item_sriov_totalvfs,
+                sriov_numvfs: item_sriov_numvfs,
+                physfn: item_physfn,
+                virtfns: item_virtfns,
+                candidate_modules: item_candidate_modules,
                 available_profiles: ProfileWrapper(Arc::default()),
             });
         }
@@ -345,7 +577,8 @@ pub struct CfhdbPciProfile {
     pub codename: String,
     pub i18n_desc: String,
     pub icon_name: String,
-    pub license: String,
+    // None when the database leaves it out, the CLI shows it as unknown in the user's language
+    pub license: Option<String>,
     pub class_ids: Vec<String>,
     pub vendor_ids: Vec<String>,
     pub device_ids: Vec<String>,
@@ -360,9 +593,38 @@ pub struct CfhdbPciProfile {
     pub removable: bool,
     pub veiled: bool,
     pub priority: i32,
+    // Codenames that must not be installed together with this profile
+    pub conflicts: Vec<String>,
+    // Needed after installing or removing the profile before it takes full effect
+    pub post_actions: Vec<CfhdbPostAction>,
 }
 
 impl CfhdbPciProfile {
+    pub fn from_json(profile: &Value, locale: &str) -> Self {
+        Self {
+            codename: get_string(profile, "codename", ""),
+            i18n_desc: get_i18n_desc(profile, locale),
+            icon_name: get_string(profile, "icon_name", "package-x-generic"),
+            license: get_optional_string(profile, "license"),
+            class_ids: get_string_vec(profile, "class_ids"),
+            vendor_ids: get_string_vec(profile, "vendor_ids"),
+            device_ids: get_string_vec(profile, "device_ids"),
+            blacklisted_class_ids: get_string_vec(profile, "blacklisted_class_ids"),
+            blacklisted_vendor_ids: get_string_vec(profile, "blacklisted_vendor_ids"),
+            blacklisted_device_ids: get_string_vec(profile, "blacklisted_device_ids"),
+            packages: get_optional_string_vec(profile, "packages"),
+            check_script: get_string(profile, "check_script", "false"),
+            install_script: get_optional_string(profile, "install_script"),
+            remove_script: get_optional_string(profile, "remove_script"),
+            experimental: profile["experimental"].as_bool().unwrap_or_default(),
+            removable: profile["removable"].as_bool().unwrap_or_default(),
+            veiled: profile["veiled"].as_bool().unwrap_or_default(),
+            priority: profile["priority"].as_i64().unwrap_or_default() as i32,
+            conflicts: get_string_vec(profile, "conflicts"),
+            post_actions: get_post_actions(profile),
+        }
+    }
+
     pub fn get_profile_from_codename(
         codename: &str,
         profiles: Vec<CfhdbPciProfile>,
@@ -377,28 +639,6 @@ impl CfhdbPciProfile {
     }
 
     pub fn get_status(&self) -> bool {
-        let file_path = "/var/cache/cfhdb/check_cmd.sh";
-        {
-            let mut file = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(file_path)
-                .expect(&(file_path.to_string() + "cannot be read"));
-            file.write_all(format!("#! /bin/bash\nset -e\n{}", self.check_script).as_bytes())
-                .expect(&(file_path.to_string() + "cannot be written to"));
-            let mut perms = file
-                .metadata()
-                .expect(&(file_path.to_string() + "cannot be read"))
-                .permissions();
-            perms.set_mode(0o777);
-            fs::set_permissions(file_path, perms)
-                .expect(&(file_path.to_string() + "cannot be written to"));
-        }
-        duct::cmd!("bash", "-c", file_path)
-            .stderr_to_stdout()
-            .stdout_null()
-            .run()
-            .is_ok()
+        run_check_script(&self.check_script)
     }
 }
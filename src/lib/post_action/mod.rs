@@ -0,0 +1,251 @@
+use crate::sysfs::{write_state_file, SYSFS_HELPER_PATH};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::{
+    fmt, fs,
+    io::{self, ErrorKind},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use users::get_current_username;
+
+// Outstanding post-actions of past transactions. Every entry carries the boot it was recorded in,
+// a reboot satisfies all of them so entries from earlier boots are dropped when loading.
+// The state is root-owned, data/tmpfiles.d/cfhdb.conf creates the directory.
+pub const PENDING_ACTIONS_PATH: &str = "/run/cfhdb/pending_actions.json";
+// Present while a reboot or relogin is outstanding, for desktop tools to show a notice
+pub const REBOOT_REQUIRED_FLAG_PATH: &str = "/run/cfhdb/reboot-required";
+pub const RELOGIN_REQUIRED_FLAG_PATH: &str = "/run/cfhdb/relogin-required";
+// cfhdb-sysfs-helper actions, unprivileged callers change the state through them
+pub const ADD_PENDING_ACTIONS: &str = "add_pending_actions";
+pub const CLEAR_PENDING_ACTIONS: &str = "clear_pending_actions";
+
+// Declared by a profile in "post_actions", e.g. ["rebuild_initramfs", "reload_module:nvidia", "reboot"]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CfhdbPostAction {
+    RebuildInitramfs,
+    ReloadModule(String),
+    Relogin,
+    Reboot,
+}
+
+impl CfhdbPostAction {
+    pub fn parse(action: &str) -> Option<Self> {
+        match action.split_once(':') {
+            Some(("reload_module", module)) if !module.is_empty() => {
+                Some(Self::ReloadModule(module.to_string()))
+            }
+            Some(_) => None,
+            None => match action {
+                "rebuild_initramfs" => Some(Self::RebuildInitramfs),
+                "relogin" => Some(Self::Relogin),
+                "reboot" => Some(Self::Reboot),
+                _ => None,
+            },
+        }
+    }
+
+    // Name without the module, used as translation key suffix
+    pub fn get_kind(&self) -> &'static str {
+        match self {
+            Self::RebuildInitramfs => "rebuild_initramfs",
+            Self::ReloadModule(_) => "reload_module",
+            Self::Relogin => "relogin",
+            Self::Reboot => "reboot",
+        }
+    }
+}
+
+impl fmt::Display for CfhdbPostAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::ReloadModule(module) => write!(f, "reload_module:{}", module),
+            t => write!(f, "{}", t.get_kind()),
+        }
+    }
+}
+
+impl Serialize for CfhdbPostAction {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for CfhdbPostAction {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let action = String::deserialize(deserializer)?;
+        Self::parse(&action)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown post action: {}", action)))
+    }
+}
+
+// Unknown actions are skipped, so older builds still load databases using newer ones
+pub fn get_post_actions(profile: &Value) -> Vec<CfhdbPostAction> {
+    let mut actions: Vec<CfhdbPostAction> = profile["post_actions"]
+        .as_array()
+        .map(|t| {
+            t.iter()
+                .filter_map(|x| x.as_str().and_then(CfhdbPostAction::parse))
+                .collect()
+        })
+        .unwrap_or_default();
+    actions.sort();
+    actions.dedup();
+    actions
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CfhdbPendingAction {
+    pub action: CfhdbPostAction,
+    pub bus: String,
+    pub codename: String,
+    pub boot_id: String,
+    // Seconds since the unix epoch
+    pub created: u64,
+}
+
+pub fn get_boot_id() -> String {
+    match fs::read_to_string("/proc/sys/kernel/random/boot_id") {
+        Ok(t) => t.trim().to_string(),
+        Err(_) => "Unknown".to_string(),
+    }
+}
+
+pub fn load_pending_actions() -> Result<Vec<CfhdbPendingAction>, io::Error> {
+    let data = match fs::read_to_string(PENDING_ACTIONS_PATH) {
+        Ok(t) => t,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let actions: Vec<CfhdbPendingAction> =
+        serde_json::from_str(&data).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    let boot_id = get_boot_id();
+    Ok(actions
+        .into_iter()
+        .filter(|x| x.boot_id == boot_id)
+        .collect())
+}
+
+fn set_flag_file(path: &str, present: bool) -> Result<(), io::Error> {
+    if present {
+        write_state_file(path, &(get_boot_id() + "\n"))
+    } else if Path::new(path).exists() {
+        fs::remove_file(path)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn save_pending_actions(actions: &[CfhdbPendingAction]) -> Result<(), io::Error> {
+    let json_pretty = serde_json::to_string_pretty(actions)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    write_state_file(PENDING_ACTIONS_PATH, &(json_pretty + "\n"))?;
+    set_flag_file(
+        REBOOT_REQUIRED_FLAG_PATH,
+        actions.iter().any(|x| x.action == CfhdbPostAction::Reboot),
+    )?;
+    set_flag_file(
+        RELOGIN_REQUIRED_FLAG_PATH,
+        actions.iter().any(|x| x.action == CfhdbPostAction::Relogin),
+    )
+}
+
+// Records the post-actions of a profile transaction, an action already pending for the same profile is kept once
+pub fn add_pending_actions(
+    bus: &str,
+    codename: &str,
+    actions: &[CfhdbPostAction],
+) -> Result<(), io::Error> {
+    let mut pending = load_pending_actions()?;
+    let boot_id = get_boot_id();
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+    for action in actions {
+        if pending
+            .iter()
+            .any(|x| &x.action == action && x.bus == bus && x.codename == codename)
+        {
+            continue;
+        }
+        pending.push(CfhdbPendingAction {
+            action: action.clone(),
+            bus: bus.to_string(),
+            codename: codename.to_string(),
+            boot_id: boot_id.clone(),
+            created,
+        });
+    }
+    save_pending_actions(&pending)
+}
+
+fn get_shell_word(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+// Line for a transaction script that already runs as root, so recording needs no extra prompt.
+// A failure only leaves the actions unrecorded, the transaction itself still succeeded.
+pub fn get_add_pending_actions_command(
+    bus: &str,
+    codename: &str,
+    actions: &[CfhdbPostAction],
+) -> String {
+    if actions.is_empty() {
+        return String::new();
+    }
+    let words: Vec<String> = [SYSFS_HELPER_PATH, ADD_PENDING_ACTIONS, bus, codename]
+        .into_iter()
+        .map(get_shell_word)
+        .chain(actions.iter().map(|x| get_shell_word(&x.to_string())))
+        .collect();
+    format!("{} || true", words.join(" "))
+}
+
+// Runs cfhdb-sysfs-helper directly as root and through pkexec otherwise
+pub fn clear_pending_actions() -> Result<(), io::Error> {
+    if get_current_username().is_some_and(|x| x == "root") {
+        return save_pending_actions(&[]);
+    }
+    let output = duct::cmd!("pkexec", SYSFS_HELPER_PATH, CLEAR_PENDING_ACTIONS)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ))
+    }
+}
+
+// Privileged side, what cfhdb-sysfs-helper runs for ADD_PENDING_ACTIONS and CLEAR_PENDING_ACTIONS
+pub fn run_pending_actions_helper(args: &[String]) -> Result<(), io::Error> {
+    let invalid = |message: String| io::Error::new(ErrorKind::InvalidInput, message);
+    match args {
+        [action] if action == CLEAR_PENDING_ACTIONS => save_pending_actions(&[]),
+        [action, bus, codename, actions @ ..] if action == ADD_PENDING_ACTIONS => {
+            let actions = actions
+                .iter()
+                .map(|x| {
+                    CfhdbPostAction::parse(x)
+                        .ok_or_else(|| invalid(format!("unknown post action {}", x)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            add_pending_actions(bus, codename, &actions)
+        }
+        _ => Err(invalid(format!(
+            "expected {} BUS CODENAME ACTION... or {}",
+            ADD_PENDING_ACTIONS, CLEAR_PENDING_ACTIONS
+        ))),
+    }
+}
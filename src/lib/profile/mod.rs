@@ -0,0 +1,300 @@
+use serde_json::Value;
+use std::{
+    env, fs,
+    io::{self, ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use users::get_current_username;
+
+// Version of the profile database format understood by this build.
+// Version 0 is the original unversioned format, which used the "Option::is_none" string in place of null.
+pub const PROFILE_SCHEMA_VERSION: u64 = 1;
+
+// Check scripts run as root are written here, data/tmpfiles.d/cfhdb.conf creates it root-owned
+pub const ROOT_CHECK_SCRIPT_DIR: &str = "/run/cfhdb";
+static CHECK_SCRIPT_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+const LEGACY_NONE_SENTINEL: &str = "Option::is_none";
+const LEGACY_NULLABLE_FIELDS: [&str; 3] = ["packages", "install_script", "remove_script"];
+
+pub fn get_schema_version(database: &Value) -> Result<u64, io::Error> {
+    match &database["schema_version"] {
+        Value::Null => Ok(0),
+        value => value.as_u64().ok_or(io::Error::new(
+            ErrorKind::InvalidData,
+            "schema_version must be a non-negative integer",
+        )),
+    }
+}
+
+fn migrate_v0_profile(profile: &mut Value) {
+    for field in LEGACY_NULLABLE_FIELDS {
+        if profile[field].as_str() == Some(LEGACY_NONE_SENTINEL) {
+            profile[field] = Value::Null;
+        }
+    }
+}
+
+pub fn migrate_database(mut database: Value) -> Result<Value, io::Error> {
+    let schema_version = get_schema_version(&database)?;
+    if schema_version > PROFILE_SCHEMA_VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "profile database schema version {} is newer than the supported version {}",
+                schema_version, PROFILE_SCHEMA_VERSION
+            ),
+        ));
+    }
+    if schema_version == 0 {
+        if let Value::Array(profiles) = &mut database["profiles"] {
+            for profile in profiles.iter_mut() {
+                migrate_v0_profile(profile);
+            }
+        }
+    }
+    database["schema_version"] = Value::from(PROFILE_SCHEMA_VERSION);
+    Ok(database)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl ProfileFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("json") => Some(Self::Json),
+            Some("yaml") | Some("yml") => Some(Self::Yaml),
+            Some("toml") => Some(Self::Toml),
+            _ => None,
+        }
+    }
+}
+
+// YAML and TOML are authoring formats only, they are decoded into the same value tree as JSON
+pub fn decode_profile_data(data: &str, format: ProfileFormat) -> Result<Value, io::Error> {
+    match format {
+        ProfileFormat::Json => {
+            serde_json::from_str(data).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+        }
+        ProfileFormat::Yaml => {
+            serde_yaml::from_str(data).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+        }
+        ProfileFormat::Toml => {
+            toml::from_str(data).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+        }
+    }
+}
+
+pub fn parse_profile_database(data: &str) -> Result<Vec<Value>, io::Error> {
+    parse_profile_database_with_format(data, ProfileFormat::Json)
+}
+
+pub fn parse_profile_database_with_format(
+    data: &str,
+    format: ProfileFormat,
+) -> Result<Vec<Value>, io::Error> {
+    get_migrated_profiles(decode_profile_data(data, format)?)
+}
+
+fn get_migrated_profiles(database: Value) -> Result<Vec<Value>, io::Error> {
+    match migrate_database(database)? {
+        Value::Object(mut database) => match database.remove("profiles") {
+            Some(Value::Array(profiles)) => Ok(profiles),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "profile database has no profiles array",
+            )),
+        },
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "profile database must be a mapping",
+        )),
+    }
+}
+
+// A file either holds a whole database ("profiles" array) or a single profile
+fn read_profile_file(path: &Path) -> Result<Vec<Value>, io::Error> {
+    let format = ProfileFormat::from_path(path).ok_or(io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("unsupported profile file: {}", path.display()),
+    ))?;
+    let data = fs::read_to_string(path)?;
+    let with_path = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+    let mut value = decode_profile_data(&data, format).map_err(with_path)?;
+    if value["profiles"].is_array() {
+        return get_migrated_profiles(value).map_err(with_path);
+    }
+    let schema_version = match value.as_object_mut() {
+        Some(profile) => profile
+            .remove("schema_version")
+            .unwrap_or(Value::from(PROFILE_SCHEMA_VERSION)),
+        None => {
+            return Err(with_path(io::Error::new(
+                ErrorKind::InvalidData,
+                "profile must be a mapping",
+            )))
+        }
+    };
+    get_migrated_profiles(serde_json::json!({
+        "schema_version": schema_version,
+        "profiles": [value],
+    }))
+    .map_err(with_path)
+}
+
+pub fn load_profile_source(path: &Path) -> Result<Vec<Value>, io::Error> {
+    if !path.is_dir() {
+        return read_profile_file(path);
+    }
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|entry| entry.is_file() && ProfileFormat::from_path(entry).is_some())
+        .collect();
+    entries.sort();
+    let mut profiles = vec![];
+    for entry in entries {
+        profiles.extend(read_profile_file(&entry)?);
+    }
+    Ok(profiles)
+}
+
+// Profile URLs pointing at the local filesystem are loaded directly instead of being downloaded
+pub fn get_local_profile_source(url: &str) -> Option<PathBuf> {
+    if let Some(path) = url.strip_prefix("file://") {
+        Some(PathBuf::from(path))
+    } else if url.starts_with('/') {
+        Some(PathBuf::from(url))
+    } else {
+        None
+    }
+}
+
+pub fn compile_profile_database(profiles: Vec<Value>) -> Value {
+    serde_json::json!({
+        "schema_version": PROFILE_SCHEMA_VERSION,
+        "profiles": profiles,
+    })
+}
+
+pub fn get_string(profile: &Value, key: &str, default: &str) -> String {
+    profile[key].as_str().unwrap_or(default).to_string()
+}
+
+pub fn get_string_vec(profile: &Value, key: &str) -> Vec<String> {
+    get_optional_string_vec(profile, key).unwrap_or_default()
+}
+
+// Absent and null fields are both treated as None
+pub fn get_optional_string(profile: &Value, key: &str) -> Option<String> {
+    profile[key].as_str().map(|x| x.to_string())
+}
+
+pub fn get_optional_string_vec(profile: &Value, key: &str) -> Option<Vec<String>> {
+    profile[key].as_array().map(|t| {
+        t.iter()
+            .map(|x| x.as_str().unwrap_or_default().to_string())
+            .collect()
+    })
+}
+
+// "de_AT.UTF-8@euro" -> ["de_AT", "de"], most specific first
+pub fn get_locale_fallback_chain(locale: &str) -> Vec<String> {
+    let locale = locale
+        .split(['.', '@'])
+        .next()
+        .unwrap_or_default()
+        .replace('-', "_");
+    let mut chain = vec![];
+    let mut current = locale.as_str();
+    while !current.is_empty() {
+        chain.push(current.to_string());
+        current = match current.rfind('_') {
+            Some(n) => &current[..n],
+            None => "",
+        };
+    }
+    chain
+}
+
+pub fn get_i18n_desc(profile: &Value, locale: &str) -> String {
+    for fallback_locale in get_locale_fallback_chain(locale) {
+        match profile[format!("i18n_desc[{}]", fallback_locale)].as_str() {
+            Some(t) if !t.is_empty() => return t.to_string(),
+            _ => {}
+        }
+    }
+    get_string(profile, "i18n_desc", "")
+}
+
+fn write_check_script(path: &Path, check_script: &str) -> Result<(), io::Error> {
+    // O_EXCL and O_NOFOLLOW refuse anything another user planted at the path beforehand
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .mode(0o700)
+        .open(path)?;
+    file.write_all(format!("#! /bin/bash\nset -e\n{}", check_script).as_bytes())
+}
+
+// Shared by the get_status of every profile type, a script that can't be written counts as failed
+pub fn run_check_script(check_script: &str) -> bool {
+    let dir = if get_current_username().is_some_and(|x| x == "root") {
+        PathBuf::from(ROOT_CHECK_SCRIPT_DIR)
+    } else {
+        env::temp_dir()
+    };
+    let path = dir.join(format!(
+        "cfhdb-check-{}-{}.sh",
+        process::id(),
+        CHECK_SCRIPT_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    if write_check_script(&path, check_script).is_err() {
+        return false;
+    }
+    let result = duct::cmd!("bash", &path)
+        .stderr_to_stdout()
+        .stdout_null()
+        .run()
+        .is_ok();
+    let _ = fs::remove_file(&path);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locale_fallback_chain_drops_encoding_and_modifier() {
+        assert_eq!(
+            get_locale_fallback_chain("de_AT.UTF-8@euro"),
+            ["de_AT", "de"]
+        );
+        assert_eq!(get_locale_fallback_chain("pt-BR"), ["pt_BR", "pt"]);
+        assert_eq!(get_locale_fallback_chain("de"), ["de"]);
+    }
+
+    #[test]
+    fn locale_fallback_chain_keeps_every_level() {
+        assert_eq!(
+            get_locale_fallback_chain("sr_RS_latin"),
+            ["sr_RS_latin", "sr_RS", "sr"]
+        );
+    }
+
+    #[test]
+    fn empty_locale_has_no_fallback_chain() {
+        assert!(get_locale_fallback_chain("").is_empty());
+        assert!(get_locale_fallback_chain(".UTF-8").is_empty());
+    }
+}
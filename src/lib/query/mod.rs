@@ -0,0 +1,443 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::io::{self, ErrorKind};
+
+// A small filter language evaluated against the serialized form of a device, so the same
+// query works on PCI, USB and BT devices:
+//   class_id ^= 03 && available_profiles
+//   kernel_driver == Unknown || !(vendor_id == "10de")
+// == and != compare case-insensitively, ^= is a prefix match, ~= a substring match.
+// A field on its own is true when it is a true bool, a non-empty string or array, or a non-zero number.
+// Array fields (available_profiles) match when any element does.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryOperator {
+    Equal,
+    NotEqual,
+    Prefix,
+    Contains,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryExpr {
+    Truthy(String),
+    Compare(String, QueryOperator, String),
+    Not(Box<QueryExpr>),
+    And(Box<QueryExpr>, Box<QueryExpr>),
+    Or(Box<QueryExpr>, Box<QueryExpr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Operator(QueryOperator),
+    And,
+    Or,
+    Not,
+    LeftParen,
+    RightParen,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_-.:*/".contains(c)
+}
+
+fn query_error(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, message)
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, io::Error> {
+    let mut tokens = vec![];
+    let mut chars = query.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let two: String = chars.clone().take(2).collect();
+        let operator = match two.as_str() {
+            "==" => Some(Token::Operator(QueryOperator::Equal)),
+            "!=" => Some(Token::Operator(QueryOperator::NotEqual)),
+            "^=" => Some(Token::Operator(QueryOperator::Prefix)),
+            "~=" => Some(Token::Operator(QueryOperator::Contains)),
+            "&&" => Some(Token::And),
+            "||" => Some(Token::Or),
+            _ => None,
+        };
+        if let Some(token) = operator {
+            chars.nth(1);
+            tokens.push(token);
+            continue;
+        }
+        match c {
+            '!' => {
+                chars.next();
+                tokens.push(Token::Not);
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::LeftParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::RightParen);
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => match chars.next() {
+                            Some(t) => value.push(t),
+                            None => break,
+                        },
+                        Some(t) if t == c => {
+                            tokens.push(Token::Quoted(value));
+                            break;
+                        }
+                        Some(t) => value.push(t),
+                        None => {
+                            return Err(query_error(format!(
+                                "unterminated string in query: {}",
+                                query
+                            )))
+                        }
+                    }
+                }
+            }
+            c if is_word_char(c) => {
+                let mut word = String::new();
+                while let Some(&t) = chars.peek() {
+                    if !is_word_char(t) {
+                        break;
+                    }
+                    word.push(t);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+            c => {
+                return Err(query_error(format!(
+                    "unexpected '{}' in query: {}",
+                    c, query
+                )))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<QueryExpr, io::Error> {
+        let mut expr = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = QueryExpr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<QueryExpr, io::Error> {
+        let mut expr = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = QueryExpr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<QueryExpr, io::Error> {
+        match self.next() {
+            Some(Token::Not) => Ok(QueryExpr::Not(Box::new(self.parse_unary()?))),
+            Some(Token::LeftParen) => {
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RightParen) => Ok(expr),
+                    _ => Err(query_error("missing ')' in query".to_string())),
+                }
+            }
+            Some(Token::Word(field)) => match self.peek() {
+                Some(Token::Operator(operator)) => {
+                    let operator = *operator;
+                    self.next();
+                    match self.next() {
+                        Some(Token::Word(value)) | Some(Token::Quoted(value)) => {
+                            Ok(QueryExpr::Compare(field, operator, value))
+                        }
+                        _ => Err(query_error(format!("missing value after {}", field))),
+                    }
+                }
+                _ => Ok(QueryExpr::Truthy(field)),
+            },
+            Some(t) => Err(query_error(format!("unexpected {:?} in query", t))),
+            None => Err(query_error("unexpected end of query".to_string())),
+        }
+    }
+}
+
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(t) => *t,
+        Value::Number(t) => t.as_f64() != Some(0.0),
+        Value::String(t) => !t.is_empty(),
+        Value::Array(t) => !t.is_empty(),
+        Value::Object(t) => !t.is_empty(),
+    }
+}
+
+fn compare_scalar(value: &Value, operator: QueryOperator, expected: &str) -> bool {
+    let value = match value {
+        Value::Null => String::new(),
+        Value::String(t) => t.to_lowercase(),
+        t => t.to_string().to_lowercase(),
+    };
+    let expected = expected.to_lowercase();
+    match operator {
+        QueryOperator::Equal | QueryOperator::NotEqual => value == expected,
+        QueryOperator::Prefix => value.starts_with(&expected),
+        QueryOperator::Contains => value.contains(&expected),
+    }
+}
+
+impl QueryExpr {
+    pub fn parse(query: &str) -> Result<Self, io::Error> {
+        let mut parser = Parser {
+            tokens: tokenize(query)?,
+            position: 0,
+        };
+        let expr = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(t) => Err(query_error(format!("unexpected {:?} in query", t))),
+        }
+    }
+
+    pub fn and(self, other: Self) -> Self {
+        QueryExpr::And(Box::new(self), Box::new(other))
+    }
+
+    pub fn get_fields(&self) -> Vec<&str> {
+        match self {
+            QueryExpr::Truthy(field) | QueryExpr::Compare(field, _, _) => vec![field.as_str()],
+            QueryExpr::Not(expr) => expr.get_fields(),
+            QueryExpr::And(a, b) | QueryExpr::Or(a, b) => {
+                let mut fields = a.get_fields();
+                fields.extend(b.get_fields());
+                fields
+            }
+        }
+    }
+
+    pub fn matches_value(&self, record: &Value) -> bool {
+        match self {
+            QueryExpr::Truthy(field) => is_truthy(&record[field]),
+            QueryExpr::Compare(field, operator, expected) => {
+                let matched = match &record[field] {
+                    Value::Array(t) => t.iter().any(|x| compare_scalar(x, *operator, expected)),
+                    t => compare_scalar(t, *operator, expected),
+                };
+                if *operator == QueryOperator::NotEqual {
+                    !matched
+                } else {
+                    matched
+                }
+            }
+            QueryExpr::Not(expr) => !expr.matches_value(record),
+            QueryExpr::And(a, b) => a.matches_value(record) && b.matches_value(record),
+            QueryExpr::Or(a, b) => a.matches_value(record) || b.matches_value(record),
+        }
+    }
+
+    pub fn matches<T: Serialize>(&self, item: &T) -> bool {
+        match serde_json::to_value(item) {
+            Ok(t) => self.matches_value(&t),
+            Err(_) => false,
+        }
+    }
+
+    // Unknown fields would silently match nothing, so they are checked against a sample record
+    pub fn validate_fields(&self, record: &Value) -> Result<(), io::Error> {
+        match self
+            .get_fields()
+            .into_iter()
+            .find(|x| record.get(x).is_none())
+        {
+            Some(field) => Err(query_error(format!("unknown field in query: {}", field))),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn filter_items<T: Serialize>(items: Vec<T>, query: &QueryExpr) -> Result<Vec<T>, io::Error> {
+    if let Some(first) = items.first() {
+        let record =
+            serde_json::to_value(first).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        query.validate_fields(&record)?;
+    }
+    Ok(items.into_iter().filter(|x| query.matches(x)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn truthy(field: &str) -> QueryExpr {
+        QueryExpr::Truthy(field.to_string())
+    }
+
+    fn compare(field: &str, operator: QueryOperator, value: &str) -> QueryExpr {
+        QueryExpr::Compare(field.to_string(), operator, value.to_string())
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            QueryExpr::parse("a || b && c").unwrap(),
+            QueryExpr::Or(
+                Box::new(truthy("a")),
+                Box::new(truthy("b").and(truthy("c")))
+            )
+        );
+        assert_eq!(
+            QueryExpr::parse("(a || b) && c").unwrap(),
+            QueryExpr::Or(Box::new(truthy("a")), Box::new(truthy("b"))).and(truthy("c"))
+        );
+    }
+
+    #[test]
+    fn not_applies_to_the_next_operand_only() {
+        assert_eq!(
+            QueryExpr::parse("!a && b").unwrap(),
+            QueryExpr::Not(Box::new(truthy("a"))).and(truthy("b"))
+        );
+        assert_eq!(
+            QueryExpr::parse("!(a && b)").unwrap(),
+            QueryExpr::Not(Box::new(truthy("a").and(truthy("b"))))
+        );
+    }
+
+    #[test]
+    fn comparison_operators_are_parsed() {
+        for (query, operator) in [
+            ("x == 1", QueryOperator::Equal),
+            ("x != 1", QueryOperator::NotEqual),
+            ("x ^= 1", QueryOperator::Prefix),
+            ("x ~= 1", QueryOperator::Contains),
+        ] {
+            assert_eq!(
+                QueryExpr::parse(query).unwrap(),
+                compare("x", operator, "1")
+            );
+        }
+    }
+
+    #[test]
+    fn quoted_values_keep_spaces_and_operators() {
+        assert_eq!(
+            QueryExpr::parse(r#"name == "GeForce RTX && 4090""#).unwrap(),
+            compare("name", QueryOperator::Equal, "GeForce RTX && 4090")
+        );
+        assert_eq!(
+            QueryExpr::parse(r#"name ~= 'it\'s'"#).unwrap(),
+            compare("name", QueryOperator::Contains, "it's")
+        );
+        assert_eq!(
+            QueryExpr::parse(r#"name == """#).unwrap(),
+            compare("name", QueryOperator::Equal, "")
+        );
+    }
+
+    #[test]
+    fn malformed_queries_are_errors() {
+        for query in [
+            "",
+            "   ",
+            "a ==",
+            "== a",
+            "a &&",
+            "|| a",
+            "(a",
+            "a)",
+            "()",
+            "a b",
+            "a & b",
+            "a = b",
+            "!",
+            r#"name == "unterminated"#,
+            r#"name == "escaped end\"#,
+            "a == (b)",
+        ] {
+            assert!(QueryExpr::parse(query).is_err(), "{} parsed", query);
+        }
+    }
+
+    #[test]
+    fn comparisons_ignore_case() {
+        let record = json!({ "vendor_name": "NVIDIA Corporation", "class_id": "0300" });
+        assert!(QueryExpr::parse("vendor_name ~= nvidia")
+            .unwrap()
+            .matches_value(&record));
+        assert!(QueryExpr::parse("class_id ^= 03")
+            .unwrap()
+            .matches_value(&record));
+        assert!(!QueryExpr::parse("class_id ^= 3")
+            .unwrap()
+            .matches_value(&record));
+        assert!(QueryExpr::parse("vendor_name != amd")
+            .unwrap()
+            .matches_value(&record));
+    }
+
+    #[test]
+    fn array_fields_match_any_element() {
+        let record = json!({ "available_profiles": ["nvidia-open", "nvidia-580"], "empty": [] });
+        let matches = |query: &str| QueryExpr::parse(query).unwrap().matches_value(&record);
+        assert!(matches("available_profiles == nvidia-580"));
+        assert!(matches("available_profiles ^= nvidia"));
+        assert!(!matches("available_profiles == mesa"));
+        // != holds when no element is equal
+        assert!(!matches("available_profiles != nvidia-open"));
+        assert!(matches("available_profiles != mesa"));
+        assert!(matches("available_profiles && !empty"));
+    }
+
+    #[test]
+    fn bare_fields_are_truthy_checks() {
+        let record = json!({ "yes": true, "no": false, "zero": 0, "count": 2, "name": "" });
+        let matches = |query: &str| QueryExpr::parse(query).unwrap().matches_value(&record);
+        assert!(matches("yes"));
+        assert!(!matches("no"));
+        assert!(!matches("zero"));
+        assert!(matches("count"));
+        assert!(!matches("name"));
+        assert!(matches("count == 2"));
+    }
+
+    #[test]
+    fn unknown_fields_are_errors() {
+        let items = vec![json!({ "vendor_id": "10de" })];
+        let query = QueryExpr::parse("vendor_id == 10de || vendr_id == 10de").unwrap();
+        let error = filter_items(items.clone(), &query).unwrap_err();
+        assert!(error.to_string().contains("vendr_id"));
+        let query = QueryExpr::parse("vendor_id == 10de").unwrap();
+        assert_eq!(filter_items(items, &query).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,133 @@
+use serde::Serialize;
+
+// Picks one profile per device across all buses. A device with an installed profile keeps it,
+// otherwise lower priority values are preferred. Experimental and veiled profiles are never picked
+// unless already installed, and a profile is skipped when it conflicts with an installed profile
+// or one picked for an earlier device.
+
+// What the resolver needs to know about a matched profile, filled in by the caller for each bus
+#[derive(Debug, Clone)]
+pub struct CfhdbProfileCandidate {
+    pub codename: String,
+    pub priority: i32,
+    pub experimental: bool,
+    pub veiled: bool,
+    pub installed: bool,
+    pub conflicts: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CfhdbDeviceCandidates {
+    pub bus: String,
+    pub id: String,
+    pub name: String,
+    pub profiles: Vec<CfhdbProfileCandidate>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CfhdbRecommendationStatus {
+    // A profile for the device is already installed, nothing else is recommended for it even when
+    // a preferred one exists
+    Installed,
+    Install,
+    // Every eligible profile conflicts with an installed or already picked one
+    Conflict,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CfhdbRecommendation {
+    pub bus: String,
+    pub id: String,
+    pub name: String,
+    pub codename: Option<String>,
+    pub status: CfhdbRecommendationStatus,
+}
+
+fn is_conflicting(a: &CfhdbProfileCandidate, b: &CfhdbProfileCandidate) -> bool {
+    a.codename != b.codename
+        && (a.conflicts.contains(&b.codename) || b.conflicts.contains(&a.codename))
+}
+
+// Devices are resolved in the given order, earlier devices win conflicts
+pub fn resolve_recommendations(devices: &[CfhdbDeviceCandidates]) -> Vec<CfhdbRecommendation> {
+    let mut selected: Vec<&CfhdbProfileCandidate> = devices
+        .iter()
+        .flat_map(|x| x.profiles.iter())
+        .filter(|x| x.installed)
+        .collect();
+    let mut recommendations = vec![];
+    for device in devices {
+        let mut candidates: Vec<&CfhdbProfileCandidate> = device
+            .profiles
+            .iter()
+            .filter(|x| x.installed || (!x.experimental && !x.veiled))
+            .collect();
+        if candidates.is_empty() {
+            continue;
+        }
+        candidates.sort_by_key(|x| x.priority);
+        // Installing a second profile next to an installed one would give the device two drivers
+        let picked = match candidates.iter().find(|x| x.installed) {
+            Some(t) => Some(*t),
+            None => candidates
+                .into_iter()
+                .find(|x| !selected.iter().any(|y| is_conflicting(x, y))),
+        };
+        let (codename, status) = match picked {
+            Some(t) if t.installed => (
+                Some(t.codename.clone()),
+                CfhdbRecommendationStatus::Installed,
+            ),
+            Some(t) => {
+                selected.push(t);
+                (Some(t.codename.clone()), CfhdbRecommendationStatus::Install)
+            }
+            None => (None, CfhdbRecommendationStatus::Conflict),
+        };
+        recommendations.push(CfhdbRecommendation {
+            bus: device.bus.clone(),
+            id: device.id.clone(),
+            name: device.name.clone(),
+            codename,
+            status,
+        });
+    }
+    recommendations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_candidate(codename: &str, priority: i32, installed: bool) -> CfhdbProfileCandidate {
+        CfhdbProfileCandidate {
+            codename: codename.to_string(),
+            priority,
+            experimental: false,
+            veiled: false,
+            installed,
+            conflicts: vec![],
+        }
+    }
+
+    #[test]
+    fn installed_lower_priority_profile_is_kept() {
+        let devices = vec![CfhdbDeviceCandidates {
+            bus: "pci".to_string(),
+            id: "0000:01:00.0".to_string(),
+            name: "GPU".to_string(),
+            profiles: vec![
+                get_candidate("preferred", 10, false),
+                get_candidate("fallback", 20, true),
+            ],
+        }];
+        let recommendations = resolve_recommendations(&devices);
+        assert_eq!(recommendations.len(), 1);
+        assert_eq!(recommendations[0].codename.as_deref(), Some("fallback"));
+        assert_eq!(
+            recommendations[0].status,
+            CfhdbRecommendationStatus::Installed
+        );
+    }
+}
@@ -0,0 +1,515 @@
+use crate::{bt::CfhdbBtDevice, dmi::CfhdbDmiInfo, pci::CfhdbPciDevice, usb::CfhdbUsbDevice};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Bumped whenever a field is removed or changes meaning, older readers refuse newer reports
+pub const REPORT_VERSION: u64 = 1;
+
+// Codenames of the matched profiles whose check script passed when the report was made
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CfhdbInstalledProfiles {
+    pub pci: Vec<String>,
+    pub usb: Vec<String>,
+    pub dmi: Vec<String>,
+    pub bt: Vec<String>,
+}
+
+// One file with everything needed to match profiles for a machine without having it at hand.
+// Devices keep their matched profiles as codenames, they are matched again when loaded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CfhdbReport {
+    pub report_version: u64,
+    pub cfhdb_version: String,
+    // Seconds since the unix epoch
+    pub created: u64,
+    pub kernel_version: String,
+    pub dmi_info: CfhdbDmiInfo,
+    pub pci_devices: Vec<CfhdbPciDevice>,
+    pub usb_devices: Vec<CfhdbUsbDevice>,
+    pub bt_devices: Vec<CfhdbBtDevice>,
+    pub installed_profiles: CfhdbInstalledProfiles,
+}
+
+impl CfhdbReport {
+    pub fn new(
+        dmi_info: CfhdbDmiInfo,
+        pci_devices: Vec<CfhdbPciDevice>,
+        usb_devices: Vec<CfhdbUsbDevice>,
+        bt_devices: Vec<CfhdbBtDevice>,
+        installed_profiles: CfhdbInstalledProfiles,
+    ) -> Self {
+        Self {
+            report_version: REPORT_VERSION,
+            cfhdb_version: env!("CARGO_PKG_VERSION").to_string(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or_default(),
+            kernel_version: Self::get_kernel_version(),
+            dmi_info,
+            pci_devices,
+            usb_devices,
+            bt_devices,
+            installed_profiles,
+        }
+    }
+
+    pub fn get_kernel_version() -> String {
+        match fs::read_to_string("/proc/sys/kernel/osrelease") {
+            Ok(t) => t.trim().to_string(),
+            Err(_) => "Unknown".to_string(),
+        }
+    }
+
+    // Loading into devices drops the recorded profile codenames, comparing reports works on this instead
+    pub fn read_value(path: &Path) -> Result<Value, io::Error> {
+        let data = fs::read_to_string(path)?;
+        let report: Value =
+            serde_json::from_str(&data).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        match report["report_version"].as_u64() {
+            Some(t) if t <= REPORT_VERSION => Ok(report),
+            Some(t) => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "report version {} is newer than the supported version {}",
+                    t, REPORT_VERSION
+                ),
+            )),
+            None => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "not a cfhdb report, report_version is missing",
+            )),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, io::Error> {
+        serde_json::from_value(Self::read_value(path)?)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    pub fn write_to_file(&self, path: &Path) -> Result<(), io::Error> {
+        let json_pretty = serde_json::to_string_pretty(self)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        fs::write(path, json_pretty + "\n")
+    }
+}
+
+struct DiffDeviceList {
+    bus: &'static str,
+    // Report field holding the devices
+    list_field: &'static str,
+    // Identifies a device across reports
+    id_field: &'static str,
+    // A removed and an added device that agree on these moved to another port or slot
+    identity_fields: &'static [&'static str],
+    name_fields: &'static [&'static str],
+    compared_fields: &'static [&'static str],
+}
+
+const DIFF_DEVICE_LISTS: [DiffDeviceList; 3] = [
+    DiffDeviceList {
+        bus: "pci",
+        list_field: "pci_devices",
+        id_field: "sysfs_busid",
+        identity_fields: &["vendor_id", "device_id"],
+        name_fields: &["vendor_name", "device_name"],
+        // A different card in the same slot keeps the bus id, so the IDs are compared as well
+        compared_fields: &[
+            "vendor_id",
+            "device_id",
+            "kernel_driver",
+            "started",
+            "enabled",
+            "available_profiles",
+        ],
+    },
+    DiffDeviceList {
+        bus: "usb",
+        list_field: "usb_devices",
+        id_field: "sysfs_busid",
+        identity_fields: &["vendor_id", "product_id", "serial_number_string_index"],
+        name_fields: &["manufacturer_string_index", "product_string_index"],
+        compared_fields: &[
+            "vendor_id",
+            "product_id",
+            "kernel_driver",
+            "started",
+            "enabled",
+            "available_profiles",
+        ],
+    },
+    DiffDeviceList {
+        bus: "bt",
+        list_field: "bt_devices",
+        id_field: "address",
+        identity_fields: &[],
+        name_fields: &["alias"],
+        compared_fields: &[
+            "paired",
+            "connected",
+            "trusted",
+            "blocked",
+            "available_profiles",
+        ],
+    },
+];
+
+const DIFF_DMI_FIELDS: [&str; 4] = [
+    "bios_vendor",
+    "bios_version",
+    "bios_date",
+    "available_profiles",
+];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CfhdbDiffStatus {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CfhdbFieldChange {
+    pub field: String,
+    pub before: Value,
+    pub after: Value,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CfhdbDeviceDiff {
+    pub bus: String,
+    pub id: String,
+    pub name: String,
+    pub status: CfhdbDiffStatus,
+    // Empty for added and removed devices
+    pub changes: Vec<CfhdbFieldChange>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CfhdbReportDiff {
+    pub kernel_version: Option<CfhdbFieldChange>,
+    pub devices: Vec<CfhdbDeviceDiff>,
+}
+
+// installed_profiles is not a device field, it is the part of the matched profiles that was installed
+fn get_installed_profiles(device: &Value, installed: &Value) -> Value {
+    let installed = installed.as_array().cloned().unwrap_or_default();
+    Value::Array(
+        device["available_profiles"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|x| installed.contains(x))
+            .collect(),
+    )
+}
+
+fn get_field_changes(before: &Value, after: &Value, fields: &[&str]) -> Vec<CfhdbFieldChange> {
+    fields
+        .iter()
+        .chain(["installed_profiles"].iter())
+        .filter(|x| before[**x] != after[**x])
+        .map(|x| CfhdbFieldChange {
+            field: x.to_string(),
+            before: before[*x].clone(),
+            after: after[*x].clone(),
+        })
+        .collect()
+}
+
+fn get_device_name(device: &Value, fields: &[&str]) -> String {
+    fields
+        .iter()
+        .filter_map(|x| device[*x].as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Devices keyed by id and each with its installed profiles added, sorted so the diff is stable
+fn get_diff_devices(
+    report: &Value,
+    bus: &str,
+    list_field: &str,
+    id_field: &str,
+) -> BTreeMap<String, Value> {
+    let mut devices = BTreeMap::new();
+    for device in report[list_field].as_array().cloned().unwrap_or_default() {
+        let mut device = device;
+        device["installed_profiles"] =
+            get_installed_profiles(&device, &report["installed_profiles"][bus]);
+        let id = match &device[id_field] {
+            Value::String(t) => t.clone(),
+            t => t.to_string(),
+        };
+        devices.insert(id, device);
+    }
+    devices
+}
+
+fn is_same_device(before: &Value, after: &Value, identity_fields: &[&str]) -> bool {
+    !identity_fields.is_empty() && identity_fields.iter().all(|x| before[*x] == after[*x])
+}
+
+pub fn diff_reports(before: &Value, after: &Value) -> CfhdbReportDiff {
+    let mut devices = vec![];
+    let mut dmi_before = before["dmi_info"].clone();
+    let mut dmi_after = after["dmi_info"].clone();
+    dmi_before["installed_profiles"] =
+        get_installed_profiles(&dmi_before, &before["installed_profiles"]["dmi"]);
+    dmi_after["installed_profiles"] =
+        get_installed_profiles(&dmi_after, &after["installed_profiles"]["dmi"]);
+    let dmi_changes = get_field_changes(&dmi_before, &dmi_after, &DIFF_DMI_FIELDS);
+    if !dmi_changes.is_empty() {
+        devices.push(CfhdbDeviceDiff {
+            bus: "dmi".to_string(),
+            id: get_device_name(&dmi_after, &["board_name"]),
+            name: get_device_name(&dmi_after, &["sys_vendor", "product_name"]),
+            status: CfhdbDiffStatus::Changed,
+            changes: dmi_changes,
+        });
+    }
+    for list in DIFF_DEVICE_LISTS {
+        let devices_before = get_diff_devices(before, list.bus, list.list_field, list.id_field);
+        let mut devices_after = get_diff_devices(after, list.bus, list.list_field, list.id_field);
+        let mut removed = vec![];
+        for (id, device_before) in devices_before {
+            let device_after = match devices_after.remove(&id) {
+                Some(t) => t,
+                None => {
+                    removed.push((id, device_before));
+                    continue;
+                }
+            };
+            let changes = get_field_changes(&device_before, &device_after, list.compared_fields);
+            if !changes.is_empty() {
+                devices.push(CfhdbDeviceDiff {
+                    bus: list.bus.to_string(),
+                    id,
+                    name: get_device_name(&device_after, list.name_fields),
+                    status: CfhdbDiffStatus::Changed,
+                    changes,
+                });
+            }
+        }
+        for (id, device_before) in removed {
+            let moved_id = devices_after
+                .iter()
+                .find(|(_, x)| is_same_device(&device_before, x, list.identity_fields))
+                .map(|(x, _)| x.clone());
+            let (status, changes, id, device) =
+                match moved_id.and_then(|x| devices_after.remove_entry(&x)) {
+                    Some((moved_id, device_after)) => {
+                        let mut changes = vec![CfhdbFieldChange {
+                            field: list.id_field.to_string(),
+                            before: Value::String(id),
+                            after: Value::String(moved_id.clone()),
+                        }];
+                        changes.extend(get_field_changes(
+                            &device_before,
+                            &device_after,
+                            list.compared_fields,
+                        ));
+                        (CfhdbDiffStatus::Changed, changes, moved_id, device_after)
+                    }
+                    None => (CfhdbDiffStatus::Removed, vec![], id, device_before),
+                };
+            devices.push(CfhdbDeviceDiff {
+                bus: list.bus.to_string(),
+                id,
+                name: get_device_name(&device, list.name_fields),
+                status,
+                changes,
+            });
+        }
+        for (id, device_after) in devices_after {
+            devices.push(CfhdbDeviceDiff {
+                bus: list.bus.to_string(),
+                id,
+                name: get_device_name(&device_after, list.name_fields),
+                status: CfhdbDiffStatus::Added,
+                changes: vec![],
+            });
+        }
+    }
+    CfhdbReportDiff {
+        kernel_version: if before["kernel_version"] != after["kernel_version"] {
+            Some(CfhdbFieldChange {
+                field: "kernel_version".to_string(),
+                before: before["kernel_version"].clone(),
+                after: after["kernel_version"].clone(),
+            })
+        } else {
+            None
+        },
+        devices,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn get_pci_device(busid: &str, device_id: &str, driver: &str) -> Value {
+        json!({
+            "sysfs_busid": busid,
+            "vendor_id": "10de",
+            "device_id": device_id,
+            "vendor_name": "NVIDIA Corporation",
+            "device_name": device_id,
+            "kernel_driver": driver,
+            "started": true,
+            "enabled": true,
+            "available_profiles": [],
+        })
+    }
+
+    fn get_usb_device(busid: &str, serial: &str) -> Value {
+        json!({
+            "sysfs_busid": busid,
+            "vendor_id": "17ef",
+            "product_id": "a396",
+            "serial_number_string_index": serial,
+            "manufacturer_string_index": "Lenovo",
+            "product_string_index": "ThinkPad Dock",
+            "kernel_driver": "hub",
+            "started": true,
+            "enabled": true,
+            "available_profiles": [],
+        })
+    }
+
+    fn get_report(pci_devices: Vec<Value>, usb_devices: Vec<Value>) -> Value {
+        json!({
+            "report_version": REPORT_VERSION,
+            "kernel_version": "6.12.0",
+            "dmi_info": { "bios_vendor": "LENOVO", "available_profiles": [] },
+            "pci_devices": pci_devices,
+            "usb_devices": usb_devices,
+            "bt_devices": [],
+            "installed_profiles": { "pci": [], "usb": [], "dmi": [], "bt": [] },
+        })
+    }
+
+    fn get_changed_fields(diff: &CfhdbDeviceDiff) -> Vec<&str> {
+        diff.changes.iter().map(|x| x.field.as_str()).collect()
+    }
+
+    #[test]
+    fn identical_reports_have_no_differences() {
+        let report = get_report(
+            vec![get_pci_device("0000:01:00.0", "2684", "nvidia")],
+            vec![],
+        );
+        let diff = diff_reports(&report, &report);
+        assert!(diff.kernel_version.is_none());
+        assert!(diff.devices.is_empty());
+    }
+
+    #[test]
+    fn added_and_removed_devices_are_listed() {
+        let before = get_report(
+            vec![get_pci_device("0000:01:00.0", "2684", "nvidia")],
+            vec![],
+        );
+        let after = get_report(
+            vec![get_pci_device("0000:02:00.0", "1b80", "nouveau")],
+            vec![],
+        );
+        let diff = diff_reports(&before, &after);
+        assert_eq!(diff.devices.len(), 2);
+        assert_eq!(diff.devices[0].id, "0000:01:00.0");
+        assert_eq!(diff.devices[0].status, CfhdbDiffStatus::Removed);
+        assert_eq!(diff.devices[1].id, "0000:02:00.0");
+        assert_eq!(diff.devices[1].status, CfhdbDiffStatus::Added);
+        assert!(diff.devices.iter().all(|x| x.changes.is_empty()));
+    }
+
+    #[test]
+    fn changed_fields_are_listed() {
+        let before = get_report(
+            vec![get_pci_device("0000:01:00.0", "2684", "nouveau")],
+            vec![],
+        );
+        let mut after = get_report(
+            vec![get_pci_device("0000:01:00.0", "2684", "nvidia")],
+            vec![],
+        );
+        after["kernel_version"] = json!("6.13.0");
+        let diff = diff_reports(&before, &after);
+        assert_eq!(diff.kernel_version.unwrap().after, json!("6.13.0"));
+        assert_eq!(diff.devices.len(), 1);
+        assert_eq!(diff.devices[0].status, CfhdbDiffStatus::Changed);
+        assert_eq!(get_changed_fields(&diff.devices[0]), ["kernel_driver"]);
+        assert_eq!(diff.devices[0].changes[0].before, json!("nouveau"));
+    }
+
+    #[test]
+    fn different_card_in_the_same_slot_is_a_change() {
+        let before = get_report(
+            vec![
+                get_pci_device("0000:01:00.0", "2684", "nvidia"),
+                get_pci_device("0000:02:00.0", "1b80", "nvidia"),
+            ],
+            vec![],
+        );
+        let after = get_report(
+            vec![
+                get_pci_device("0000:01:00.0", "1b80", "nvidia"),
+                get_pci_device("0000:02:00.0", "2684", "nvidia"),
+            ],
+            vec![],
+        );
+        let diff = diff_reports(&before, &after);
+        assert_eq!(diff.devices.len(), 2);
+        for device in &diff.devices {
+            assert_eq!(device.status, CfhdbDiffStatus::Changed);
+            assert_eq!(get_changed_fields(device), ["device_id"]);
+        }
+    }
+
+    #[test]
+    fn device_on_another_port_is_a_move() {
+        let before = get_report(vec![], vec![get_usb_device("3-1", "S1")]);
+        let after = get_report(vec![], vec![get_usb_device("3-2", "S1")]);
+        let diff = diff_reports(&before, &after);
+        assert_eq!(diff.devices.len(), 1);
+        assert_eq!(diff.devices[0].id, "3-2");
+        assert_eq!(diff.devices[0].status, CfhdbDiffStatus::Changed);
+        assert_eq!(get_changed_fields(&diff.devices[0]), ["sysfs_busid"]);
+        assert_eq!(diff.devices[0].changes[0].before, json!("3-1"));
+    }
+
+    #[test]
+    fn device_with_another_serial_is_not_a_move() {
+        let before = get_report(vec![], vec![get_usb_device("3-1", "S1")]);
+        let after = get_report(vec![], vec![get_usb_device("3-2", "S2")]);
+        let statuses: Vec<_> = diff_reports(&before, &after)
+            .devices
+            .iter()
+            .map(|x| x.status)
+            .collect();
+        assert_eq!(statuses, [CfhdbDiffStatus::Removed, CfhdbDiffStatus::Added]);
+    }
+
+    #[test]
+    fn installed_profiles_are_compared() {
+        let mut device = get_pci_device("0000:01:00.0", "2684", "nvidia");
+        device["available_profiles"] = json!(["nvidia-open"]);
+        let before = get_report(vec![device.clone()], vec![]);
+        let mut after = get_report(vec![device], vec![]);
+        after["installed_profiles"]["pci"] = json!(["nvidia-open"]);
+        let diff = diff_reports(&before, &after);
+        assert_eq!(diff.devices.len(), 1);
+        assert_eq!(get_changed_fields(&diff.devices[0]), ["installed_profiles"]);
+    }
+}
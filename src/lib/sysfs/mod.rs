@@ -0,0 +1,1321 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+use users::get_current_username;
+
+// Device state changes need root. They go through cfhdb-sysfs-helper, which pkexec runs for
+// unprivileged users and which reports a failure as one JSON CfhdbSysfsError line on stdout.
+pub const SYSFS_HELPER_PATH: &str = "/usr/lib/cfhdb/cfhdb-sysfs-helper";
+// A stopped device is bound again after a reboot, so the state lives under /run.
+// data/tmpfiles.d/cfhdb.conf creates the directory.
+pub const STOPPED_DEVICES_PATH: &str = "/run/cfhdb/stopped_devices.json";
+// Regenerated whenever a blacklist changes and by "cfhdb apply-blacklist" at boot
+pub const BLACKLIST_UDEV_RULES_PATH: &str = "/etc/udev/rules.d/61-cfhdb-blacklist.rules";
+// No driver has this name, so a PCI device with it as driver_override stays unbound
+pub const BLACKLIST_DRIVER_OVERRIDE: &str = "none";
+pub const DRIVER_OVERRIDES_PATH: &str = "/etc/cfhdb/pci_driver_overrides.json";
+// Sorted before the blacklist rules, so a blacklisted device stays unbound either way
+pub const DRIVER_OVERRIDE_UDEV_RULES_PATH: &str =
+    "/etc/udev/rules.d/60-cfhdb-driver-override.rules";
+pub const RUNTIME_PM_PATH: &str = "/etc/cfhdb/pci_runtime_pm.json";
+// Sorted after the blacklist rules, it only changes power/control and never the driver
+pub const RUNTIME_PM_UDEV_RULES_PATH: &str = "/etc/udev/rules.d/62-cfhdb-runtime-pm.rules";
+// Bus ID rescan_bus takes to rescan every PCI bus instead of the one below a bridge
+pub const RESCAN_ALL_BUSES: &str = "all";
+// pkexec exit codes for a dismissed dialog and a denied request
+const PKEXEC_DISMISSED: i32 = 126;
+const PKEXEC_NOT_AUTHORIZED: i32 = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CfhdbSysfsBus {
+    Pci,
+    Usb,
+}
+
+impl CfhdbSysfsBus {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pci" => Some(Self::Pci),
+            "usb" => Some(Self::Usb),
+            _ => None,
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Self::Pci => "pci",
+            Self::Usb => "usb",
+        }
+    }
+
+    pub fn get_blacklist_path(&self) -> &'static str {
+        match self {
+            Self::Pci => "/etc/cfhdb/pci_blacklist",
+            Self::Usb => "/etc/cfhdb/usb_blacklist",
+        }
+    }
+
+    // USB drivers bind to the first interface of a device, not the device itself
+    pub fn get_driver_target(&self, busid: &str) -> String {
+        match self {
+            Self::Pci => busid.to_string(),
+            Self::Usb => format!("{}:1.0", busid),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CfhdbSysfsAction {
+    StartDevice,
+    StopDevice,
+    EnableDevice,
+    DisableDevice,
+    // PCI only, these take a driver, without one the override is cleared
+    BindDevice,
+    BindDevicePersistent,
+    // PCI only, binds every device of the IOMMU group except bridges to the driver
+    BindIommuGroup,
+    BindIommuGroupPersistent,
+    // PCI only, these take the power/control value
+    SetRuntimePm,
+    SetRuntimePmPersistent,
+    // PCI only, reset takes an optional reset method
+    ResetDevice,
+    RemoveDevice,
+    RescanBus,
+    // PCI only, takes the number of virtual functions
+    SetVfCount,
+}
+
+impl CfhdbSysfsAction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "start_device" => Some(Self::StartDevice),
+            "stop_device" => Some(Self::StopDevice),
+            "enable_device" => Some(Self::EnableDevice),
+            "disable_device" => Some(Self::DisableDevice),
+            "bind_device" => Some(Self::BindDevice),
+            "bind_device_persistent" => Some(Self::BindDevicePersistent),
+            "bind_iommu_group" => Some(Self::BindIommuGroup),
+            "bind_iommu_group_persistent" => Some(Self::BindIommuGroupPersistent),
+            "set_runtime_pm" => Some(Self::SetRuntimePm),
+            "set_runtime_pm_persistent" => Some(Self::SetRuntimePmPersistent),
+            "reset_device" => Some(Self::ResetDevice),
+            "remove_device" => Some(Self::RemoveDevice),
+            "rescan_bus" => Some(Self::RescanBus),
+            "set_vf_count" => Some(Self::SetVfCount),
+            _ => None,
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Self::StartDevice => "start_device",
+            Self::StopDevice => "stop_device",
+            Self::EnableDevice => "enable_device",
+            Self::DisableDevice => "disable_device",
+            Self::BindDevice => "bind_device",
+            Self::BindDevicePersistent => "bind_device_persistent",
+            Self::BindIommuGroup => "bind_iommu_group",
+            Self::BindIommuGroupPersistent => "bind_iommu_group_persistent",
+            Self::SetRuntimePm => "set_runtime_pm",
+            Self::SetRuntimePmPersistent => "set_runtime_pm_persistent",
+            Self::ResetDevice => "reset_device",
+            Self::RemoveDevice => "remove_device",
+            Self::RescanBus => "rescan_bus",
+            Self::SetVfCount => "set_vf_count",
+        }
+    }
+}
+
+// power/control of a device, "auto" lets it suspend while idle and "on" keeps it powered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CfhdbRuntimePmControl {
+    Auto,
+    On,
+}
+
+impl CfhdbRuntimePmControl {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(Self::Auto),
+            "on" => Some(Self::On),
+            _ => None,
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::On => "on",
+        }
+    }
+}
+
+// Driver a device was unbound from by stop_device, for start_device to bind it to again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CfhdbStoppedDevice {
+    pub bus: String,
+    pub busid: String,
+    pub driver: String,
+}
+
+// Driver a PCI device is bound to at every boot, matched by slot and IDs like a blacklist entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CfhdbDriverOverride {
+    pub busid: String,
+    pub id: Option<String>,
+    pub driver: String,
+}
+
+// Runtime PM setting a PCI device gets at every boot, matched like a driver override
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CfhdbRuntimePmSetting {
+    pub busid: String,
+    pub id: Option<String>,
+    pub control: CfhdbRuntimePmControl,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum CfhdbSysfsError {
+    InvalidArguments { message: String },
+    NoSuchDevice { bus: String, busid: String },
+    // Stopping a device no driver is bound to
+    NoDriver { busid: String },
+    // Starting a device no driver would take
+    NoMatchingDriver { busid: String },
+    NoIommuGroup { busid: String },
+    // The device supports none of the kernel's reset methods, or not the one asked for
+    NoResetMethod { busid: String },
+    // Setting virtual functions on a device without SR-IOV
+    NoSriov { busid: String },
+    // Enabling a device that a blacklist entry for every device of its model still matches
+    StillBlacklisted { busid: String, entry: String },
+    NotAuthorized,
+    Io { message: String },
+}
+
+impl CfhdbSysfsError {
+    // Stable identifier, also used as the JSON error code of the CLI
+    pub fn get_code(&self) -> &'static str {
+        match self {
+            Self::InvalidArguments { .. } => "invalid_arguments",
+            Self::NoSuchDevice { .. } => "no_such_device",
+            Self::NoDriver { .. } => "no_driver",
+            Self::NoMatchingDriver { .. } => "no_matching_driver",
+            Self::NoIommuGroup { .. } => "no_iommu_group",
+            Self::NoResetMethod { .. } => "no_reset_method",
+            Self::NoSriov { .. } => "no_sriov",
+            Self::StillBlacklisted { .. } => "still_blacklisted",
+            Self::NotAuthorized => "not_authorized",
+            Self::Io { .. } => "io_error",
+        }
+    }
+}
+
+impl fmt::Display for CfhdbSysfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidArguments { message } => write!(f, "invalid arguments: {}", message),
+            Self::NoSuchDevice { bus, busid } => write!(f, "no {} device {}", bus, busid),
+            Self::NoDriver { busid } => write!(f, "no driver is bound to {}", busid),
+            Self::NoMatchingDriver { busid } => write!(f, "no driver could be bound to {}", busid),
+            Self::NoIommuGroup { busid } => {
+                write!(f, "{} is in no IOMMU group, is the IOMMU enabled?", busid)
+            }
+            Self::NoResetMethod { busid } => write!(f, "{} can't be reset this way", busid),
+            Self::NoSriov { busid } => write!(f, "{} has no SR-IOV capability", busid),
+            Self::StillBlacklisted { busid, entry } => {
+                write!(f, "{} is still blacklisted by \"{}\"", busid, entry)
+            }
+            Self::NotAuthorized => write!(f, "not authorized"),
+            Self::Io { message } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for CfhdbSysfsError {}
+
+impl From<io::Error> for CfhdbSysfsError {
+    fn from(e: io::Error) -> Self {
+        Self::Io {
+            message: e.to_string(),
+        }
+    }
+}
+
+// Unprivileged side, runs the helper directly as root and through pkexec otherwise
+pub fn run_sysfs_helper(
+    action: CfhdbSysfsAction,
+    bus: CfhdbSysfsBus,
+    busid: &str,
+    argument: Option<&str>,
+) -> Result<(), CfhdbSysfsError> {
+    let mut args = vec![action.get_name(), bus.get_name(), busid];
+    args.extend(argument);
+    let is_root = get_current_username().is_some_and(|x| x == "root");
+    let cmd = if is_root {
+        duct::cmd(SYSFS_HELPER_PATH, args)
+    } else {
+        duct::cmd("pkexec", [SYSFS_HELPER_PATH].into_iter().chain(args))
+    };
+    let output = cmd.stdout_capture().unchecked().run()?;
+    match output.status.code() {
+        Some(0) => Ok(()),
+        Some(PKEXEC_DISMISSED) | Some(PKEXEC_NOT_AUTHORIZED) if !is_root => {
+            Err(CfhdbSysfsError::NotAuthorized)
+        }
+        _ => Err(
+            serde_json::from_slice(&output.stdout).unwrap_or_else(|_| CfhdbSysfsError::Io {
+                message: String::from_utf8_lossy(&output.stdout).trim().to_string(),
+            }),
+        ),
+    }
+}
+
+fn get_device_path(bus: CfhdbSysfsBus, busid: &str) -> Result<PathBuf, CfhdbSysfsError> {
+    // Bus IDs are single path components, anything else could point outside of sysfs
+    if busid.is_empty() || busid.contains('/') || busid.starts_with('.') {
+        return Err(CfhdbSysfsError::InvalidArguments {
+            message: format!("invalid bus id {}", busid),
+        });
+    }
+    let device_path = Path::new("/sys/bus")
+        .join(bus.get_name())
+        .join("devices")
+        .join(busid);
+    if device_path.exists() {
+        Ok(device_path)
+    } else {
+        Err(CfhdbSysfsError::NoSuchDevice {
+            bus: bus.get_name().to_string(),
+            busid: busid.to_string(),
+        })
+    }
+}
+
+fn get_bound_driver(bus: CfhdbSysfsBus, target: &str) -> Option<String> {
+    fs::read_link(
+        Path::new("/sys/bus")
+            .join(bus.get_name())
+            .join("devices")
+            .join(target)
+            .join("driver"),
+    )
+    .ok()
+    .and_then(|x| x.file_name().map(|x| x.to_string_lossy().into_owned()))
+}
+
+fn read_lines(path: &str) -> Result<Vec<String>, io::Error> {
+    match fs::read_to_string(path) {
+        Ok(t) => Ok(t.lines().map(|x| x.to_string()).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+// Written next to the target and renamed over it, a reader never sees a partial file
+pub(crate) fn write_state_file(path: &str, data: &str) -> Result<(), io::Error> {
+    fs::create_dir_all(Path::new(path).parent().unwrap())?;
+    let temp_path = format!("{}.tmp", path);
+    match fs::remove_file(&temp_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .mode(0o644)
+        .open(&temp_path)?;
+    file.write_all(data.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+fn write_lines(path: &str, lines: &[String]) -> Result<(), io::Error> {
+    write_state_file(
+        path,
+        &lines.iter().map(|x| x.clone() + "\n").collect::<String>(),
+    )
+}
+
+fn write_sysfs(path: PathBuf, value: &str) -> Result<(), CfhdbSysfsError> {
+    fs::write(&path, value).map_err(|e| CfhdbSysfsError::Io {
+        message: format!("{}: {}", path.display(), e),
+    })
+}
+
+fn load_stopped_devices() -> Result<Vec<CfhdbStoppedDevice>, io::Error> {
+    let data = match fs::read_to_string(STOPPED_DEVICES_PATH) {
+        Ok(t) => t,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn save_stopped_devices(devices: &[CfhdbStoppedDevice]) -> Result<(), io::Error> {
+    let json_pretty = serde_json::to_string_pretty(devices)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_state_file(STOPPED_DEVICES_PATH, &(json_pretty + "\n"))
+}
+
+// Entries of devices that were unplugged or bound again by something else are left out
+pub fn get_stopped_devices() -> Result<Vec<CfhdbStoppedDevice>, io::Error> {
+    Ok(load_stopped_devices()?
+        .into_iter()
+        .filter(|x| match CfhdbSysfsBus::from_name(&x.bus) {
+            Some(bus) => {
+                get_device_path(bus, &x.busid).is_ok()
+                    && get_bound_driver(bus, &bus.get_driver_target(&x.busid)).is_none()
+            }
+            None => false,
+        })
+        .collect())
+}
+
+pub fn get_stopped_busids(bus: CfhdbSysfsBus) -> Vec<String> {
+    get_stopped_devices()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|x| x.bus == bus.get_name())
+        .map(|x| x.busid)
+        .collect()
+}
+
+// Stopping unbinds the driver, so a device without one is only stopped when cfhdb recorded it.
+// Other unbound devices have no started state.
+pub fn get_started_state(bound_started: Option<bool>, stopped: bool) -> Option<bool> {
+    match bound_started {
+        Some(t) => Some(t),
+        None if stopped => Some(false),
+        None => None,
+    }
+}
+
+fn stop_device(bus: CfhdbSysfsBus, busid: &str, target: &str) -> Result<(), CfhdbSysfsError> {
+    let driver = match get_bound_driver(bus, target) {
+        Some(t) => t,
+        None => {
+            return Err(CfhdbSysfsError::NoDriver {
+                busid: target.to_string(),
+            })
+        }
+    };
+    let mut stopped = load_stopped_devices()?;
+    stopped.retain(|x| !(x.bus == bus.get_name() && x.busid == busid));
+    stopped.push(CfhdbStoppedDevice {
+        bus: bus.get_name().to_string(),
+        busid: busid.to_string(),
+        driver: driver.clone(),
+    });
+    save_stopped_devices(&stopped)?;
+    write_sysfs(
+        Path::new("/sys/bus")
+            .join(bus.get_name())
+            .join("drivers")
+            .join(driver)
+            .join("unbind"),
+        target,
+    )
+}
+
+// Module and driver names come from the caller, they are never passed on as options or paths
+fn check_module_name(name: &str) -> Result<(), CfhdbSysfsError> {
+    if !name.is_empty()
+        && name
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '-')
+    {
+        Ok(())
+    } else {
+        Err(CfhdbSysfsError::InvalidArguments {
+            message: format!("invalid module name {}", name),
+        })
+    }
+}
+
+// A module that fails to load is not an error by itself, the device then just stays unbound
+fn load_module(module: &str) -> Result<(), CfhdbSysfsError> {
+    check_module_name(module)?;
+    let _ = duct::cmd!("modprobe", "--", module)
+        .stdout_to_stderr()
+        .unchecked()
+        .run();
+    Ok(())
+}
+
+// Drivers are often named like their module with "_" and "-" swapped, e.g. vfio-pci in vfio_pci
+fn get_driver_name(bus: CfhdbSysfsBus, name: &str) -> String {
+    let drivers_path = Path::new("/sys/bus").join(bus.get_name()).join("drivers");
+    [
+        name.to_string(),
+        name.replace('_', "-"),
+        name.replace('-', "_"),
+    ]
+    .into_iter()
+    .find(|x| drivers_path.join(x).exists())
+    .unwrap_or_else(|| name.to_string())
+}
+
+// Rebinds the driver the device was stopped from. Without a recorded driver the modalias module is
+// loaded and the kernel picks the driver, the same as when the device was plugged in.
+fn start_device(
+    bus: CfhdbSysfsBus,
+    busid: &str,
+    target: &str,
+    module: Option<&str>,
+) -> Result<(), CfhdbSysfsError> {
+    let bus_path = Path::new("/sys/bus").join(bus.get_name());
+    let mut stopped = load_stopped_devices()?;
+    let recorded = stopped
+        .iter()
+        .position(|x| x.bus == bus.get_name() && x.busid == busid)
+        .map(|n| stopped.remove(n));
+    if recorded.is_some() {
+        save_stopped_devices(&stopped)?;
+    }
+    if get_bound_driver(bus, target).is_some() {
+        return Ok(());
+    }
+    clear_blacklist_override(bus, target)?;
+    if let Some(recorded) = recorded.filter(|_| get_bound_driver(bus, target).is_none()) {
+        let bind_path = bus_path.join("drivers").join(recorded.driver).join("bind");
+        if bind_path.exists() {
+            write_sysfs(bind_path, target)?;
+        }
+    }
+    if get_bound_driver(bus, target).is_none() {
+        if let Some(module) = module {
+            load_module(module)?;
+        }
+        // Loading the module may already have bound it
+        if get_bound_driver(bus, target).is_none() {
+            write_sysfs(bus_path.join("drivers_probe"), target)?;
+        }
+    }
+    match get_bound_driver(bus, target) {
+        Some(_) => Ok(()),
+        None => Err(CfhdbSysfsError::NoMatchingDriver {
+            busid: target.to_string(),
+        }),
+    }
+}
+
+// vendor:product in lowercase hex, e.g. "10de:1c82"
+fn get_device_id(bus: CfhdbSysfsBus, busid: &str) -> Option<String> {
+    let device_path = Path::new("/sys/bus")
+        .join(bus.get_name())
+        .join("devices")
+        .join(busid);
+    let (vendor_file, product_file) = match bus {
+        CfhdbSysfsBus::Pci => ("vendor", "device"),
+        CfhdbSysfsBus::Usb => ("idVendor", "idProduct"),
+    };
+    let read_id = |file: &str| -> Option<String> {
+        let id = fs::read_to_string(device_path.join(file)).ok()?;
+        Some(id.trim().trim_start_matches("0x").to_lowercase())
+    };
+    Some(format!(
+        "{}:{}",
+        read_id(vendor_file)?,
+        read_id(product_file)?
+    ))
+}
+
+fn get_device_serial(bus: CfhdbSysfsBus, busid: &str) -> Option<String> {
+    match bus {
+        CfhdbSysfsBus::Pci => None,
+        CfhdbSysfsBus::Usb => {
+            fs::read_to_string(Path::new("/sys/bus/usb/devices").join(busid).join("serial"))
+                .ok()
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty() && !x.contains(char::is_whitespace))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CfhdbBlacklistMatcher {
+    // The bus ID, which is the port path of a USB device and the slot of a PCI device
+    Path(String),
+    // vendor:product in lowercase hex
+    Id(String),
+    // USB serial number
+    Serial(String),
+}
+
+// One line of the blacklist, made of matchers that all have to match, e.g.
+// "id=046d:c52b serial=5A8F21" or "id=10de:1c82 path=0000:01:00.0".
+// A bare bus ID from older versions is read as "path=".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CfhdbBlacklistEntry(pub Vec<CfhdbBlacklistMatcher>);
+
+impl CfhdbBlacklistEntry {
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let mut matchers = vec![];
+        for word in line.split_whitespace() {
+            matchers.push(match word.split_once('=') {
+                Some(("path", value)) => CfhdbBlacklistMatcher::Path(value.to_string()),
+                Some(("id", value)) => CfhdbBlacklistMatcher::Id(value.to_lowercase()),
+                Some(("serial", value)) => CfhdbBlacklistMatcher::Serial(value.to_string()),
+                Some(_) => return None,
+                None => CfhdbBlacklistMatcher::Path(word.to_string()),
+            });
+        }
+        Some(Self(matchers))
+    }
+
+    // What disable_device writes. The serial number follows a USB device to any port, without one
+    // the IDs keep a different device later found at the same path from matching.
+    pub fn from_device(bus: CfhdbSysfsBus, busid: &str) -> Self {
+        let mut matchers = vec![];
+        if let Some(id) = get_device_id(bus, busid) {
+            matchers.push(CfhdbBlacklistMatcher::Id(id));
+        }
+        match get_device_serial(bus, busid) {
+            Some(serial) => matchers.push(CfhdbBlacklistMatcher::Serial(serial)),
+            None => matchers.push(CfhdbBlacklistMatcher::Path(busid.to_string())),
+        }
+        Self(matchers)
+    }
+
+    pub fn matches(&self, bus: CfhdbSysfsBus, busid: &str) -> bool {
+        self.matches_device(
+            busid,
+            get_device_id(bus, busid).as_deref(),
+            get_device_serial(bus, busid).as_deref(),
+        )
+    }
+
+    fn matches_device(&self, busid: &str, id: Option<&str>, serial: Option<&str>) -> bool {
+        self.0.iter().all(|matcher| match matcher {
+            CfhdbBlacklistMatcher::Path(path) => path == busid,
+            CfhdbBlacklistMatcher::Id(t) => id == Some(t.as_str()),
+            CfhdbBlacklistMatcher::Serial(t) => serial == Some(t.as_str()),
+        })
+    }
+
+    // An entry with a path or serial number is about one device, one with only IDs is about
+    // every device of that model
+    fn names_device(&self, busid: &str, serial: Option<&str>) -> bool {
+        self.0.iter().any(|matcher| match matcher {
+            CfhdbBlacklistMatcher::Path(path) => path == busid,
+            CfhdbBlacklistMatcher::Id(_) => false,
+            CfhdbBlacklistMatcher::Serial(t) => serial == Some(t.as_str()),
+        })
+    }
+}
+
+impl fmt::Display for CfhdbBlacklistEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let matchers: Vec<String> = self
+            .0
+            .iter()
+            .map(|matcher| match matcher {
+                CfhdbBlacklistMatcher::Path(path) => format!("path={}", path),
+                CfhdbBlacklistMatcher::Id(id) => format!("id={}", id),
+                CfhdbBlacklistMatcher::Serial(serial) => format!("serial={}", serial),
+            })
+            .collect();
+        write!(f, "{}", matchers.join(" "))
+    }
+}
+
+pub fn get_blacklist(bus: CfhdbSysfsBus) -> Result<Vec<CfhdbBlacklistEntry>, io::Error> {
+    Ok(read_lines(bus.get_blacklist_path())?
+        .iter()
+        .filter_map(|x| CfhdbBlacklistEntry::parse(x))
+        .collect())
+}
+
+pub fn is_blacklisted(bus: CfhdbSysfsBus, busid: &str) -> bool {
+    get_blacklist(bus)
+        .unwrap_or_default()
+        .iter()
+        .any(|x| x.matches(bus, busid))
+}
+
+// Devices present right now, USB interfaces are handled together with their device
+fn get_present_devices(bus: CfhdbSysfsBus) -> Result<Vec<String>, io::Error> {
+    let mut devices = vec![];
+    for entry in fs::read_dir(Path::new("/sys/bus").join(bus.get_name()).join("devices"))? {
+        let busid = entry?.file_name().to_string_lossy().into_owned();
+        if bus == CfhdbSysfsBus::Usb && busid.contains(':') {
+            continue;
+        }
+        devices.push(busid);
+    }
+    devices.sort();
+    Ok(devices)
+}
+
+// Each blacklist entry with the bus IDs of the present devices it matches
+pub fn resolve_blacklist(
+    bus: CfhdbSysfsBus,
+) -> Result<Vec<(CfhdbBlacklistEntry, Vec<String>)>, io::Error> {
+    let blacklist = get_blacklist(bus)?;
+    if blacklist.is_empty() {
+        return Ok(vec![]);
+    }
+    let devices = get_present_devices(bus)?;
+    Ok(blacklist
+        .into_iter()
+        .map(|entry| {
+            let matching = devices
+                .iter()
+                .filter(|x| entry.matches(bus, x))
+                .cloned()
+                .collect();
+            (entry, matching)
+        })
+        .collect())
+}
+
+// Booting with cfhdb.ignore-blacklist gets a system with a bad blacklist entry back
+pub fn is_blacklist_ignored() -> bool {
+    fs::read_to_string("/proc/cmdline")
+        .is_ok_and(|x| x.split_whitespace().any(|x| x == "cfhdb.ignore-blacklist"))
+}
+
+// Privileged, returns false for a device no driver is bound to
+pub fn stop_blacklisted_device(bus: CfhdbSysfsBus, busid: &str) -> Result<bool, CfhdbSysfsError> {
+    let target = bus.get_driver_target(busid);
+    match get_bound_driver(bus, &target) {
+        Some(_) => stop_device(bus, busid, &target).map(|_| true),
+        None => Ok(false),
+    }
+}
+
+// udev applies these before the modalias module is loaded, so a blacklisted PCI device gets a
+// driver_override no driver has and the first interface of a USB device is deauthorized
+fn get_blacklist_udev_rule(bus: CfhdbSysfsBus, entry: &CfhdbBlacklistEntry) -> Option<String> {
+    let mut keys = vec!["ACTION==\"add\"".to_string()];
+    match bus {
+        CfhdbSysfsBus::Pci => keys.push("SUBSYSTEM==\"pci\"".to_string()),
+        CfhdbSysfsBus::Usb => {
+            keys.push("SUBSYSTEM==\"usb\"".to_string());
+            keys.push("ENV{DEVTYPE}==\"usb_interface\"".to_string());
+            keys.push("KERNEL==\"*:1.0\"".to_string());
+        }
+    }
+    for matcher in &entry.0 {
+        let value = match matcher {
+            CfhdbBlacklistMatcher::Path(t)
+            | CfhdbBlacklistMatcher::Id(t)
+            | CfhdbBlacklistMatcher::Serial(t) => t,
+        };
+        // Anything udev would read as a glob or the end of the value can not be matched exactly
+        if value.contains(['"', '*', '?', '[', '|', '\\']) {
+            return None;
+        }
+        match (bus, matcher) {
+            (_, CfhdbBlacklistMatcher::Path(path)) => {
+                keys.push(format!("KERNEL==\"{}\"", bus.get_driver_target(path)))
+            }
+            (CfhdbSysfsBus::Pci, CfhdbBlacklistMatcher::Id(id)) => {
+                let (vendor, device) = id.split_once(':')?;
+                keys.push(format!("ATTR{{vendor}}==\"0x{}\"", vendor));
+                keys.push(format!("ATTR{{device}}==\"0x{}\"", device));
+            }
+            (CfhdbSysfsBus::Usb, CfhdbBlacklistMatcher::Id(id)) => {
+                let (vendor, product) = id.split_once(':')?;
+                keys.push(format!("ATTRS{{idVendor}}==\"{}\"", vendor));
+                keys.push(format!("ATTRS{{idProduct}}==\"{}\"", product));
+            }
+            (CfhdbSysfsBus::Pci, CfhdbBlacklistMatcher::Serial(_)) => return None,
+            (CfhdbSysfsBus::Usb, CfhdbBlacklistMatcher::Serial(serial)) => {
+                keys.push(format!("ATTRS{{serial}}==\"{}\"", serial))
+            }
+        }
+    }
+    keys.push(match bus {
+        CfhdbSysfsBus::Pci => format!("ATTR{{driver_override}}=\"{}\"", BLACKLIST_DRIVER_OVERRIDE),
+        CfhdbSysfsBus::Usb => "ATTR{authorized}=\"0\"".to_string(),
+    });
+    Some(keys.join(", "))
+}
+
+pub fn get_blacklist_udev_rules() -> Result<String, io::Error> {
+    let mut blacklists = vec![];
+    for bus in [CfhdbSysfsBus::Pci, CfhdbSysfsBus::Usb] {
+        blacklists.push((bus, get_blacklist(bus)?));
+    }
+    Ok(format_blacklist_udev_rules(&blacklists))
+}
+
+fn format_blacklist_udev_rules(blacklists: &[(CfhdbSysfsBus, Vec<CfhdbBlacklistEntry>)]) -> String {
+    let mut rules = vec![
+        "# Generated by cfhdb from /etc/cfhdb/pci_blacklist and /etc/cfhdb/usb_blacklist"
+            .to_string(),
+        "IMPORT{cmdline}=\"cfhdb.ignore-blacklist\"".to_string(),
+        "ENV{cfhdb.ignore-blacklist}==\"1\", GOTO=\"cfhdb_blacklist_end\"".to_string(),
+    ];
+    for (bus, blacklist) in blacklists {
+        rules.extend(
+            blacklist
+                .iter()
+                .filter_map(|x| get_blacklist_udev_rule(*bus, x)),
+        );
+    }
+    rules.push("LABEL=\"cfhdb_blacklist_end\"".to_string());
+    rules.join("\n") + "\n"
+}
+
+pub fn write_blacklist_udev_rules() -> Result<(), io::Error> {
+    write_state_file(BLACKLIST_UDEV_RULES_PATH, &get_blacklist_udev_rules()?)
+}
+
+// A device started by hand while blacklisted is let through once
+fn clear_blacklist_override(bus: CfhdbSysfsBus, target: &str) -> Result<(), CfhdbSysfsError> {
+    let target_path = Path::new("/sys/bus")
+        .join(bus.get_name())
+        .join("devices")
+        .join(target);
+    let (attribute, blocked, allowed) = match bus {
+        CfhdbSysfsBus::Pci => ("driver_override", BLACKLIST_DRIVER_OVERRIDE, "\n"),
+        CfhdbSysfsBus::Usb => ("authorized", "0", "1"),
+    };
+    match fs::read_to_string(target_path.join(attribute)) {
+        Ok(t) if t.trim() == blocked => write_sysfs(target_path.join(attribute), allowed),
+        _ => Ok(()),
+    }
+}
+
+// Lines that are not entries, like comments, are kept as they are. Enabling only removes the
+// entries about this one device, one that matches every device of the model is left to the user.
+fn set_blacklisted(
+    bus: CfhdbSysfsBus,
+    busid: &str,
+    blacklisted: bool,
+) -> Result<(), CfhdbSysfsError> {
+    let path = bus.get_blacklist_path();
+    let mut blacklist = read_lines(path)?;
+    let id = get_device_id(bus, busid);
+    let serial = get_device_serial(bus, busid);
+    let get_match = |line: &String| {
+        CfhdbBlacklistEntry::parse(line)
+            .filter(|x| x.matches_device(busid, id.as_deref(), serial.as_deref()))
+    };
+    let is_device_entry =
+        |line: &String| get_match(line).is_some_and(|x| x.names_device(busid, serial.as_deref()));
+    if blacklisted {
+        if blacklist.iter().any(|x| get_match(x).is_some()) {
+            return Ok(());
+        }
+        blacklist.push(CfhdbBlacklistEntry::from_device(bus, busid).to_string());
+    } else {
+        if let Some(entry) = blacklist
+            .iter()
+            .filter_map(get_match)
+            .find(|x| !x.names_device(busid, serial.as_deref()))
+        {
+            return Err(CfhdbSysfsError::StillBlacklisted {
+                busid: busid.to_string(),
+                entry: entry.to_string(),
+            });
+        }
+        if !blacklist.iter().any(is_device_entry) {
+            return Ok(());
+        }
+        blacklist.retain(|x| !is_device_entry(x));
+    }
+    write_lines(path, &blacklist)?;
+    write_blacklist_udev_rules()?;
+    Ok(())
+}
+
+// The override makes drivers_probe pick the driver even when its modules.alias does not list the
+// device, which is how a GPU is moved between e.g. nvidia, nouveau and vfio-pci
+fn bind_device(busid: &str, driver: Option<&str>) -> Result<(), CfhdbSysfsError> {
+    let bus = CfhdbSysfsBus::Pci;
+    let device_path = get_device_path(bus, busid)?;
+    let driver = match driver {
+        Some(driver) => {
+            load_module(driver)?;
+            Some(get_driver_name(bus, driver))
+        }
+        None => None,
+    };
+    write_sysfs(
+        device_path.join("driver_override"),
+        driver.as_deref().unwrap_or("\n"),
+    )?;
+    let bound = get_bound_driver(bus, busid);
+    if bound.is_some() && bound == driver {
+        return Ok(());
+    }
+    if let Some(bound) = bound {
+        write_sysfs(
+            Path::new("/sys/bus/pci/drivers").join(bound).join("unbind"),
+            busid,
+        )?;
+    }
+    write_sysfs(Path::new("/sys/bus/pci/drivers_probe").to_path_buf(), busid)?;
+    match (driver, get_bound_driver(bus, busid)) {
+        (Some(driver), Some(bound)) if driver == bound => Ok(()),
+        (None, _) => Ok(()),
+        _ => Err(CfhdbSysfsError::NoMatchingDriver {
+            busid: busid.to_string(),
+        }),
+    }
+}
+
+pub fn get_driver_overrides() -> Result<Vec<CfhdbDriverOverride>, io::Error> {
+    let data = match fs::read_to_string(DRIVER_OVERRIDES_PATH) {
+        Ok(t) => t,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// A saved device only matches while it sits in the same slot and still has the same IDs
+fn get_pci_udev_match_keys(busid: &str, id: &Option<String>) -> Vec<String> {
+    let mut keys = vec![
+        "ACTION==\"add\"".to_string(),
+        "SUBSYSTEM==\"pci\"".to_string(),
+        format!("KERNEL==\"{}\"", busid),
+    ];
+    if let Some((vendor, device)) = id.as_ref().and_then(|x| x.split_once(':')) {
+        keys.push(format!("ATTR{{vendor}}==\"0x{}\"", vendor));
+        keys.push(format!("ATTR{{device}}==\"0x{}\"", device));
+    }
+    keys
+}
+
+// The module is loaded from the rule as well, drivers like vfio-pci are not loaded by modalias
+fn get_driver_override_udev_rules(overrides: &[CfhdbDriverOverride]) -> String {
+    let mut rules = vec![format!(
+        "# Generated by cfhdb from {}",
+        DRIVER_OVERRIDES_PATH
+    )];
+    for driver_override in overrides {
+        let mut keys = get_pci_udev_match_keys(&driver_override.busid, &driver_override.id);
+        keys.push(format!(
+            "ATTR{{driver_override}}=\"{}\"",
+            driver_override.driver
+        ));
+        keys.push(format!(
+            "RUN{{builtin}}+=\"kmod load {}\"",
+            driver_override.driver
+        ));
+        rules.push(keys.join(", "));
+    }
+    rules.join("\n") + "\n"
+}
+
+fn set_persistent_driver_override(
+    busid: &str,
+    driver: Option<&str>,
+) -> Result<(), CfhdbSysfsError> {
+    let bus = CfhdbSysfsBus::Pci;
+    let mut overrides = get_driver_overrides()?;
+    overrides.retain(|x| x.busid != busid);
+    if let Some(driver) = driver {
+        check_module_name(driver)?;
+        overrides.push(CfhdbDriverOverride {
+            busid: busid.to_string(),
+            id: get_device_id(bus, busid),
+            driver: get_driver_name(bus, driver),
+        });
+    }
+    let json_pretty = serde_json::to_string_pretty(&overrides)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_state_file(DRIVER_OVERRIDES_PATH, &(json_pretty + "\n"))?;
+    write_state_file(
+        DRIVER_OVERRIDE_UDEV_RULES_PATH,
+        &get_driver_override_udev_rules(&overrides),
+    )?;
+    Ok(())
+}
+
+// Devices of an IOMMU group can only be passed through together. Bridges are left to the host,
+// vfio-pci does not take them.
+pub fn get_iommu_group_endpoints(busid: &str) -> Result<Vec<String>, CfhdbSysfsError> {
+    let group_path = get_device_path(CfhdbSysfsBus::Pci, busid)?
+        .join("iommu_group")
+        .join("devices");
+    let entries = fs::read_dir(group_path).map_err(|_| CfhdbSysfsError::NoIommuGroup {
+        busid: busid.to_string(),
+    })?;
+    let mut endpoints = vec![];
+    for entry in entries {
+        let member = entry?.file_name().to_string_lossy().into_owned();
+        let class = fs::read_to_string(
+            Path::new("/sys/bus/pci/devices")
+                .join(&member)
+                .join("class"),
+        )?;
+        if !class.trim().trim_start_matches("0x").starts_with("06") {
+            endpoints.push(member);
+        }
+    }
+    endpoints.sort();
+    Ok(endpoints)
+}
+
+fn bind_iommu_group(
+    busid: &str,
+    driver: Option<&str>,
+    persistent: bool,
+) -> Result<(), CfhdbSysfsError> {
+    let driver = driver.ok_or_else(|| CfhdbSysfsError::InvalidArguments {
+        message: "bind_iommu_group needs a driver".to_string(),
+    })?;
+    for member in get_iommu_group_endpoints(busid)? {
+        bind_device(&member, Some(driver))?;
+        if persistent {
+            set_persistent_driver_override(&member, Some(driver))?;
+        }
+    }
+    Ok(())
+}
+
+fn get_runtime_pm_control(
+    argument: Option<&str>,
+) -> Result<CfhdbRuntimePmControl, CfhdbSysfsError> {
+    argument
+        .and_then(CfhdbRuntimePmControl::from_name)
+        .ok_or_else(|| CfhdbSysfsError::InvalidArguments {
+            message: "set_runtime_pm needs auto or on".to_string(),
+        })
+}
+
+fn set_runtime_pm(busid: &str, control: CfhdbRuntimePmControl) -> Result<(), CfhdbSysfsError> {
+    write_sysfs(
+        get_device_path(CfhdbSysfsBus::Pci, busid)?
+            .join("power")
+            .join("control"),
+        control.get_name(),
+    )
+}
+
+pub fn get_runtime_pm_settings() -> Result<Vec<CfhdbRuntimePmSetting>, io::Error> {
+    let data = match fs::read_to_string(RUNTIME_PM_PATH) {
+        Ok(t) => t,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn get_runtime_pm_udev_rules(settings: &[CfhdbRuntimePmSetting]) -> String {
+    let mut rules = vec![format!("# Generated by cfhdb from {}", RUNTIME_PM_PATH)];
+    for setting in settings {
+        let mut keys = get_pci_udev_match_keys(&setting.busid, &setting.id);
+        keys.push(format!(
+            "ATTR{{power/control}}=\"{}\"",
+            setting.control.get_name()
+        ));
+        rules.push(keys.join(", "));
+    }
+    rules.join("\n") + "\n"
+}
+
+fn set_persistent_runtime_pm(
+    busid: &str,
+    control: CfhdbRuntimePmControl,
+) -> Result<(), CfhdbSysfsError> {
+    let mut settings = get_runtime_pm_settings()?;
+    settings.retain(|x| x.busid != busid);
+    settings.push(CfhdbRuntimePmSetting {
+        busid: busid.to_string(),
+        id: get_device_id(CfhdbSysfsBus::Pci, busid),
+        control,
+    });
+    let json_pretty = serde_json::to_string_pretty(&settings)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_state_file(RUNTIME_PM_PATH, &(json_pretty + "\n"))?;
+    write_state_file(
+        RUNTIME_PM_UDEV_RULES_PATH,
+        &get_runtime_pm_udev_rules(&settings),
+    )?;
+    Ok(())
+}
+
+// Empty before kernel 5.15, which added reset_method. The reset file exists whenever a method does.
+pub fn get_reset_methods(busid: &str) -> Vec<String> {
+    fs::read_to_string(
+        Path::new("/sys/bus/pci/devices")
+            .join(busid)
+            .join("reset_method"),
+    )
+    .unwrap_or_default()
+    .split_whitespace()
+    .map(|x| x.to_string())
+    .collect()
+}
+
+// A method is only used for this reset, the methods the kernel tries are put back afterwards
+fn reset_device(busid: &str, method: Option<&str>) -> Result<(), CfhdbSysfsError> {
+    let device_path = get_device_path(CfhdbSysfsBus::Pci, busid)?;
+    let no_reset_method = || CfhdbSysfsError::NoResetMethod {
+        busid: busid.to_string(),
+    };
+    if !device_path.join("reset").exists() {
+        return Err(no_reset_method());
+    }
+    let method = match method {
+        Some(method) => method,
+        None => return write_sysfs(device_path.join("reset"), "1"),
+    };
+    let methods = get_reset_methods(busid);
+    if !methods.iter().any(|x| x == method) {
+        return Err(no_reset_method());
+    }
+    write_sysfs(device_path.join("reset_method"), method)?;
+    let result = write_sysfs(device_path.join("reset"), "1");
+    write_sysfs(device_path.join("reset_method"), &methods.join(" "))?;
+    result
+}
+
+// The device is gone from sysfs afterwards, until the bus above it is rescanned
+fn remove_device(busid: &str) -> Result<(), CfhdbSysfsError> {
+    write_sysfs(
+        get_device_path(CfhdbSysfsBus::Pci, busid)?.join("remove"),
+        "1",
+    )
+}
+
+fn rescan_bus(bridge: &str) -> Result<(), CfhdbSysfsError> {
+    let rescan_path = match bridge {
+        RESCAN_ALL_BUSES => Path::new("/sys/bus/pci/rescan").to_path_buf(),
+        _ => get_device_path(CfhdbSysfsBus::Pci, bridge)?.join("rescan"),
+    };
+    write_sysfs(rescan_path, "1")
+}
+
+// The PF driver creates the VFs, and only takes a new count after they were all removed
+fn set_vf_count(busid: &str, count: Option<&str>) -> Result<(), CfhdbSysfsError> {
+    let device_path = get_device_path(CfhdbSysfsBus::Pci, busid)?;
+    let read_count = |name: &str| -> Option<u32> {
+        fs::read_to_string(device_path.join(name))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    let total = read_count("sriov_totalvfs").ok_or_else(|| CfhdbSysfsError::NoSriov {
+        busid: busid.to_string(),
+    })?;
+    let count: u32 = match count.and_then(|x| x.parse().ok()) {
+        Some(t) if t <= total => t,
+        _ => {
+            return Err(CfhdbSysfsError::InvalidArguments {
+                message: format!("set_vf_count needs a count from 0 to {}", total),
+            })
+        }
+    };
+    if get_bound_driver(CfhdbSysfsBus::Pci, busid).is_none() {
+        return Err(CfhdbSysfsError::NoDriver {
+            busid: busid.to_string(),
+        });
+    }
+    let current = read_count("sriov_numvfs").unwrap_or(0);
+    if current == count {
+        return Ok(());
+    }
+    if current != 0 && count != 0 {
+        write_sysfs(device_path.join("sriov_numvfs"), "0")?;
+    }
+    write_sysfs(device_path.join("sriov_numvfs"), &count.to_string())
+}
+
+// Privileged side, what cfhdb-sysfs-helper runs
+pub fn run_sysfs_action(
+    action: CfhdbSysfsAction,
+    bus: CfhdbSysfsBus,
+    busid: &str,
+    argument: Option<&str>,
+) -> Result<(), CfhdbSysfsError> {
+    // The bus ID of a rescan is a bridge or RESCAN_ALL_BUSES, and removed devices are rescanned
+    if action == CfhdbSysfsAction::RescanBus && bus == CfhdbSysfsBus::Pci {
+        return rescan_bus(busid);
+    }
+    get_device_path(bus, busid)?;
+    let target = bus.get_driver_target(busid);
+    match action {
+        CfhdbSysfsAction::BindDevice
+        | CfhdbSysfsAction::BindDevicePersistent
+        | CfhdbSysfsAction::BindIommuGroup
+        | CfhdbSysfsAction::BindIommuGroupPersistent
+        | CfhdbSysfsAction::SetRuntimePm
+        | CfhdbSysfsAction::SetRuntimePmPersistent
+        | CfhdbSysfsAction::ResetDevice
+        | CfhdbSysfsAction::RemoveDevice
+        | CfhdbSysfsAction::RescanBus
+        | CfhdbSysfsAction::SetVfCount
+            if bus != CfhdbSysfsBus::Pci =>
+        {
+            Err(CfhdbSysfsError::InvalidArguments {
+                message: format!("{} is only supported for pci devices", action.get_name()),
+            })
+        }
+        CfhdbSysfsAction::BindDevice => bind_device(busid, argument),
+        CfhdbSysfsAction::BindDevicePersistent => {
+            bind_device(busid, argument)?;
+            set_persistent_driver_override(busid, argument)
+        }
+        CfhdbSysfsAction::BindIommuGroup => bind_iommu_group(busid, argument, false),
+        CfhdbSysfsAction::BindIommuGroupPersistent => bind_iommu_group(busid, argument, true),
+        CfhdbSysfsAction::SetRuntimePm => set_runtime_pm(busid, get_runtime_pm_control(argument)?),
+        CfhdbSysfsAction::SetRuntimePmPersistent => {
+            let control = get_runtime_pm_control(argument)?;
+            set_runtime_pm(busid, control)?;
+            set_persistent_runtime_pm(busid, control)
+        }
+        CfhdbSysfsAction::ResetDevice => reset_device(busid, argument),
+        CfhdbSysfsAction::RemoveDevice => remove_device(busid),
+        CfhdbSysfsAction::RescanBus => rescan_bus(busid),
+        CfhdbSysfsAction::SetVfCount => set_vf_count(busid, argument),
+        CfhdbSysfsAction::StartDevice => start_device(bus, busid, &target, argument),
+        CfhdbSysfsAction::StopDevice => stop_device(bus, busid, &target),
+        CfhdbSysfsAction::EnableDevice => set_blacklisted(bus, busid, false),
+        CfhdbSysfsAction::DisableDevice => set_blacklisted(bus, busid, true),
+    }
+}
+
+// "start_device pci 0000:01:00.0 [module]" or "bind_device pci 0000:01:00.0 [driver]"
+pub fn parse_helper_args(
+    args: &[String],
+) -> Result<(CfhdbSysfsAction, CfhdbSysfsBus, &str, Option<&str>), CfhdbSysfsError> {
+    let invalid = |message: String| CfhdbSysfsError::InvalidArguments { message };
+    match args {
+        [action, bus, busid, rest @ ..] if rest.len() <= 1 => Ok((
+            CfhdbSysfsAction::from_name(action)
+                .ok_or_else(|| invalid(format!("unknown action {}", action)))?,
+            CfhdbSysfsBus::from_name(bus).ok_or_else(|| invalid(format!("unknown bus {}", bus)))?,
+            busid.as_str(),
+            rest.first().map(|x| x.as_str()),
+        )),
+        _ => Err(invalid("expected ACTION BUS BUSID [ARGUMENT]".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> CfhdbBlacklistEntry {
+        CfhdbBlacklistEntry::parse(line).unwrap()
+    }
+
+    #[test]
+    fn blacklist_entry_is_parsed() {
+        assert_eq!(
+            parse("id=046D:C52B serial=5A8F21"),
+            CfhdbBlacklistEntry(vec![
+                CfhdbBlacklistMatcher::Id("046d:c52b".to_string()),
+                CfhdbBlacklistMatcher::Serial("5A8F21".to_string()),
+            ])
+        );
+        assert_eq!(
+            parse("  id=10de:1c82 path=0000:01:00.0 "),
+            CfhdbBlacklistEntry(vec![
+                CfhdbBlacklistMatcher::Id("10de:1c82".to_string()),
+                CfhdbBlacklistMatcher::Path("0000:01:00.0".to_string()),
+            ])
+        );
+        assert_eq!(
+            parse("id=10de:1c82 path=0000:01:00.0").to_string(),
+            "id=10de:1c82 path=0000:01:00.0"
+        );
+    }
+
+    #[test]
+    fn bare_bus_id_is_a_path() {
+        assert_eq!(
+            parse("3-1.2"),
+            CfhdbBlacklistEntry(vec![CfhdbBlacklistMatcher::Path("3-1.2".to_string())])
+        );
+    }
+
+    #[test]
+    fn comments_and_unknown_matchers_are_not_entries() {
+        assert_eq!(CfhdbBlacklistEntry::parse(""), None);
+        assert_eq!(CfhdbBlacklistEntry::parse("   "), None);
+        assert_eq!(CfhdbBlacklistEntry::parse("# id=10de:1c82"), None);
+        assert_eq!(CfhdbBlacklistEntry::parse("id=10de:1c82 class=03"), None);
+    }
+
+    #[test]
+    fn every_matcher_has_to_match() {
+        let entry = parse("id=046d:c52b serial=5A8F21");
+        assert!(entry.matches_device("3-1", Some("046d:c52b"), Some("5A8F21")));
+        assert!(entry.matches_device("3-2", Some("046d:c52b"), Some("5A8F21")));
+        assert!(!entry.matches_device("3-1", Some("046d:c52b"), Some("5A8F22")));
+        assert!(!entry.matches_device("3-1", Some("046d:c52b"), None));
+        assert!(!entry.matches_device("3-1", Some("046d:c52c"), Some("5A8F21")));
+        let entry = parse("id=10de:1c82 path=0000:01:00.0");
+        assert!(entry.matches_device("0000:01:00.0", Some("10de:1c82"), None));
+        assert!(!entry.matches_device("0000:02:00.0", Some("10de:1c82"), None));
+        assert!(!entry.matches_device("0000:01:00.0", None, None));
+    }
+
+    #[test]
+    fn only_entries_with_a_path_or_serial_name_a_device() {
+        assert!(parse("id=10de:1c82 path=0000:01:00.0").names_device("0000:01:00.0", None));
+        assert!(parse("id=046d:c52b serial=5A8F21").names_device("3-1", Some("5A8F21")));
+        assert!(parse("0000:01:00.0").names_device("0000:01:00.0", None));
+        assert!(!parse("id=10de:1c82").names_device("0000:01:00.0", None));
+        assert!(!parse("id=046d:c52b serial=5A8F21").names_device("3-1", None));
+    }
+
+    #[test]
+    fn blacklist_udev_rules_are_generated() {
+        let rules = format_blacklist_udev_rules(&[
+            (
+                CfhdbSysfsBus::Pci,
+                vec![parse("id=10de:1c82 path=0000:01:00.0")],
+            ),
+            (
+                CfhdbSysfsBus::Usb,
+                vec![parse("id=046d:c52b serial=5A8F21")],
+            ),
+        ]);
+        let lines: Vec<&str> = rules.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "# Generated by cfhdb from /etc/cfhdb/pci_blacklist and /etc/cfhdb/usb_blacklist",
+                "IMPORT{cmdline}=\"cfhdb.ignore-blacklist\"",
+                "ENV{cfhdb.ignore-blacklist}==\"1\", GOTO=\"cfhdb_blacklist_end\"",
+                "ACTION==\"add\", SUBSYSTEM==\"pci\", ATTR{vendor}==\"0x10de\", \
+                 ATTR{device}==\"0x1c82\", KERNEL==\"0000:01:00.0\", \
+                 ATTR{driver_override}=\"none\"",
+                "ACTION==\"add\", SUBSYSTEM==\"usb\", ENV{DEVTYPE}==\"usb_interface\", \
+                 KERNEL==\"*:1.0\", ATTRS{idVendor}==\"046d\", ATTRS{idProduct}==\"c52b\", \
+                 ATTRS{serial}==\"5A8F21\", ATTR{authorized}=\"0\"",
+                "LABEL=\"cfhdb_blacklist_end\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn blacklist_entries_udev_can_not_match_get_no_rule() {
+        let rules = format_blacklist_udev_rules(&[
+            (
+                CfhdbSysfsBus::Pci,
+                vec![parse("serial=5A8F21"), parse("id=10de")],
+            ),
+            (
+                CfhdbSysfsBus::Usb,
+                vec![parse("serial=5A8F*"), parse("path=3-1\"")],
+            ),
+        ]);
+        assert_eq!(rules.lines().count(), 4);
+        assert!(rules.ends_with("LABEL=\"cfhdb_blacklist_end\"\n"));
+    }
+}
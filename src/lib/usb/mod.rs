@@ -1,13 +1,21 @@
-use regex::Regex;
-use serde::{Serialize, Serializer};
+use crate::{
+    modules::{get_bound_module, get_candidate_modules, CfhdbKernelModule, CfhdbModuleAliases},
+    post_action::{get_post_actions, CfhdbPostAction},
+    profile::*,
+    sysfs::{
+        get_started_state, get_stopped_busids, is_blacklisted, run_sysfs_helper, CfhdbSysfsAction,
+        CfhdbSysfsBus, CfhdbSysfsError,
+    },
+};
+use serde::{de::IgnoredAny, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 use std::{
     collections::HashMap,
-    fs::{self, File},
-    io::{self, BufRead, ErrorKind, Write},
-    os::unix::fs::PermissionsExt,
+    fs,
+    io::{self, ErrorKind},
+    path::PathBuf,
     sync::{Arc, Mutex},
 };
-use users::get_current_username;
 
 // Implement Serialize for Rc<RefCell<Option<Vec<Rc<CfhdbUsbProfile>>
 
@@ -33,6 +41,17 @@ impl Serialize for ProfileWrapper {
     }
 }
 
+// Codenames can't be turned back into profiles, loaded devices start unmatched and are matched again
+impl<'de> Deserialize<'de> for ProfileWrapper {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        IgnoredAny::deserialize(deserializer)?;
+        Ok(ProfileWrapper(Arc::default()))
+    }
+}
+
 fn from_hex(hex_number: u32, fill: usize) -> String {
     format!("{:01$x}", hex_number, fill)
 }
@@ -124,7 +143,7 @@ struct LsUsbEntry {
     interface_class: String,
 }
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CfhdbUsbDevice {
     // String identification
     pub manufacturer_string_index: String,
@@ -145,6 +164,9 @@ pub struct CfhdbUsbDevice {
     pub started: Option<bool>,
     pub enabled: bool,
     pub speed: String,
+    // Modules whose modules.alias entries match the device, in the order modprobe tries them
+    #[serde(default)]
+    pub candidate_modules: Vec<String>,
     // Cfhdb Extras
     pub available_profiles: ProfileWrapper,
 }
@@ -244,126 +266,62 @@ impl CfhdbUsbDevice {
     }
 
     fn get_enabled(busid: &str) -> bool {
-        let usb_busid_blacklist_path = "/etc/cfhdb/usb_blacklist";
-        match File::open(&usb_busid_blacklist_path) {
-            Ok(file) => {
-                let reader = io::BufReader::new(file);
-                for line in reader.lines() {
-                    match line {
-                        Ok(t) => {
-                            if t.trim() == busid {
-                                return false;
-                            }
-                        }
-                        Err(_) => {}
-                    };
-                }
-            }
-            Err(_) => {}
-        }
-        return true;
+        !is_blacklisted(CfhdbSysfsBus::Usb, busid)
     }
 
-    fn get_modinfo_name(busid: &str) -> Result<String, io::Error> {
-        let modalias = fs::read_to_string(format!("/sys/bus/usb/devices/{}:1.0/modalias", busid))?;
-        let modinfo_cmd = duct::cmd!("modinfo", modalias);
-        let stdout = modinfo_cmd.read()?;
-        let re = Regex::new(r"name:\s+(\w+)").unwrap();
-        for line in stdout.lines() {
-            if let Some(captures) = re.captures(line) {
-                // Extract the module name from the capture group
-                if let Some(module_name) = captures.get(1) {
-                    return Ok(module_name.as_str().to_string());
-                }
-            }
+    // Drivers bind to the first interface, not the device itself
+    fn get_interface_path(busid: &str) -> PathBuf {
+        PathBuf::from(format!("/sys/bus/usb/devices/{}:1.0", busid))
+    }
+
+    fn get_candidate_module_names(aliases: &CfhdbModuleAliases, busid: &str) -> Vec<String> {
+        match fs::read_to_string(Self::get_interface_path(busid).join("modalias")) {
+            Ok(modalias) => aliases.get_modules(&modalias),
+            Err(_) => vec![],
         }
-        Err(io::Error::new(ErrorKind::NotFound, "not found"))
     }
 
-    pub fn stop_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "stop_device",
-                "usb",
-                &self.sysfs_busid
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "stop_device",
-                "usb",
-                &self.sysfs_busid
-            )
-        };
-        cmd.run()?;
-        Ok(())
+    pub fn stop_device(&self) -> Result<(), CfhdbSysfsError> {
+        run_sysfs_helper(
+            CfhdbSysfsAction::StopDevice,
+            CfhdbSysfsBus::Usb,
+            &self.sysfs_busid,
+            None,
+        )
     }
 
-    pub fn start_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "start_device",
-                "usb",
-                &self.sysfs_busid,
-                Self::get_modinfo_name(&self.sysfs_busid).unwrap_or("".to_string())
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "start_device",
-                "usb",
-                &self.sysfs_busid,
-                Self::get_modinfo_name(&self.sysfs_busid).unwrap_or("".to_string())
-            )
-        };
-        cmd.run()?;
-        Ok(())
+    pub fn start_device(&self) -> Result<(), CfhdbSysfsError> {
+        run_sysfs_helper(
+            CfhdbSysfsAction::StartDevice,
+            CfhdbSysfsBus::Usb,
+            &self.sysfs_busid,
+            self.candidate_modules.first().map(|x| x.as_str()),
+        )
     }
 
-    pub fn enable_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "enable_device",
-                "usb",
-                &self.sysfs_busid
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "enable_device",
-                "usb",
-                &self.sysfs_busid
-            )
-        };
-        cmd.run()?;
-        Ok(())
+    pub fn enable_device(&self) -> Result<(), CfhdbSysfsError> {
+        run_sysfs_helper(
+            CfhdbSysfsAction::EnableDevice,
+            CfhdbSysfsBus::Usb,
+            &self.sysfs_busid,
+            None,
+        )
     }
 
-    pub fn disable_device(&self) -> Result<(), io::Error> {
-        let cmd = if get_current_username().unwrap() == "root" {
-            duct::cmd!(
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "disable_device",
-                "usb",
-                &self.sysfs_busid
-            )
-        } else {
-            duct::cmd!(
-                "pkexec",
-                "/usr/lib/cfhdb/scripts/sysfs_helper.sh",
-                "disable_device",
-                "usb",
-                &self.sysfs_busid
-            )
-        };
-        cmd.run()?;
-        Ok(())
+    pub fn disable_device(&self) -> Result<(), CfhdbSysfsError> {
+        run_sysfs_helper(
+            CfhdbSysfsAction::DisableDevice,
+            CfhdbSysfsBus::Usb,
+            &self.sysfs_busid,
+            None,
+        )
+    }
+
+    // Candidate modules of the first interface with their modinfo fields
+    pub fn get_candidate_modules(&self) -> Result<Vec<CfhdbKernelModule>, io::Error> {
+        let interface_path = Self::get_interface_path(&self.sysfs_busid);
+        let modalias = fs::read_to_string(interface_path.join("modalias"))?;
+        get_candidate_modules(&modalias, get_bound_module(&interface_path).as_deref())
     }
 
     pub fn get_device_from_busid(busid: &str) -> Result<CfhdbUsbDevice, io::Error> {
@@ -390,6 +348,9 @@ impl CfhdbUsbDevice {
         // Get hardware devices
         let usb_devices = rusb::devices().unwrap();
         let mut devices = vec![];
+        // Without modules.alias the devices are still listed, just without candidates
+        let aliases = CfhdbModuleAliases::get().unwrap_or_default();
+        let stopped_busids = get_stopped_busids(CfhdbSysfsBus::Usb);
 
         for iter in usb_devices.iter() {
             let device_descriptor = iter.device_descriptor().unwrap();
@@ -413,6 +374,7 @@ impl CfhdbUsbDevice {
                     None => ("???".to_owned(), "???".to_owned(), "00".to_owned()),
                 };
             let item_started = Self::get_started(&item_sysfs_busid);
+            let item_stopped = stopped_busids.contains(&item_sysfs_busid);
             let item_enabled = Self::get_enabled(&item_sysfs_busid);
             let item_serial_number_string_index =
                 Self::get_serial(&item_sysfs_busid).unwrap_or("Unknown".to_string());
@@ -423,6 +385,8 @@ impl CfhdbUsbDevice {
             let item_port_number = iter.port_number();
             let item_kernel_driver =
                 Self::get_kernel_driver(&item_sysfs_busid).unwrap_or("Unknown".to_string());
+            let item_candidate_modules =
+                Self::get_candidate_module_names(&aliases, &item_sysfs_busid);
             let item_speed = match iter.speed() {
                 rusb::Speed::Low => "1.0",
                 rusb::Speed::Full => "1.1",
@@ -445,13 +409,17 @@ impl CfhdbUsbDevice {
                 port_number: item_port_number,
                 address: item_address,
                 kernel_driver: item_kernel_driver.clone(),
-                started: if item_kernel_driver != "Unknown" {
-                    Some(item_started)
-                } else {
-                    None
-                },
+                started: get_started_state(
+                    if item_kernel_driver != "Unknown" {
+                        Some(item_started)
+                    } else {
+                        None
+                    },
+                    item_stopped,
+                ),
                 enabled: item_enabled,
                 speed: item_speed.to_string(),
+                candidate_modules: item_candidate_modules,
                 available_profiles: ProfileWrapper(Arc::default()),
             });
         }
@@ -483,12 +451,13 @@ impl CfhdbUsbDevice {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct CfhdbUsbProfile {
     pub codename: String,
     pub i18n_desc: String,
     pub icon_name: String,
-    pub license: String,
+    // None when the database leaves it out, the CLI shows it as unknown in the user's language
+    pub license: Option<String>,
     pub class_codes: Vec<String>,
     pub vendor_ids: Vec<String>,
     pub product_ids: Vec<String>,
@@ -503,9 +472,38 @@ pub struct CfhdbUsbProfile {
     pub removable: bool,
     pub veiled: bool,
     pub priority: i32,
+    // Codenames that must not be installed together with this profile
+    pub conflicts: Vec<String>,
+    // Needed after installing or removing the profile before it takes full effect
+    pub post_actions: Vec<CfhdbPostAction>,
 }
 
 impl CfhdbUsbProfile {
+    pub fn from_json(profile: &Value, locale: &str) -> Self {
+        Self {
+            codename: get_string(profile, "codename", ""),
+            i18n_desc: get_i18n_desc(profile, locale),
+            icon_name: get_string(profile, "icon_name", "package-x-generic"),
+            license: get_optional_string(profile, "license"),
+            class_codes: get_string_vec(profile, "class_codes"),
+            vendor_ids: get_string_vec(profile, "vendor_ids"),
+            product_ids: get_string_vec(profile, "product_ids"),
+            blacklisted_class_codes: get_string_vec(profile, "blacklisted_class_codes"),
+            blacklisted_vendor_ids: get_string_vec(profile, "blacklisted_vendor_ids"),
+            blacklisted_product_ids: get_string_vec(profile, "blacklisted_product_ids"),
+            packages: get_optional_string_vec(profile, "packages"),
+            check_script: get_string(profile, "check_script", "false"),
+            install_script: get_optional_string(profile, "install_script"),
+            remove_script: get_optional_string(profile, "remove_script"),
+            experimental: profile["experimental"].as_bool().unwrap_or_default(),
+            removable: profile["removable"].as_bool().unwrap_or_default(),
+            veiled: profile["veiled"].as_bool().unwrap_or_default(),
+            priority: profile["priority"].as_i64().unwrap_or_default() as i32,
+            conflicts: get_string_vec(profile, "conflicts"),
+            post_actions: get_post_actions(profile),
+        }
+    }
+
     pub fn get_profile_from_codename(
         codename: &str,
         profiles: Vec<CfhdbUsbProfile>,
@@ -520,28 +518,6 @@ impl CfhdbUsbProfile {
     }
 
     pub fn get_status(&self) -> bool {
-        let file_path = "/var/cache/cfhdb/check_cmd.sh";
-        {
-            let mut file = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(file_path)
-                .expect(&(file_path.to_string() + "cannot be read"));
-            file.write_all(format!("#! /bin/bash\nset -e\n{}", self.check_script).as_bytes())
-                .expect(&(file_path.to_string() + "cannot be written to"));
-            let mut perms = file
-                .metadata()
-                .expect(&(file_path.to_string() + "cannot be read"))
-                .permissions();
-            perms.set_mode(0o777);
-            fs::set_permissions(file_path, perms)
-                .expect(&(file_path.to_string() + "cannot be written to"));
-        }
-        duct::cmd!("bash", "-c", file_path)
-            .stderr_to_stdout()
-            .stdout_null()
-            .run()
-            .is_ok()
+        run_check_script(&self.check_script)
     }
 }
@@ -0,0 +1,132 @@
+use crate::output_func::*;
+use cli_table::{Cell, Color, Style, Table};
+use libcfhdb::profile::get_locale_fallback_chain;
+use rust_i18n::Backend;
+use std::{cell::Cell as FlagCell, collections::BTreeMap, fs, path::Path, sync::OnceLock};
+
+const DEFAULT_LOCALE: &str = "en_US";
+
+// The locale the user asked for, profile descriptions are matched against it even when the CLI has no strings for it
+static REQUESTED_LOCALE: OnceLock<String> = OnceLock::new();
+
+thread_local! {
+    static IN_CHAIN_LOOKUP: FlagCell<bool> = const { FlagCell::new(false) };
+}
+
+// Resolves every key through the fallback chain, so a key missing from de_AT comes from de
+// before en_US. rust_i18n asks this backend before its own exact lookup, which the walk calls
+// back into.
+pub struct LocaleChainBackend;
+
+impl Backend for LocaleChainBackend {
+    fn available_locales(&self) -> Vec<&str> {
+        vec![]
+    }
+
+    fn translate(&self, locale: &str, key: &str) -> Option<&str> {
+        if IN_CHAIN_LOOKUP.get() {
+            return None;
+        }
+        IN_CHAIN_LOOKUP.set(true);
+        let value = get_locale_fallback_chain(locale)
+            .iter()
+            .find_map(|x| crate::_RUST_I18N_BACKEND.translate(x, key));
+        IN_CHAIN_LOOKUP.set(false);
+        value
+    }
+}
+
+// Most specific form of the requested locale, en_US is only the last fallback of each key
+pub fn resolve_ui_locale(requested: &str) -> String {
+    get_locale_fallback_chain(requested)
+        .into_iter()
+        .next()
+        .unwrap_or(DEFAULT_LOCALE.to_string())
+}
+
+pub fn set_locale(requested: &str) {
+    let _ = REQUESTED_LOCALE.set(requested.to_string());
+    rust_i18n::set_locale(&resolve_ui_locale(requested));
+}
+
+pub fn get_profile_locale() -> String {
+    match REQUESTED_LOCALE.get() {
+        Some(t) => t.clone(),
+        None => rust_i18n::locale().to_string(),
+    }
+}
+
+fn get_locale_keys(path: &Path) -> Result<Vec<String>, std::io::Error> {
+    let data = fs::read_to_string(path)?;
+    let res: serde_json::Value = serde_json::from_str(&data)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    match res.as_object() {
+        Some(t) => Ok(t.keys().cloned().collect()),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{} is not a JSON object", path.display()),
+        )),
+    }
+}
+
+pub fn check_locales(json: bool, locales_dir: &str) {
+    let reference_keys =
+        match get_locale_keys(&Path::new(locales_dir).join(format!("{}.json", DEFAULT_LOCALE))) {
+            Ok(t) => t,
+            Err(e) => exit_with_error("io_error", e),
+        };
+    let mut entries = match fs::read_dir(locales_dir) {
+        Ok(t) => t
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().and_then(|x| x.to_str()) == Some("json"))
+            .collect::<Vec<_>>(),
+        Err(e) => exit_with_error("io_error", e),
+    };
+    entries.sort();
+    let mut missing_keys_map: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for path in entries {
+        let locale = match path.file_stem().and_then(|x| x.to_str()) {
+            Some(t) if t != DEFAULT_LOCALE => t.to_string(),
+            _ => continue,
+        };
+        let locale_keys = match get_locale_keys(&path) {
+            Ok(t) => t,
+            Err(e) => exit_with_error("io_error", e),
+        };
+        let missing_keys = reference_keys
+            .iter()
+            .filter(|x| !locale_keys.contains(x))
+            .cloned()
+            .collect();
+        missing_keys_map.insert(locale, missing_keys);
+    }
+    if json {
+        print_json_result(missing_keys_map);
+    } else {
+        let mut table_struct = vec![];
+        for (locale, missing_keys) in missing_keys_map {
+            table_struct.push(vec![
+                locale.cell(),
+                if missing_keys.is_empty() {
+                    missing_keys.len().cell().foreground_color(Some(Color::Green))
+                } else {
+                    missing_keys.len().cell().foreground_color(Some(Color::Red))
+                },
+                missing_keys.join("\n").cell(),
+            ]);
+        }
+        let table = table_struct
+            .table()
+            .title(vec![
+                t!("locale_table_locale").cell().bold(true),
+                t!("locale_table_missing_count").cell().bold(true),
+                t!("locale_table_missing_keys").cell().bold(true),
+            ])
+            .bold(true);
+
+        let table_display = table.display().unwrap();
+
+        println!("{}", table_display);
+    }
+}
@@ -1,6 +1,14 @@
-use std::{fs, io::Write, os::unix::fs::PermissionsExt, path::Path, process::exit};
+use std::{
+    ffi::CString,
+    fs,
+    io::Write,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Path, PathBuf},
+    sync::Once,
+};
 
-use cli_table::{format::Justify, Cell, Color, Style, Table};
+use clap::ArgMatches;
+use clap_complete::CompleteEnv;
 use colored::Colorize;
 use serde::Deserialize;
 use users::get_current_username;
@@ -8,10 +16,22 @@ use sys_locale::get_locale;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+mod blacklist_func;
+mod cli;
 mod config;
+mod dbus_func;
 mod bt_func;
 mod dmi_func;
+mod firmware_func;
+mod locale_func;
+mod monitor_func;
+mod output_func;
+mod pending_func;
 mod pci_func;
+mod profile_func;
+mod recommend_func;
+mod report_func;
+mod stopped_func;
 mod usb_func;
 
 
@@ -22,14 +42,17 @@ USER=$(whoami)
 
 chown $USER:$USER -R /var/cache/cfhdb || pkexec chown $USER:$USER -R /var/cache/cfhdb 
 chmod 777 -R /var/cache/cfhdb || pkexec chmod 777 -R /var/cache/cfhdb
-rm -rf /var/cache/cfhdb/check_cmd.sh || pkexec rm -rf /var/cache/cfhdb/check_cmd.sh
 
 "###;
 
 // Init translations for current crate.
 #[macro_use]
 extern crate rust_i18n;
-i18n!("locales", fallback = "en_US");
+i18n!(
+    "locales",
+    fallback = "en_US",
+    backend = locale_func::LocaleChainBackend
+);
 
 #[derive(Deserialize)]
 pub struct ProfileUrlConfig {
@@ -39,620 +62,283 @@ pub struct ProfileUrlConfig {
     bt_json_url: String,
 }
 
-fn print_help_msg() {
-    let table = vec![
-        // Secondary titles
-        vec![
-            t!("help_msg_title1")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Blue)),
-            t!("help_msg_title2")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Blue)),
-            t!("help_msg_title3")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Blue)),
-        ],
-        // Program arguments title
-        vec![
-            t!("")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Yellow)),
-            t!("help_msg_title_program")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Yellow)),
-            t!("")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Yellow)),
-        ],
-        // Program arguments entries
-        vec![
-            t!("help_msg_action_help").cell(),
-            "--help".cell(),
-            "-h".cell(),
-        ],
-        vec![
-            t!("help_msg_action_version").cell(),
-            "--version".cell(),
-            "-v".cell(),
-        ],
-        vec![
-            t!("help_msg_action_json").cell(),
-            "--json".cell(),
-            "-j".cell(),
-        ],
-        // PCI arguments title
-        vec![
-            t!("")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Yellow)),
-            t!("help_msg_title_pci")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Yellow)),
-            t!("")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Yellow)),
-        ],
-        // PCI arguments entries
-        vec![
-            t!("help_msg_action_list_pci_devices").cell(),
-            "--list-pci-devices".cell(),
-            "-lpd".cell(),
-        ],
-        vec![
-            t!("help_msg_action_list_compatible_pci_profiles").cell(),
-            "--list-pci-profiles {sysfs_id}".cell(),
-            "-lpp".cell(),
-        ],
-        vec![
-            t!("help_msg_action_install_pci_profile").cell(),
-            "--install-pci-profile {profile codename}".cell(),
-            "-ipp".cell(),
-        ],
-        vec![
-            t!("help_msg_action_uninstall_pci_profile").cell(),
-            "--uninstall-pci-profile {profile codename}".cell(),
-            "-upp".cell(),
-        ],
-        vec![
-            t!("help_msg_action_enable_pci_device").cell(),
-            "--enable-pci-device {sysfs_id}".cell(),
-            "-epd".cell(),
-        ],
-        vec![
-            t!("help_msg_action_disable_pci_device").cell(),
-            "--disable-pci-device {sysfs_id}".cell(),
-            "-dpd".cell(),
-        ],
-        vec![
-            t!("help_msg_action_start_pci_device").cell(),
-            "--start-pci-device {sysfs_id}".cell(),
-            "-sspd".cell(),
-        ],
-        vec![
-            t!("help_msg_action_stop_pci_device").cell(),
-            "--stop-pci-device {sysfs_id}".cell(),
-            "-srpd".cell(),
-        ],
-        // USB arguments title
-        vec![
-            t!("")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Yellow)),
-            t!("help_msg_title_usb")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Yellow)),
-            t!("")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Yellow)),
-        ],
-        // USB arguments entries
-        vec![
-            t!("help_msg_action_list_usb_devices").cell(),
-            "--list-usb-devices".cell(),
-            "-lud".cell(),
-        ],
-        vec![
-            t!("help_msg_action_list_compatible_usb_profiles").cell(),
-            "--list-usb-profiles {sysfs_id}".cell(),
-            "-lup".cell(),
-        ],
-        vec![
-            t!("help_msg_action_install_usb_profile").cell(),
-            "--install-usb-profile {profile codename}".cell(),
-            "-iup".cell(),
-        ],
-        vec![
-            t!("help_msg_action_uninstall_usb_profile").cell(),
-            "--uninstall-usb-profile {profile codename}".cell(),
-            "-uup".cell(),
-        ],
-        vec![
-            t!("help_msg_action_enable_usb_device").cell(),
-            "--enable-usb-device {sysfs_id}".cell(),
-            "-eud".cell(),
-        ],
-        vec![
-            t!("help_msg_action_disable_usb_device").cell(),
-            "--disable-usb-device {sysfs_id}".cell(),
-            "-dud".cell(),
-        ],
-        vec![
-            t!("help_msg_action_start_usb_device").cell(),
-            "--start-usb-device {sysfs_id}".cell(),
-            "-ssud".cell(),
-        ],
-        vec![
-            t!("help_msg_action_stop_usb_device").cell(),
-            "--stop-usb-device {sysfs_id}".cell(),
-            "-srud".cell(),
-        ],
-        // DMI arguments title
-        vec![
-            t!("")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Yellow)),
-            t!("help_msg_title_dmi")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Yellow)),
-            t!("")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Yellow)),
-        ],
-        // DMI arguments entries
-        vec![
-            t!("help_msg_action_list_dmi_info").cell(),
-            "--list-dmi-info".cell(),
-            "-ldi".cell(),
-        ],
-        vec![
-            t!("help_msg_action_list_compatible_dmi_profiles").cell(),
-            "--list-dmi-profiles".cell(),
-            "-ldp".cell(),
-        ],
-        vec![
-            t!("help_msg_action_install_dmi_profile").cell(),
-            "--install-dmi-profile {profile codename}".cell(),
-            "-idp".cell(),
-        ],
-        vec![
-            t!("help_msg_action_uninstall_dmi_profile").cell(),
-            "--uninstall-dmi-profile {profile codename}".cell(),
-            "-udp".cell(),
-        ],
-        // BT arguments title
-        vec![
-            t!("")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Yellow)),
-            t!("help_msg_title_bt")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Yellow)),
-            t!("")
-                .cell()
-                .bold(true)
-                .justify(Justify::Center)
-                .foreground_color(Some(Color::Yellow)),
-        ],
-        // BT arguments entries
-        vec![
-            t!("help_msg_action_list_bt_devices").cell(),
-            "--list-bt-devices".cell(),
-            "-lbd".cell(),
-        ],
-        vec![
-            t!("help_msg_action_list_compatible_bt_profiles").cell(),
-            "--list-bt-profiles {address}".cell(),
-            "-lbp".cell(),
-        ],
-        vec![
-            t!("help_msg_action_install_bt_profile").cell(),
-            "--install-bt-profile {profile codename}".cell(),
-            "-ibp".cell(),
-        ],
-        vec![
-            t!("help_msg_action_uninstall_bt_profile").cell(),
-            "--uninstall-bt-profile {profile codename}".cell(),
-            "-ubp".cell(),
-        ],
-        vec![
-            t!("help_msg_action_pair_bt_device").cell(),
-            "--pair-bt-device {address}".cell(),
-            "-pbd".cell(),
-        ],
-        vec![
-            t!("help_msg_action_connect_bt_device").cell(),
-            "--connect-bt-device {address}".cell(),
-            "-cbd".cell(),
-        ],
-        vec![
-            t!("help_msg_action_disconnect_bt_device").cell(),
-            "--disconnect-bt-device {address}".cell(),
-            "-pbd".cell(),
-        ],
-        vec![
-            t!("help_msg_action_trust_bt_device").cell(),
-            "--trust-bt-device {address}".cell(),
-            "-tbd".cell(),
-        ],
-        vec![
-            t!("help_msg_action_untrust_bt_device").cell(),
-            "--untrust-bt-device {address}".cell(),
-            "-utbd".cell(),
-        ],
-        vec![
-            t!("help_msg_action_block_bt_device").cell(),
-            "--block-bt-device {address}".cell(),
-            "-bbd".cell(),
-        ],
-        vec![
-            t!("help_msg_action_unblock_bt_device").cell(),
-            "--unblock-bt-device {address}".cell(),
-            "-ubbd".cell(),
-        ],
-    ]
-    .table()
-    .title(vec![
-        t!("help_msg_title0")
-            .cell()
-            .bold(true)
-            .justify(Justify::Center),
-        VERSION.cell().bold(true).justify(Justify::Center),
-        "".cell().bold(true).justify(Justify::Center),
-    ])
-    .bold(true);
-
-
-    let table_display = table.display().unwrap();
-
-    println!("{}", table_display);
-}
-fn parse_args(args: Vec<String>) {
-    let mut json_mode = false;
-    let mut action = "-h";
-    let mut additional_arguments = vec![];
-    for arg in args {
-        match arg.as_str() {
-            // Global modes
-            "-j" | "--json" => json_mode = true,
-            // Program arguments
-            "-h" | "--help" => action = "h",
-            "-v" | "--version" => action = "v",
-            // PCI arguments
-            "-lpd" | "--list-pci-devices" => action = "lpd",
-            "-lpp" | "--list-pci-profiles" => action = "lpp",
-            "-ipp" | "--install-pci-profile" => action = "ipp",
-            "-upp" | "--uninstall-pci-profile" => action = "upp",
-            "-epd" | "--enable-pci-device" => action = "epd",
-            "-dpd" | "--disable-pci-device" => action = "dpd",
-            "-sspd" | "--start-pci-device" => action = "sspd",
-            "-srpd" | "--stop-pci-device" => action = "srpd",
-            // USB arguments
-            "-lud" | "--list-usb-devices" => action = "lud",
-            "-lup" | "--list-usb-profiles" => action = "lup",
-            "-iup" | "--install-usb-profile" => action = "iup",
-            "-uup" | "--uninstall-usb-profile" => action = "uup",
-            "-eud" | "--enable-usb-device" => action = "eud",
-            "-dud" | "--disable-usb-device" => action = "dud",
-            "-ssud" | "--start-usb-device" => action = "ssud",
-            "-srud" | "--stop-usb-device" => action = "srud",
-            // DMI arguments
-            "-ldi" | "--list-dmi-info" => action = "ldi",
-            "-ldp" | "--list-dmi-profiles" => action = "ldp",
-            "-idp" | "--install-dmi-profile" => action = "idp",
-            "-udp" | "--uninstall-dmi-profile" => action = "udp",
-            // BT arguments
-            "-lbd" | "--list-bt-devices" => action = "lbd",
-            "-lbp" | "--list-bt-profiles" => action = "lbp",
-            "-ibp" | "--install-bt-profile" => action = "ibp",
-            "-ubp" | "--uninstall-bt-profile" => action = "ubp",
-            "-pbd" | "--pair-bt-device" => action = "pbd",
-            "-cbd" | "--connect-bt-device" => action = "cbd",
-            "-dbd" | "--disconnect-bt-device" => action = "dbd",
-            "-tbd" | "--trust-bt-device" => action = "tbd",
-            "-utbd" | "--untrust-bt-device" => action = "utbd",
-            "-bbd" | "--block-bt-device" => action = "bbd",
-            "-ubbd" | "--unblock-bt-device" => action = "ubbd",
-            _ => {
-                additional_arguments.push(arg);
-            }
-        }
+fn run_command(matches: &ArgMatches) {
+    let output_format = matches.get_one::<String>("format").unwrap();
+    let json_mode = matches.get_flag("json") || output_format == "json";
+    output_func::set_output_format(
+        if json_mode {
+            output_func::OutputFormat::Json
+        } else {
+            output_func::OutputFormat::from_name(output_format).unwrap()
+        },
+        matches
+            .get_many::<String>("columns")
+            .map(|x| x.cloned().collect()),
+    );
+    let (command, command_matches) = matches.subcommand().unwrap();
+    if json_mode {
+        let verb = command_matches.subcommand_name().unwrap_or_default();
+        output_func::set_json_mode(format!("{} {}", command, verb).trim());
     }
-    match action {
+    let (verb, verb_matches) = command_matches
+        .subcommand()
+        .unwrap_or(("", command_matches));
+    let get_arg = |name: &str| verb_matches.get_one::<String>(name).unwrap();
+    let get_flag = |name: &str| verb_matches.get_flag(name);
+    let get_query = |bus: &str| match cli::get_device_query(bus, verb_matches) {
+        Ok(t) => t,
+        Err(e) => output_func::exit_with_error("invalid_query", e),
+    };
+    match (command, verb) {
         // Program arguments
-        "h" => print_help_msg(),
-        "v" => {
-            println!("{}", VERSION)
-        }
-        "j" => print_help_msg(),
-        // PCI arguments
-        "lpd" => {
-            pci_func::display_pci_devices(json_mode);
-        }
-        "lpp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                pci_func::display_pci_profiles(json_mode, &additional_arguments[1]);
-            }
-        }
-        "ipp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                pci_func::install_pci_profile(&additional_arguments[1]);
-            }
-        }
-        "upp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                pci_func::uninstall_pci_profile(&additional_arguments[1]);
-            }
-        }
-        "epd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                pci_func::enable_pci_device(&additional_arguments[1]);
-            }
-        }
-        "dpd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                pci_func::disable_pci_device(&additional_arguments[1]);
-            }
-        }
-        "sspd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                pci_func::start_pci_device(&additional_arguments[1]);
+        ("locales", "check") => locale_func::check_locales(json_mode, get_arg("directory")),
+        ("profiles", "compile") => profile_func::compile_profiles(
+            get_arg("source"),
+            verb_matches.get_one::<String>("output"),
+        ),
+        ("recommend", _) => recommend_func::display_recommendations(json_mode),
+        ("auto-install", _) => recommend_func::auto_install_profiles(json_mode),
+        ("pending", _) => pending_func::display_pending_actions(json_mode, get_flag("clear")),
+        ("stopped", _) => stopped_func::display_stopped_devices(json_mode),
+        ("apply-blacklist", _) => blacklist_func::apply_blacklist(json_mode),
+        ("firmware", _) => firmware_func::display_missing_firmware(json_mode),
+        ("dbus-service", _) => dbus_func::run_dbus_service(),
+        ("monitor", _) => monitor_func::monitor_devices(json_mode, get_flag("notify")),
+        ("report", "export") => report_func::export_report(get_arg("file")),
+        ("report", "import") => report_func::import_report(
+            json_mode,
+            get_arg("file"),
+            &verb_matches
+                .get_many::<(String, String)>("profile-source")
+                .map(|x| x.cloned().collect::<Vec<_>>())
+                .unwrap_or_default(),
+        ),
+        ("report", "diff") => {
+            report_func::diff_report_files(json_mode, get_arg("before"), get_arg("after"))
+        }
+        ("completions", _) => {
+            if let Err(e) = cli::write_completions(get_arg("shell"), &mut std::io::stdout()) {
+                output_func::exit_with_error("io_error", e);
             }
         }
-        "srpd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                pci_func::stop_pci_device(&additional_arguments[1]);
+        ("man", _) => {
+            match cli::generate_man_pages(Path::new(get_arg("directory"))) {
+                Ok(_) => {
+                    if json_mode {
+                        output_func::print_json_result(
+                            serde_json::json!({ "directory": get_arg("directory") }),
+                        );
+                    }
+                }
+                Err(e) => output_func::exit_with_error("io_error", e),
             }
         }
+        // PCI arguments
+        ("pci", "list") => pci_func::display_pci_devices(
+            json_mode,
+            get_query("pci").as_ref(),
+            get_flag("show-veiled"),
+        ),
+        ("pci", "profiles") => pci_func::display_pci_profiles(
+            json_mode,
+            get_arg("device"),
+            get_flag("show-veiled"),
+        ),
+        ("pci", "install") => profile_func::install_profile(
+            "pci",
+            get_arg("profile"),
+            get_flag("allow-experimental"),
+            pci_func::get_pci_profile_transaction,
+        ),
+        ("pci", "uninstall") => profile_func::uninstall_profile(
+            "pci",
+            get_arg("profile"),
+            get_flag("force"),
+            pci_func::get_pci_profile_transaction,
+        ),
+        ("pci", "enable") => pci_func::enable_pci_device(get_arg("device")),
+        ("pci", "disable") => pci_func::disable_pci_device(get_arg("device")),
+        ("pci", "start") => pci_func::start_pci_device(get_arg("device")),
+        ("pci", "stop") => pci_func::stop_pci_device(get_arg("device")),
+        ("pci", "bind") => pci_func::bind_pci_device(
+            get_arg("device"),
+            verb_matches.get_one::<String>("driver").map(|x| x.as_str()),
+            get_flag("persistent"),
+        ),
+        ("pci", "drivers") => pci_func::display_pci_drivers(json_mode, get_arg("device")),
+        ("pci", "iommu") => pci_func::display_iommu_groups(json_mode),
+        ("pci", "vfio-bind") => {
+            pci_func::vfio_bind_pci_device(get_arg("device"), get_flag("persistent"))
+        }
+        ("pci", "power") => pci_func::display_pci_power(
+            json_mode,
+            verb_matches.get_one::<String>("device").map(|x| x.as_str()),
+        ),
+        ("pci", "runtime-pm") => pci_func::set_pci_runtime_pm(
+            get_arg("device"),
+            get_arg("control"),
+            get_flag("persistent"),
+        ),
+        ("pci", "reset") => pci_func::reset_pci_device(
+            get_arg("device"),
+            verb_matches.get_one::<String>("method").map(|x| x.as_str()),
+        ),
+        ("pci", "remove") => pci_func::remove_pci_device(get_arg("device")),
+        ("pci", "rescan") => pci_func::rescan_pci_bus(
+            verb_matches.get_one::<String>("bridge").map(|x| x.as_str()),
+        ),
+        ("pci", "sriov") => pci_func::display_sriov_devices(json_mode),
+        ("pci", "set-vfs") => pci_func::set_pci_vf_count(
+            get_arg("device"),
+            *verb_matches.get_one::<u32>("count").unwrap(),
+        ),
         // USB arguments
-        "lud" => {
-            usb_func::display_usb_devices(json_mode);
-        }
-        "lup" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                usb_func::display_usb_profiles(json_mode, &additional_arguments[1]);
-            }
-        }
-        "iup" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                usb_func::install_usb_profile(&additional_arguments[1]);
-            }
-        }
-        "uup" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                usb_func::uninstall_usb_profile(&additional_arguments[1]);
-            }
-        }
-        "eud" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                usb_func::enable_usb_device(&additional_arguments[1]);
-            }
-        }
-        "dud" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                usb_func::disable_usb_device(&additional_arguments[1]);
-            }
-        }
-        "ssud" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                usb_func::start_usb_device(&additional_arguments[1]);
-            }
-        }
-        "srud" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                usb_func::stop_usb_device(&additional_arguments[1]);
-            }
-        }
+        ("usb", "list") => usb_func::display_usb_devices(
+            json_mode,
+            get_query("usb").as_ref(),
+            get_flag("show-veiled"),
+        ),
+        ("usb", "profiles") => usb_func::display_usb_profiles(
+            json_mode,
+            get_arg("device"),
+            get_flag("show-veiled"),
+        ),
+        ("usb", "install") => profile_func::install_profile(
+            "usb",
+            get_arg("profile"),
+            get_flag("allow-experimental"),
+            usb_func::get_usb_profile_transaction,
+        ),
+        ("usb", "uninstall") => profile_func::uninstall_profile(
+            "usb",
+            get_arg("profile"),
+            get_flag("force"),
+            usb_func::get_usb_profile_transaction,
+        ),
+        ("usb", "enable") => usb_func::enable_usb_device(get_arg("device")),
+        ("usb", "disable") => usb_func::disable_usb_device(get_arg("device")),
+        ("usb", "start") => usb_func::start_usb_device(get_arg("device")),
+        ("usb", "stop") => usb_func::stop_usb_device(get_arg("device")),
+        ("usb", "drivers") => usb_func::display_usb_drivers(json_mode, get_arg("device")),
         // DMI arguments
-        "ldi" => {
-            dmi_func::display_dmi_info(json_mode);
-        }
-        "ldp" => {
-            dmi_func::display_dmi_profiles(json_mode);
-        }
-        "idp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                dmi_func::install_dmi_profile(&additional_arguments[1]);
-            }
-        }
-        "udp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                dmi_func::uninstall_dmi_profile(&additional_arguments[1]);
-            }
-        }
+        ("dmi", "info") => dmi_func::display_dmi_info(json_mode, get_flag("show-veiled")),
+        ("dmi", "profiles") => dmi_func::display_dmi_profiles(json_mode, get_flag("show-veiled")),
+        ("dmi", "install") => profile_func::install_profile(
+            "dmi",
+            get_arg("profile"),
+            get_flag("allow-experimental"),
+            dmi_func::get_dmi_profile_transaction,
+        ),
+        ("dmi", "uninstall") => profile_func::uninstall_profile(
+            "dmi",
+            get_arg("profile"),
+            get_flag("force"),
+            dmi_func::get_dmi_profile_transaction,
+        ),
         // BT arguments
-        "lbd" => {
-            bt_func::display_bt_devices(json_mode);
-        }
-        "lbp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::display_bt_profiles(json_mode, &additional_arguments[1]);
-            }
-        }
-        "ibp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::install_bt_profile(&additional_arguments[1]);
-            }
-        }
-        "ubp" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_profile_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::uninstall_bt_profile(&additional_arguments[1]);
-            }
-        }
-        "pbd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::pair_bt_device(&additional_arguments[1]);
-            }
-        }
-        "cbd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::connect_bt_device(&additional_arguments[1]);
-            }
-        }
-        "dbd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::disconnect_bt_device(&additional_arguments[1]);
-            }
-        }
-        "tbd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::trust_bt_device(&additional_arguments[1]);
-            }
-        }
-        "utbd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::untrust_bt_device(&additional_arguments[1]);
-            }
-        }
-        "bbd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::block_bt_device(&additional_arguments[1]);
-            }
-        }
-        "ubbd" => {
-            if additional_arguments.len() < 2 {
-                eprintln!("{}", t!("no_device_specified"));
-                std::process::exit(1);
-            } else {
-                bt_func::unblock_bt_device(&additional_arguments[1]);
-            }
-        }
-        // Unknown argument
-        _ => {
-            eprintln!("{}", t!("unknown_argument"));
-            std::process::exit(1);
-        }
+        ("bt", "list") => bt_func::display_bt_devices(
+            json_mode,
+            get_query("bt").as_ref(),
+            get_flag("show-veiled"),
+        ),
+        ("bt", "profiles") => bt_func::display_bt_profiles(
+            json_mode,
+            get_arg("device"),
+            get_flag("show-veiled"),
+        ),
+        ("bt", "install") => profile_func::install_profile(
+            "bt",
+            get_arg("profile"),
+            get_flag("allow-experimental"),
+            bt_func::get_bt_profile_transaction,
+        ),
+        ("bt", "uninstall") => profile_func::uninstall_profile(
+            "bt",
+            get_arg("profile"),
+            get_flag("force"),
+            bt_func::get_bt_profile_transaction,
+        ),
+        ("bt", "pair") => bt_func::pair_bt_device(get_arg("device")),
+        ("bt", "connect") => bt_func::connect_bt_device(get_arg("device")),
+        ("bt", "disconnect") => bt_func::disconnect_bt_device(get_arg("device")),
+        ("bt", "trust") => bt_func::trust_bt_device(get_arg("device")),
+        ("bt", "untrust") => bt_func::untrust_bt_device(get_arg("device")),
+        ("bt", "block") => bt_func::block_bt_device(get_arg("device")),
+        ("bt", "unblock") => bt_func::unblock_bt_device(get_arg("device")),
+        // clap only accepts the subcommands declared in cli.rs
+        _ => unreachable!(),
     }
 }
 
 fn main() {
-    // fix perms
-    duct::cmd!("bash", "-c", PERM_FIX_PROG).run().unwrap();
-    // Setup locales
-    let current_locale = get_locale().unwrap_or_else(|| String::from("en-US")).replace("-", "_");
-    rust_i18n::set_locale(&current_locale);
     let args: Vec<String> = std::env::args().collect();
-    let arg_num = args.len();
-    match arg_num {
-        0 | 1 => {
-            print_help_msg();
-        }
-        _ => {
-            parse_args(args);
-        }
+    // Setup locales, before the command is built so its help text is translated
+    let requested_locale = cli::get_requested_locale(&args)
+        .unwrap_or_else(|| get_locale().unwrap_or_else(|| String::from("en-US")));
+    locale_func::set_locale(&requested_locale);
+    // Answers shell completion requests ("COMPLETE=bash cfhdb ...") and exits
+    CompleteEnv::with_factory(cli::build_command).complete();
+    // Parse errors are reported in the JSON envelope too when --json was asked for
+    let json_requested = args.iter().enumerate().any(|(n, x)| {
+        x == "-j"
+            || x == "--json"
+            || x == "--format=json"
+            || ((x == "-f" || x == "--format") && args.get(n + 1).is_some_and(|x| x == "json"))
+    });
+    if json_requested {
+        output_func::set_json_mode("");
     }
+    let args = match cli::translate_legacy_args(args) {
+        Ok(t) => t,
+        Err(arg) => output_func::exit_with_error(
+            "multiple_actions_specified",
+            t!("multiple_actions_specified", arg = arg),
+        ),
+    };
+    let matches = match cli::build_command().try_get_matches_from(args) {
+        Ok(t) => t,
+        Err(e) if !e.use_stderr() || !output_func::is_json_mode() => e.exit(),
+        Err(e) => output_func::exit_with_error("invalid_arguments", e.to_string().trim()),
+    };
+    run_command(&matches);
+}
+
+fn is_writable(path: &Path) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(t) => unsafe { libc::access(t.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+// Called right before /var/cache/cfhdb is written, so only commands that write it can prompt for
+// pkexec, and only when a file in it is not writable. Root uses /var/lib/cfhdb for profiles and
+// can write the cache anyway, taking it over would only lock the user out.
+pub fn fix_cache_permissions() {
+    static CACHE_CHECKED: Once = Once::new();
+    CACHE_CHECKED.call_once(|| {
+        if get_current_username().is_some_and(|x| x == "root") {
+            return;
+        }
+        let cache_dir = Path::new("/var/cache/cfhdb");
+        let writable = is_writable(cache_dir)
+            && fs::read_dir(cache_dir)
+                .map(|x| x.flatten().all(|x| is_writable(&x.path())))
+                .unwrap_or(false);
+        if writable {
+            return;
+        }
+        // A cache that stays unwritable only means profiles are downloaded again next time
+        if let Err(e) = duct::cmd!("bash", "-c", PERM_FIX_PROG)
+            .stdout_to_stderr()
+            .run()
+        {
+            eprintln!("[{}] {}", t!("warn").bright_yellow(), e);
+        }
+    });
 }
 
 pub fn run_in_lock_script(script: &str) {
+    fix_cache_permissions();
     let file_path = "/var/cache/cfhdb/script_lock.sh";
     let file_fs_path = Path::new(file_path);
     if file_fs_path.exists() {
@@ -680,9 +366,10 @@ pub fn run_in_lock_script(script: &str) {
     } else {
         duct::cmd!("pkexec", file_path)
     };
-    match final_cmd.run() {
+    // Package manager output is logging too, stdout is kept for results
+    match final_cmd.stdout_to_stderr().run() {
         Ok(_) => {
-            println!(
+            eprintln!(
                 "[{}] {}",
                 t!("info").bright_green(),
                 t!("install_script_successful")
@@ -690,13 +377,23 @@ pub fn run_in_lock_script(script: &str) {
             fs::remove_file(file_fs_path).unwrap();
         }
         Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("install_script_failed"));
             fs::remove_file(file_fs_path).unwrap();
-            exit(1);
+            output_func::exit_with_translated_error("install_script_failed");
         }
     }
 }
 
+// Any user can write /var/cache/cfhdb, so root keeps its own copy and never runs a planted
+// check_script. data/tmpfiles.d/cfhdb.conf creates the root-owned directory.
+pub fn get_profile_cache_path(bus: &str) -> PathBuf {
+    let cache_dir = if get_current_username().is_some_and(|x| x == "root") {
+        "/var/lib/cfhdb"
+    } else {
+        "/var/cache/cfhdb"
+    };
+    Path::new(cache_dir).join(format!("{}.json", bus))
+}
+
 pub fn get_profile_url_config() -> ProfileUrlConfig {
     let file_path = "/etc/cfhdb/profile-config.json";
     let json_content = fs::read_to_string(file_path).unwrap();
@@ -0,0 +1,183 @@
+use crate::{
+    bt_func::get_visible_bt_profiles, output_func::*, pci_func::get_visible_pci_profiles,
+    usb_func::get_visible_usb_profiles,
+};
+use colored::Colorize;
+use libcfhdb::{
+    bt::{CfhdbBtDevice, CfhdbBtProfile},
+    monitor::{CfhdbDeviceMonitor, CfhdbHotplugEvent},
+    pci::{CfhdbPciDevice, CfhdbPciProfile},
+    usb::{CfhdbUsbDevice, CfhdbUsbProfile},
+};
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+struct MonitorProfile {
+    codename: String,
+    i18n_desc: String,
+    experimental: bool,
+}
+
+// Emitted for a new device that has compatible profiles which are not installed yet
+#[derive(Serialize)]
+struct MonitorSuggestion {
+    bus: String,
+    id: String,
+    name: String,
+    profiles: Vec<MonitorProfile>,
+}
+
+// Profiles are loaded once when monitoring starts, so events are matched without hitting the network
+struct MonitorProfiles {
+    pci: Vec<CfhdbPciProfile>,
+    usb: Vec<CfhdbUsbProfile>,
+    bt: Vec<CfhdbBtProfile>,
+}
+
+fn get_uninstalled_profiles<T>(
+    profiles: Option<Vec<Arc<T>>>,
+    get_profile: fn(&T) -> MonitorProfile,
+    get_status: fn(&T) -> bool,
+) -> Vec<MonitorProfile> {
+    profiles
+        .unwrap_or_default()
+        .iter()
+        .filter(|x| !get_status(x))
+        .map(|x| get_profile(x))
+        .collect()
+}
+
+fn get_suggestion(
+    event: &CfhdbHotplugEvent,
+    profiles: &MonitorProfiles,
+) -> Option<MonitorSuggestion> {
+    let (bus, id, name, uninstalled) = match event {
+        CfhdbHotplugEvent::Pci(busid) => {
+            let device = CfhdbPciDevice::get_device_from_busid(busid).ok()?;
+            CfhdbPciDevice::set_available_profiles(&profiles.pci, &device);
+            let uninstalled = get_uninstalled_profiles(
+                device.available_profiles.0.lock().unwrap().clone(),
+                |x: &CfhdbPciProfile| MonitorProfile {
+                    codename: x.codename.clone(),
+                    i18n_desc: x.i18n_desc.clone(),
+                    experimental: x.experimental,
+                },
+                CfhdbPciProfile::get_status,
+            );
+            (
+                "pci",
+                busid,
+                format!("{} {}", device.vendor_name, device.device_name),
+                uninstalled,
+            )
+        }
+        CfhdbHotplugEvent::Usb(busid) => {
+            let device = CfhdbUsbDevice::get_device_from_busid(busid).ok()?;
+            CfhdbUsbDevice::set_available_profiles(&profiles.usb, &device);
+            let uninstalled = get_uninstalled_profiles(
+                device.available_profiles.0.lock().unwrap().clone(),
+                |x: &CfhdbUsbProfile| MonitorProfile {
+                    codename: x.codename.clone(),
+                    i18n_desc: x.i18n_desc.clone(),
+                    experimental: x.experimental,
+                },
+                CfhdbUsbProfile::get_status,
+            );
+            (
+                "usb",
+                busid,
+                format!(
+                    "{} {}",
+                    device.manufacturer_string_index, device.product_string_index
+                ),
+                uninstalled,
+            )
+        }
+        CfhdbHotplugEvent::Bt(address) => {
+            let device = CfhdbBtDevice::get_device_from_address(address).ok()?;
+            CfhdbBtDevice::set_available_profiles(&profiles.bt, &device);
+            let uninstalled = get_uninstalled_profiles(
+                device.available_profiles.0.lock().unwrap().clone(),
+                |x: &CfhdbBtProfile| MonitorProfile {
+                    codename: x.codename.clone(),
+                    i18n_desc: x.i18n_desc.clone(),
+                    experimental: x.experimental,
+                },
+                CfhdbBtProfile::get_status,
+            );
+            ("bt", address, device.alias.clone(), uninstalled)
+        }
+    };
+    if uninstalled.is_empty() {
+        return None;
+    }
+    Some(MonitorSuggestion {
+        bus: bus.to_string(),
+        id: id.clone(),
+        name,
+        profiles: uninstalled,
+    })
+}
+
+fn get_suggestion_message(suggestion: &MonitorSuggestion) -> String {
+    t!(
+        "monitor_profiles_available",
+        name = suggestion.name,
+        id = suggestion.id,
+        codenames = suggestion
+            .profiles
+            .iter()
+            .map(|x| x.codename.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+    .to_string()
+}
+
+// A missing notification daemon must not stop monitoring
+fn send_notification(suggestion: &MonitorSuggestion) {
+    let result = duct::cmd!(
+        "notify-send",
+        "--app-name=cfhdb",
+        "--icon=preferences-system",
+        t!("monitor_notification_title").to_string(),
+        get_suggestion_message(suggestion)
+    )
+    .stdout_to_stderr()
+    .run();
+    if let Err(e) = result {
+        eprintln!("[{}] {}", t!("warn").bright_yellow(), e);
+    }
+}
+
+pub fn monitor_devices(json: bool, notify: bool) {
+    let profiles = MonitorProfiles {
+        pci: get_visible_pci_profiles(false),
+        usb: get_visible_usb_profiles(false),
+        bt: get_visible_bt_profiles(false),
+    };
+    let monitor = match CfhdbDeviceMonitor::new(true) {
+        Ok(t) => t,
+        Err(e) => exit_with_error("io_error", e),
+    };
+    eprintln!("[{}] {}", t!("info").bright_green(), t!("monitor_started"));
+    while let Some(event) = monitor.recv() {
+        let suggestion = match get_suggestion(&event, &profiles) {
+            Some(t) => t,
+            None => continue,
+        };
+        if notify {
+            send_notification(&suggestion);
+        }
+        if json {
+            print_json_event(&suggestion);
+        } else {
+            println!(
+                "[{}] {}",
+                t!("info").bright_green(),
+                get_suggestion_message(&suggestion)
+            );
+        }
+    }
+}
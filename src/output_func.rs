@@ -0,0 +1,403 @@
+use cli_table::{Cell, Style, Table};
+use colored::Colorize;
+use serde::Serialize;
+use serde_json::Value;
+use std::{fmt::Display, process::exit, sync::Mutex};
+
+// Bumped whenever a field is removed or changes meaning, new fields can be added within a version
+pub const JSON_OUTPUT_VERSION: u64 = 1;
+
+// Every "code" exit_with_error is called with, listed in the JSON OUTPUT section of --help and the
+// man page. Codes are part of the versioned output and are only removed with a version bump.
+pub const JSON_ERROR_CODES: [&str; 29] = [
+    "apply_blacklist_requires_root",
+    "dbus_error",
+    "failed_to_get_bt_devices",
+    "failed_to_get_pci_devices",
+    "failed_to_get_usb_devices",
+    "install_script_failed",
+    "invalid_arguments",
+    "invalid_query",
+    "invalid_report",
+    "io_error",
+    "multiple_actions_specified",
+    "no_driver",
+    "no_iommu_group",
+    "no_matching_bt_device",
+    "no_matching_driver",
+    "no_matching_pci_device",
+    "no_matching_profile_codename",
+    "no_matching_usb_device",
+    "no_profiles_available_for_device",
+    "no_profiles_available_for_info",
+    "no_profiles_available_for_system",
+    "no_reset_method",
+    "no_sriov",
+    "no_such_device",
+    "not_authorized",
+    "profile_experimental",
+    "profile_not_removable",
+    "still_blacklisted",
+    "unknown_column",
+];
+
+// Set when --json is given, holds the command name reported in the envelope ("pci list", "bt pair" ...)
+static JSON_COMMAND: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Serialize)]
+struct JsonError {
+    // Stable identifier scripts can match on, the message is translated
+    code: String,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct JsonEnvelope<'a, T: Serialize> {
+    version: u64,
+    command: &'a str,
+    success: bool,
+    data: Option<T>,
+    error: Option<JsonError>,
+}
+
+pub fn set_json_mode(command: &str) {
+    *JSON_COMMAND.lock().unwrap() = Some(command.to_string());
+}
+
+pub fn is_json_mode() -> bool {
+    JSON_COMMAND.lock().unwrap().is_some()
+}
+
+fn get_envelope<T: Serialize>(data: Option<T>, error: Option<JsonError>) -> Value {
+    let command = JSON_COMMAND.lock().unwrap().clone().unwrap_or_default();
+    let envelope = JsonEnvelope {
+        version: JSON_OUTPUT_VERSION,
+        command: &command,
+        success: error.is_none(),
+        data,
+        error,
+    };
+    serde_json::to_value(&envelope).unwrap()
+}
+
+fn print_envelope<T: Serialize>(data: Option<T>, error: Option<JsonError>) {
+    let json_pretty = serde_json::to_string_pretty(&get_envelope(data, error)).unwrap();
+    println!("{}", json_pretty);
+}
+
+pub fn print_json_result<T: Serialize>(data: T) {
+    print_envelope(Some(data), None);
+}
+
+// Long running commands print one envelope per line, so consumers can read events as they come
+pub fn print_json_event<T: Serialize>(data: T) {
+    println!("{}", get_envelope(Some(data), None));
+}
+
+pub fn exit_with_error(code: &str, message: impl Display) -> ! {
+    if is_json_mode() {
+        print_envelope::<Value>(
+            None,
+            Some(JsonError {
+                code: code.to_string(),
+                message: message.to_string(),
+            }),
+        );
+    } else {
+        eprintln!("[{}] {}", t!("error").red(), message);
+    }
+    exit(1);
+}
+
+// For errors that are a plain message, the translation key doubles as the error code
+pub fn exit_with_translated_error(key: &str) -> ! {
+    exit_with_error(key, t!(key))
+}
+
+pub const PROFILE_COLUMNS: [&str; 6] = [
+    "codename",
+    "i18n_desc",
+    "license",
+    "priority",
+    "experimental",
+    "installed",
+];
+
+// Full profile details as listed by the "profiles" commands.
+// A missing license stays null here, only the profile tables show it as the localized unknown.
+pub fn profile_to_json<T: Serialize>(profile: &T, installed: bool) -> Value {
+    let mut value = serde_json::to_value(profile).unwrap();
+    value["installed"] = Value::Bool(installed);
+    value
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Yaml,
+    Csv,
+    Tsv,
+    Markdown,
+}
+
+impl OutputFormat {
+    pub const NAMES: [&'static str; 6] = ["table", "json", "yaml", "csv", "tsv", "markdown"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "table" => Some(Self::Table),
+            "json" => Some(Self::Json),
+            "yaml" => Some(Self::Yaml),
+            "csv" => Some(Self::Csv),
+            "tsv" => Some(Self::Tsv),
+            "markdown" => Some(Self::Markdown),
+            _ => None,
+        }
+    }
+}
+
+static OUTPUT_FORMAT: Mutex<(OutputFormat, Option<Vec<String>>)> =
+    Mutex::new((OutputFormat::Table, None));
+
+pub fn set_output_format(format: OutputFormat, columns: Option<Vec<String>>) {
+    *OUTPUT_FORMAT.lock().unwrap() = (format, columns);
+}
+
+// Table and JSON keep their grouped layout unless columns are picked, everything else is a flat record list
+pub fn is_record_output() -> bool {
+    let (format, columns) = &*OUTPUT_FORMAT.lock().unwrap();
+    columns.is_some() || !matches!(format, OutputFormat::Table | OutputFormat::Json)
+}
+
+pub fn get_cell_string(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(t) => t.clone(),
+        Value::Array(t) => t.iter().map(get_cell_string).collect::<Vec<_>>().join(","),
+        t => t.to_string(),
+    }
+}
+
+fn escape_csv(cell: &str) -> String {
+    if cell.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell.to_string()
+    }
+}
+
+fn escape_markdown(cell: &str) -> String {
+    cell.replace('|', "\\|").replace('\n', "<br>")
+}
+
+// Prints serialized devices or profiles as flat records, nothing is truncated.
+// Without --columns yaml and json keep every field, the tabular formats use default_columns.
+pub fn print_records(records: Vec<Value>, default_columns: &[&str]) {
+    let (format, columns) = OUTPUT_FORMAT.lock().unwrap().clone();
+    let available_columns: Vec<String> = match records.first() {
+        Some(Value::Object(t)) => t.keys().cloned().collect(),
+        _ => default_columns.iter().map(|x| x.to_string()).collect(),
+    };
+    if let Some(columns) = &columns {
+        if let Some(column) = columns.iter().find(|x| !available_columns.contains(x)) {
+            exit_with_error(
+                "unknown_column",
+                t!(
+                    "unknown_column",
+                    column = column,
+                    columns = available_columns.join(", ")
+                ),
+            );
+        }
+    }
+    let columns = match columns {
+        Some(t) => t,
+        None if matches!(format, OutputFormat::Json | OutputFormat::Yaml) => available_columns,
+        None => default_columns.iter().map(|x| x.to_string()).collect(),
+    };
+    let records: Vec<serde_json::Map<String, Value>> = records
+        .iter()
+        .map(|record| {
+            columns
+                .iter()
+                .map(|column| (column.clone(), record[column].clone()))
+                .collect()
+        })
+        .collect();
+    match format {
+        OutputFormat::Json => print_json_result(records),
+        OutputFormat::Yaml => print!("{}", serde_yaml::to_string(&records).unwrap()),
+        OutputFormat::Csv => {
+            println!(
+                "{}",
+                columns
+                    .iter()
+                    .map(|x| escape_csv(x))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
+            for record in records {
+                let row: Vec<String> = record
+                    .values()
+                    .map(|x| escape_csv(&get_cell_string(x)))
+                    .collect();
+                println!("{}", row.join(","));
+            }
+        }
+        OutputFormat::Tsv => {
+            println!("{}", columns.join("\t"));
+            for record in records {
+                let row: Vec<String> = record
+                    .values()
+                    .map(|x| get_cell_string(x).replace(['\t', '\n', '\r'], " "))
+                    .collect();
+                println!("{}", row.join("\t"));
+            }
+        }
+        OutputFormat::Markdown => {
+            println!("| {} |", columns.join(" | "));
+            println!("|{}", "---|".repeat(columns.len()));
+            for record in records {
+                let row: Vec<String> = record
+                    .values()
+                    .map(|x| escape_markdown(&get_cell_string(x)))
+                    .collect();
+                println!("| {} |", row.join(" | "));
+            }
+        }
+        OutputFormat::Table => {
+            let table_struct: Vec<Vec<_>> = records
+                .iter()
+                .map(|record| record.values().map(|x| get_cell_string(x).cell()).collect())
+                .collect();
+            let table = table_struct
+                .table()
+                .title(
+                    columns
+                        .iter()
+                        .map(|x| x.cell().bold(true))
+                        .collect::<Vec<_>>(),
+                )
+                .bold(true);
+
+            let table_display = table.display().unwrap();
+
+            println!("{}", table_display);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libcfhdb::sysfs::CfhdbSysfsError;
+    use std::{fs, path::Path};
+
+    fn get_sysfs_errors() -> Vec<CfhdbSysfsError> {
+        let busid = "0000:00:00.0".to_string();
+        let errors = vec![
+            CfhdbSysfsError::InvalidArguments {
+                message: String::new(),
+            },
+            CfhdbSysfsError::NoSuchDevice {
+                bus: "pci".to_string(),
+                busid: busid.clone(),
+            },
+            CfhdbSysfsError::NoDriver {
+                busid: busid.clone(),
+            },
+            CfhdbSysfsError::NoMatchingDriver {
+                busid: busid.clone(),
+            },
+            CfhdbSysfsError::NoIommuGroup {
+                busid: busid.clone(),
+            },
+            CfhdbSysfsError::NoResetMethod {
+                busid: busid.clone(),
+            },
+            CfhdbSysfsError::NoSriov {
+                busid: busid.clone(),
+            },
+            CfhdbSysfsError::StillBlacklisted {
+                busid: busid.clone(),
+                entry: String::new(),
+            },
+            CfhdbSysfsError::NotAuthorized,
+            CfhdbSysfsError::Io {
+                message: String::new(),
+            },
+        ];
+        // A new variant fails to compile here until it is added above
+        for error in &errors {
+            match error {
+                CfhdbSysfsError::InvalidArguments { .. }
+                | CfhdbSysfsError::NoSuchDevice { .. }
+                | CfhdbSysfsError::NoDriver { .. }
+                | CfhdbSysfsError::NoMatchingDriver { .. }
+                | CfhdbSysfsError::NoIommuGroup { .. }
+                | CfhdbSysfsError::NoResetMethod { .. }
+                | CfhdbSysfsError::NoSriov { .. }
+                | CfhdbSysfsError::StillBlacklisted { .. }
+                | CfhdbSysfsError::NotAuthorized
+                | CfhdbSysfsError::Io { .. } => {}
+            }
+        }
+        errors
+    }
+
+    // Codes passed as a string literal to exit_with_error or exit_with_translated_error
+    fn get_source_error_codes(directory: &Path, codes: &mut Vec<String>) {
+        for entry in fs::read_dir(directory).unwrap().flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                get_source_error_codes(&path, codes);
+                continue;
+            }
+            if path.extension().and_then(|x| x.to_str()) != Some("rs") {
+                continue;
+            }
+            let source = fs::read_to_string(&path).unwrap();
+            for call in ["exit_with_error(", "exit_with_translated_error("] {
+                for (n, _) in source.match_indices(call) {
+                    let rest = source[n + call.len()..].trim_start();
+                    let code = match rest.strip_prefix('"') {
+                        Some(t) => &t[..t.find('"').unwrap()],
+                        None => continue,
+                    };
+                    // Skips the call names quoted in this test
+                    let is_code_char = |x: char| x.is_ascii_lowercase() || x == '_';
+                    if !code.is_empty() && code.chars().all(is_code_char) {
+                        codes.push(code.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_error_code_is_documented() {
+        let mut codes: Vec<String> = get_sysfs_errors()
+            .iter()
+            .map(|x| x.get_code().to_string())
+            .collect();
+        get_source_error_codes(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("src"),
+            &mut codes,
+        );
+        for code in &codes {
+            assert!(
+                JSON_ERROR_CODES.contains(&code.as_str()),
+                "{} is not listed",
+                code
+            );
+        }
+        for code in JSON_ERROR_CODES {
+            assert!(codes.iter().any(|x| x == code), "{} is never used", code);
+        }
+        assert!(
+            JSON_ERROR_CODES.is_sorted(),
+            "JSON_ERROR_CODES is not sorted"
+        );
+    }
+}
@@ -1,20 +1,62 @@
-use crate::{config::*, get_profile_url_config, run_in_lock_script};
+use crate::{
+    config::*, fix_cache_permissions, get_profile_cache_path, get_profile_url_config,
+    locale_func::get_profile_locale, output_func::*, profile_func::ProfileTransaction,
+};
 use cli_table::{Cell, Color, Style, Table};
 use colored::Colorize;
 use lazy_static::lazy_static;
-use libcfhdb::pci::*;
-use std::{collections::HashMap, fs, ops::Deref, path::Path, process::exit};
+use libcfhdb::{
+    pci::*,
+    sysfs::CfhdbRuntimePmControl,
+    profile::{get_local_profile_source, load_profile_source, parse_profile_database},
+    query::{filter_items, QueryExpr},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    ops::Deref,
+};
 
 lazy_static! {
     static ref PCI_PROFILE_JSON_URL: String = get_profile_url_config().pci_json_url;
 }
 
+const PCI_DEVICE_COLUMNS: [&str; 9] = [
+    "class_name",
+    "vendor_name",
+    "device_name",
+    "sysfs_busid",
+    "kernel_driver",
+    "started",
+    "enabled",
+    "iommu_group",
+    "candidate_modules",
+];
+
 fn display_pci_devices_print_json(hashmap: HashMap<String, Vec<CfhdbPciDevice>>) {
-    let json_pretty = serde_json::to_string_pretty(&hashmap).unwrap();
-    println!("{}", json_pretty);
+    // Sorted by class so the output is stable between runs
+    print_json_result(hashmap.into_iter().collect::<BTreeMap<_, _>>());
+}
+fn display_pci_devices_print_records(mut devices: Vec<CfhdbPciDevice>) {
+    devices.sort_by(|a, b| (&a.class_id, &a.sysfs_busid).cmp(&(&b.class_id, &b.sysfs_busid)));
+    print_records(
+        devices
+            .iter()
+            .map(|x| serde_json::to_value(x).unwrap())
+            .collect(),
+        &PCI_DEVICE_COLUMNS,
+    );
 }
 fn display_pci_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbPciDevice>>) {
-    for (class, devices) in hashmap {
+    for (class, mut devices) in hashmap {
+        // Virtual functions are listed right below their physical function
+        devices.sort_by_key(|x| {
+            (
+                x.physfn.clone().unwrap_or_else(|| x.sysfs_busid.clone()),
+                x.physfn.is_some(),
+                x.sysfs_busid.clone(),
+            )
+        });
         let mut table_struct = vec![];
         for device in devices {
             let cell_table = vec![
@@ -28,7 +70,10 @@ fn display_pci_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbPciDevi
                     Some((idx, _)) => device.device_name[..idx].to_string() + "...",
                 }
                 .cell(),
-                device.sysfs_busid.cell(),
+                match device.physfn {
+                    Some(_) => format!("└ {}", device.sysfs_busid).cell(),
+                    None => device.sysfs_busid.cell(),
+                },
                 match device.kernel_driver.as_str() {
                     "Unknown" => t!("unknown")
                         .to_string()
@@ -36,6 +81,17 @@ fn display_pci_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbPciDevi
                         .foreground_color(Some(Color::Yellow)),
                     _ => device.kernel_driver.cell(),
                 },
+                match device.candidate_modules.is_empty() {
+                    true => t!("enabled_na").cell(),
+                    false => {
+                        let candidates = device.candidate_modules.join(", ");
+                        match candidates.char_indices().nth(24) {
+                            None => candidates,
+                            Some((idx, _)) => candidates[..idx].to_string() + "...",
+                        }
+                        .cell()
+                    }
+                },
                 match device.started {
                     Some(t) => {
                         if t {
@@ -65,6 +121,7 @@ fn display_pci_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbPciDevi
                 t!("pci_table_name").cell().bold(true),
                 t!("pci_table_sysfs_bus_id").cell().bold(true),
                 t!("pci_table_driver").cell().bold(true),
+                t!("pci_table_candidates").cell().bold(true),
                 t!("pci_table_started").cell().bold(true),
                 t!("pci_table_enabled").cell().bold(true),
             ])
@@ -84,14 +141,7 @@ fn display_pci_profiles_print_cli_table(target: &CfhdbPciDevice) {
     let mut table_struct = vec![];
     let mut profiles = match target.available_profiles.0.lock().unwrap().clone() {
         Some(t) => t,
-        None => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_profiles_available_for_device")
-            );
-            exit(1);
-        }
+        None => exit_with_translated_error("no_profiles_available_for_device"),
     };
     profiles.sort_by_key(|k| k.priority);
     for profile in profiles {
@@ -104,7 +154,10 @@ fn display_pci_profiles_print_cli_table(target: &CfhdbPciDevice) {
                 Some((idx, _)) => profile.i18n_desc[..idx].to_string() + "...",
             }
             .cell(),
-            profile.license.cell(),
+            profile
+                .license
+                .unwrap_or_else(|| t!("unknown").to_string())
+                .cell(),
             profile.priority.cell(),
             if profile.experimental {
                 t!("enabled_yes").cell().foreground_color(Some(Color::Red))
@@ -138,186 +191,77 @@ fn display_pci_profiles_print_cli_table(target: &CfhdbPciDevice) {
     println!("{}\n{}", target.sysfs_busid.bright_green(), table_display);
 }
 
-pub fn display_pci_devices(json: bool) {
+pub fn display_pci_devices(json: bool, query: Option<&QueryExpr>, show_veiled: bool) {
     match CfhdbPciDevice::get_devices() {
         Some(devices) => {
-            let profiles = match get_pci_profiles_from_url() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
+            let profiles = get_visible_pci_profiles(show_veiled);
             for i in &devices {
                 CfhdbPciDevice::set_available_profiles(&profiles, &i);
             }
-            let hashmap = CfhdbPciDevice::create_class_hashmap(devices);
-            if json {
-                display_pci_devices_print_json(hashmap)
+            let devices = match query {
+                Some(query) => match filter_items(devices, query) {
+                    Ok(t) => t,
+                    Err(e) => exit_with_error("invalid_query", e),
+                },
+                None => devices,
+            };
+            if is_record_output() {
+                display_pci_devices_print_records(devices)
+            } else if json {
+                display_pci_devices_print_json(CfhdbPciDevice::create_class_hashmap(devices))
             } else {
-                display_pci_devices_print_cli_table(hashmap)
+                display_pci_devices_print_cli_table(CfhdbPciDevice::create_class_hashmap(devices))
             }
         }
-        None => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("failed_to_get_pci_devices")
-            );
-            exit(1);
-        }
+        None => exit_with_translated_error("failed_to_get_pci_devices"),
     }
 }
 
-pub fn display_pci_profiles(json: bool, target: &str) {
+pub fn display_pci_profiles(json: bool, target: &str, show_veiled: bool) {
     match CfhdbPciDevice::get_device_from_busid(target) {
         Ok(target_device) => {
-            let profiles = match get_pci_profiles_from_url() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
+            let profiles = get_visible_pci_profiles(show_veiled);
             CfhdbPciDevice::set_available_profiles(&profiles, &target_device);
-            if json {
+            if json || is_record_output() {
                 let mut profile_arc =
                     match target_device.available_profiles.0.lock().unwrap().clone() {
                         Some(t) => t,
-                        None => {
-                            eprintln!(
-                                "[{}] {}",
-                                t!("error").red(),
-                                t!("no_profiles_available_for_device")
-                            );
-                            exit(1);
-                        }
+                        None => exit_with_translated_error("no_profiles_available_for_device"),
                     };
                 profile_arc.sort_by_key(|k| k.priority);
                 let profiles = profile_arc
                     .iter()
-                    .map(|s| s.codename.clone())
+                    .map(|s| profile_to_json(s.deref(), s.get_status()))
                     .collect::<Vec<_>>();
-                let json_pretty = serde_json::to_string_pretty(&profiles).unwrap();
-                println!("{}", json_pretty);
+                if is_record_output() {
+                    print_records(profiles, &PROFILE_COLUMNS);
+                } else {
+                    print_json_result(profiles);
+                }
             } else {
                 display_pci_profiles_print_cli_table(&target_device);
             }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_pci_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_pci_device"),
     }
 }
 
-pub fn install_pci_profile(profile_codename: &str) {
-    let profiles = match get_pci_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    match CfhdbPciProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if target_profile.get_status() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_already_installed")
-                );
-            } else {
-                match target_profile.install_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_installer(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_installer(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
-            }
-        }
-        Err(_) => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
-            );
-            exit(1);
-        }
-    }
-}
-pub fn uninstall_pci_profile(profile_codename: &str) {
-    let profiles = match get_pci_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    match CfhdbPciProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if !target_profile.get_status() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_not_installed")
-                );
-            } else {
-                match target_profile.remove_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_uninstaller(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_uninstaller(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
-            }
-        }
-        Err(_) => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
-            );
-            exit(1);
-        }
-    }
+pub fn get_pci_profile_transaction(
+    profile_codename: &str,
+) -> Result<Option<ProfileTransaction>, std::io::Error> {
+    let profiles = get_pci_profiles_from_url()?;
+    Ok(
+        CfhdbPciProfile::get_profile_from_codename(profile_codename, profiles)
+            .ok()
+            .map(|x| ProfileTransaction {
+                installed: x.get_status(),
+                experimental: x.experimental,
+                removable: x.removable,
+                install_commands: get_install_commands(&x.packages, &x.install_script),
+                remove_commands: get_remove_commands(&x.packages, &x.remove_script),
+                post_actions: x.post_actions,
+            }),
+    )
 }
 
 pub fn enable_pci_device(target_sysfs_id: &str) {
@@ -325,16 +269,13 @@ pub fn enable_pci_device(target_sysfs_id: &str) {
         Ok(target_device) => {
             match target_device.enable_device() {
                 Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
+                Err(e) => exit_with_error(e.get_code(), e),
             };
+            if is_json_mode() {
+                print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+            }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_pci_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_pci_device"),
     }
 }
 pub fn disable_pci_device(target_sysfs_id: &str) {
@@ -342,16 +283,13 @@ pub fn disable_pci_device(target_sysfs_id: &str) {
         Ok(target_device) => {
             match target_device.disable_device() {
                 Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
+                Err(e) => exit_with_error(e.get_code(), e),
             };
+            if is_json_mode() {
+                print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+            }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_pci_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_pci_device"),
     }
 }
 
@@ -360,16 +298,13 @@ pub fn start_pci_device(target_sysfs_id: &str) {
         Ok(target_device) => {
             match target_device.start_device() {
                 Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
+                Err(e) => exit_with_error(e.get_code(), e),
             };
+            if is_json_mode() {
+                print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+            }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_pci_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_pci_device"),
     }
 }
 pub fn stop_pci_device(target_sysfs_id: &str) {
@@ -377,22 +312,386 @@ pub fn stop_pci_device(target_sysfs_id: &str) {
         Ok(target_device) => {
             match target_device.stop_device() {
                 Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
+                Err(e) => exit_with_error(e.get_code(), e),
             };
+            if is_json_mode() {
+                print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+            }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_pci_device"));
-            exit(1);
+        Err(_) => exit_with_translated_error("no_matching_pci_device"),
+    }
+}
+
+pub fn bind_pci_device(target_sysfs_id: &str, driver: Option<&str>, persistent: bool) {
+    match CfhdbPciDevice::get_device_from_busid(target_sysfs_id) {
+        Ok(target_device) => {
+            if let Err(e) = target_device.bind_device(driver, persistent) {
+                exit_with_error(e.get_code(), e);
+            }
+            let message = match driver {
+                Some(t) => t!("pci_device_bound", device = target_sysfs_id, driver = t),
+                None => t!("pci_driver_override_cleared", device = target_sysfs_id),
+            };
+            eprintln!("[{}] {}", t!("info").bright_green(), message);
+            if is_json_mode() {
+                print_json_result(serde_json::json!({
+                    "device": target_sysfs_id,
+                    "driver": driver,
+                    "persistent": persistent,
+                }));
+            }
+        }
+        Err(_) => exit_with_translated_error("no_matching_pci_device"),
+    }
+}
+
+const PCI_DRIVER_COLUMNS: [&str; 7] = [
+    "name",
+    "version",
+    "license",
+    "firmware",
+    "bound",
+    "blacklisted",
+    "override",
+];
+
+pub fn display_pci_drivers(json: bool, target_sysfs_id: &str) {
+    let target_device = match CfhdbPciDevice::get_device_from_busid(target_sysfs_id) {
+        Ok(t) => t,
+        Err(_) => exit_with_translated_error("no_matching_pci_device"),
+    };
+    let modules = match target_device.get_candidate_modules() {
+        Ok(t) => t,
+        Err(e) => exit_with_error("io_error", e),
+    };
+    // Module names use "_" where driver names often use "-"
+    let is_same_driver = |a: &str, b: &str| a.replace('-', "_") == b.replace('-', "_");
+    let driver_override = target_device.get_driver_override();
+    let records: Vec<_> = modules
+        .iter()
+        .map(|x| {
+            let mut record = serde_json::to_value(x).unwrap();
+            record["override"] = driver_override
+                .as_deref()
+                .is_some_and(|t| is_same_driver(&x.name, t))
+                .into();
+            record
+        })
+        .collect();
+    if json && !is_record_output() {
+        print_json_result(records);
+    } else if records.is_empty() && !is_record_output() {
+        eprintln!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("no_candidate_drivers")
+        );
+    } else {
+        print_records(records, &PCI_DRIVER_COLUMNS);
+    }
+}
+
+const IOMMU_GROUP_COLUMNS: [&str; 6] = [
+    "iommu_group",
+    "sysfs_busid",
+    "class_name",
+    "vendor_name",
+    "device_name",
+    "kernel_driver",
+];
+
+fn get_iommu_group_record(
+    group: &str,
+    busid: &str,
+    devices: &[CfhdbPciDevice],
+) -> serde_json::Value {
+    let device = devices.iter().find(|x| x.sysfs_busid == busid);
+    serde_json::json!({
+        "iommu_group": group,
+        "sysfs_busid": busid,
+        "class_name": device.map(|x| x.class_name.as_str()),
+        "vendor_name": device.map(|x| x.vendor_name.as_str()),
+        "device_name": device.map(|x| x.device_name.as_str()),
+        "kernel_driver": device.map(|x| x.kernel_driver.as_str()),
+    })
+}
+
+pub fn display_iommu_groups(json: bool) {
+    let groups = match get_iommu_groups() {
+        Ok(t) => t,
+        Err(e) => exit_with_error("io_error", e),
+    };
+    let devices = CfhdbPciDevice::get_devices().unwrap_or_default();
+    let records: Vec<_> = groups
+        .iter()
+        .flat_map(|group| {
+            group
+                .devices
+                .iter()
+                .map(|x| get_iommu_group_record(&group.id, x, &devices))
+        })
+        .collect();
+    if json && !is_record_output() {
+        print_json_result(records);
+    } else if records.is_empty() && !is_record_output() {
+        eprintln!("[{}] {}", t!("info").bright_green(), t!("no_iommu_groups"));
+    } else {
+        print_records(records, &IOMMU_GROUP_COLUMNS);
+    }
+}
+
+// Binds the whole IOMMU group of a device to vfio-pci, devices the host uses only get a warning
+pub fn vfio_bind_pci_device(target_sysfs_id: &str, persistent: bool) {
+    let target_device = match CfhdbPciDevice::get_device_from_busid(target_sysfs_id) {
+        Ok(t) => t,
+        Err(_) => exit_with_translated_error("no_matching_pci_device"),
+    };
+    let group = match &target_device.iommu_group {
+        Some(t) => t.clone(),
+        None => exit_with_translated_error("no_iommu_group"),
+    };
+    let devices = CfhdbPciDevice::get_devices().unwrap_or_default();
+    let mut members = target_device.get_iommu_group_members();
+    members.insert(0, target_device.sysfs_busid.clone());
+    let mut endpoints = vec![];
+    for device in devices.iter().filter(|x| members.contains(&x.sysfs_busid)) {
+        // Bridges stay with the host
+        if device.class_id.starts_with("06") {
+            continue;
         }
+        if !["Unknown", "vfio-pci"].contains(&device.kernel_driver.as_str()) {
+            eprintln!(
+                "[{}] {}",
+                t!("warn").bright_yellow(),
+                t!(
+                    "vfio_device_in_use",
+                    device = device.sysfs_busid,
+                    name = device.device_name,
+                    driver = device.kernel_driver
+                )
+            );
+        }
+        endpoints.push(device.sysfs_busid.clone());
+    }
+    if let Err(e) = target_device.bind_iommu_group("vfio-pci", persistent) {
+        exit_with_error(e.get_code(), e);
+    }
+    eprintln!(
+        "[{}] {}",
+        t!("info").bright_green(),
+        t!("vfio_group_bound", group = group, devices = endpoints.join(", "))
+    );
+    if is_json_mode() {
+        print_json_result(serde_json::json!({
+            "device": target_sysfs_id,
+            "iommu_group": group,
+            "devices": endpoints,
+            "persistent": persistent,
+        }));
+    }
+}
+
+const PCI_POWER_COLUMNS: [&str; 6] = [
+    "sysfs_busid",
+    "device_name",
+    "control",
+    "runtime_status",
+    "d_state",
+    "aspm",
+];
+
+// aspm only lists the enabled states, all of them are in the JSON output
+fn get_pci_power_record(device: &CfhdbPciDevice) -> serde_json::Value {
+    serde_json::json!({
+        "sysfs_busid": device.sysfs_busid,
+        "device_name": device.device_name,
+        "control": device.power.control,
+        "runtime_status": device.power.runtime_status,
+        "d_state": device.power.d_state,
+        "aspm": device
+            .power
+            .aspm
+            .iter()
+            .filter(|(_, enabled)| **enabled)
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>(),
+    })
+}
+
+pub fn display_pci_power(json: bool, target_sysfs_id: Option<&str>) {
+    let devices = match target_sysfs_id {
+        Some(busid) => match CfhdbPciDevice::get_device_from_busid(busid) {
+            Ok(t) => vec![t],
+            Err(_) => exit_with_translated_error("no_matching_pci_device"),
+        },
+        None => CfhdbPciDevice::get_devices().unwrap_or_default(),
+    };
+    if json && !is_record_output() {
+        print_json_result(
+            devices
+                .iter()
+                .map(|x| (x.sysfs_busid.as_str(), &x.power))
+                .collect::<BTreeMap<_, _>>(),
+        );
+    } else {
+        print_records(
+            devices.iter().map(get_pci_power_record).collect(),
+            &PCI_POWER_COLUMNS,
+        );
+    }
+}
+
+pub fn set_pci_runtime_pm(target_sysfs_id: &str, control: &str, persistent: bool) {
+    let target_device = match CfhdbPciDevice::get_device_from_busid(target_sysfs_id) {
+        Ok(t) => t,
+        Err(_) => exit_with_translated_error("no_matching_pci_device"),
+    };
+    // clap only lets auto and on through
+    let control = CfhdbRuntimePmControl::from_name(control).unwrap();
+    if let Err(e) = target_device.set_runtime_pm(control, persistent) {
+        exit_with_error(e.get_code(), e);
+    }
+    eprintln!(
+        "[{}] {}",
+        t!("info").bright_green(),
+        t!(
+            "pci_runtime_pm_set",
+            device = target_sysfs_id,
+            control = control.get_name()
+        )
+    );
+    if is_json_mode() {
+        print_json_result(serde_json::json!({
+            "device": target_sysfs_id,
+            "control": control,
+            "persistent": persistent,
+        }));
+    }
+}
+
+pub fn reset_pci_device(target_sysfs_id: &str, method: Option<&str>) {
+    let target_device = match CfhdbPciDevice::get_device_from_busid(target_sysfs_id) {
+        Ok(t) => t,
+        Err(_) => exit_with_translated_error("no_matching_pci_device"),
+    };
+    if let Err(e) = target_device.reset_device(method) {
+        exit_with_error(e.get_code(), e);
+    }
+    eprintln!(
+        "[{}] {}",
+        t!("info").bright_green(),
+        t!("pci_device_reset", device = target_sysfs_id)
+    );
+    if is_json_mode() {
+        print_json_result(serde_json::json!({
+            "device": target_sysfs_id,
+            "method": method,
+        }));
     }
 }
 
-fn get_pci_profiles_from_url() -> Result<Vec<CfhdbPciProfile>, std::io::Error> {
-    let cached_db_path = Path::new("/var/cache/cfhdb/pci.json");
-    println!(
+pub fn remove_pci_device(target_sysfs_id: &str) {
+    let target_device = match CfhdbPciDevice::get_device_from_busid(target_sysfs_id) {
+        Ok(t) => t,
+        Err(_) => exit_with_translated_error("no_matching_pci_device"),
+    };
+    if let Err(e) = target_device.remove_device() {
+        exit_with_error(e.get_code(), e);
+    }
+    eprintln!(
+        "[{}] {}",
+        t!("info").bright_green(),
+        t!("pci_device_removed", device = target_sysfs_id)
+    );
+    if is_json_mode() {
+        print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+    }
+}
+
+// Removed devices have no sysfs entry left, so the bridge is not looked up in the device list
+pub fn rescan_pci_bus(bridge: Option<&str>) {
+    let before: Vec<String> = CfhdbPciDevice::get_devices()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|x| x.sysfs_busid)
+        .collect();
+    if let Err(e) = CfhdbPciDevice::rescan_bus(bridge) {
+        exit_with_error(e.get_code(), e);
+    }
+    let found: Vec<String> = CfhdbPciDevice::get_devices()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|x| x.sysfs_busid)
+        .filter(|x| !before.contains(x))
+        .collect();
+    eprintln!(
+        "[{}] {}",
+        t!("info").bright_green(),
+        t!("pci_bus_rescanned", count = found.len())
+    );
+    if is_json_mode() {
+        print_json_result(serde_json::json!({
+            "bridge": bridge,
+            "devices": found,
+        }));
+    }
+}
+
+const SRIOV_COLUMNS: [&str; 6] = [
+    "sysfs_busid",
+    "device_name",
+    "kernel_driver",
+    "sriov_totalvfs",
+    "sriov_numvfs",
+    "virtfns",
+];
+
+// Physical functions only, their virtual functions are in virtfns
+pub fn display_sriov_devices(json: bool) {
+    let records: Vec<_> = CfhdbPciDevice::get_devices()
+        .unwrap_or_default()
+        .iter()
+        .filter(|x| x.sriov_totalvfs.is_some())
+        .map(|x| serde_json::to_value(x).unwrap())
+        .collect();
+    if json && !is_record_output() {
+        print_json_result(records);
+    } else if records.is_empty() && !is_record_output() {
+        eprintln!("[{}] {}", t!("info").bright_green(), t!("no_sriov_devices"));
+    } else {
+        print_records(records, &SRIOV_COLUMNS);
+    }
+}
+
+pub fn set_pci_vf_count(target_sysfs_id: &str, count: u32) {
+    let target_device = match CfhdbPciDevice::get_device_from_busid(target_sysfs_id) {
+        Ok(t) => t,
+        Err(_) => exit_with_translated_error("no_matching_pci_device"),
+    };
+    if let Err(e) = target_device.set_vf_count(count) {
+        exit_with_error(e.get_code(), e);
+    }
+    // Read back so the output lists the VFs the driver created
+    let virtfns = CfhdbPciDevice::get_device_from_busid(target_sysfs_id)
+        .map(|x| x.virtfns)
+        .unwrap_or_default();
+    eprintln!(
+        "[{}] {}",
+        t!("info").bright_green(),
+        t!("pci_vf_count_set", device = target_sysfs_id, count = count)
+    );
+    if is_json_mode() {
+        print_json_result(serde_json::json!({
+            "device": target_sysfs_id,
+            "count": count,
+            "virtfns": virtfns,
+        }));
+    }
+}
+
+fn download_pci_profile_database() -> Result<String, std::io::Error> {
+    let cached_db_path = &get_profile_cache_path("pci");
+    eprintln!(
         "[{}] {}",
         t!("info").bright_green(),
         t!("pci_download_starting")
@@ -403,24 +702,25 @@ fn get_pci_profiles_from_url() -> Result<Vec<CfhdbPciProfile>, std::io::Error> {
         .unwrap();
     let data = match client.get(PCI_PROFILE_JSON_URL.clone()).send() {
         Ok(t) => {
-            println!(
+            eprintln!(
                 "[{}] {}",
                 t!("info").bright_green(),
                 t!("pci_download_successful")
             );
             let cache = t.text().unwrap();
+            fix_cache_permissions();
             let _ = fs::File::create(cached_db_path);
             let _ = fs::write(cached_db_path, &cache);
             cache
         }
         Err(_) => {
-            println!(
+            eprintln!(
                 "[{}] {}",
                 t!("warn").bright_yellow(),
                 t!("pci_download_failed")
             );
             if cached_db_path.exists() {
-                println!(
+                eprintln!(
                     "[{}] {}",
                     t!("info").bright_green(),
                     t!("pci_download_cache_found")
@@ -439,140 +739,31 @@ fn get_pci_profiles_from_url() -> Result<Vec<CfhdbPciProfile>, std::io::Error> {
             }
         }
     };
-    let mut profiles_array = vec![];
-    let res: serde_json::Value = serde_json::from_str(&data).expect("Unable to parse");
-    if let serde_json::Value::Array(profiles) = &res["profiles"] {
-        for profile in profiles {
-            let codename = profile["codename"].as_str().unwrap_or_default().to_string();
-            let i18n_desc =
-                match profile[format!("i18n_desc[{}]", rust_i18n::locale().to_string())].as_str() {
-                    Some(t) => {
-                        if !t.is_empty() {
-                            t.to_string()
-                        } else {
-                            profile["i18n_desc"]
-                                .as_str()
-                                .unwrap_or_default()
-                                .to_string()
-                        }
-                    }
-                    None => profile["i18n_desc"]
-                        .as_str()
-                        .unwrap_or_default()
-                        .to_string(),
-                };
-            let icon_name = profile["icon_name"]
-                .as_str()
-                .unwrap_or("package-x-generic")
-                .to_string();
-            let license = profile["license"]
-                .as_str()
-                .unwrap_or(&t!("unknown"))
-                .to_string();
-            let class_ids: Vec<String> = match profile["class_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let vendor_ids: Vec<String> = match profile["vendor_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let device_ids: Vec<String> = match profile["device_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let blacklisted_class_ids: Vec<String> =
-                match profile["blacklisted_class_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let blacklisted_vendor_ids: Vec<String> =
-                match profile["blacklisted_vendor_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let blacklisted_device_ids: Vec<String> =
-                match profile["blacklisted_device_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let packages: Option<Vec<String>> = match profile["packages"].as_str() {
-                Some(_) => None,
-                None => Some(
-                    profile["packages"]
-                        .as_array()
-                        .expect("invalid_pci_profile_json")
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                ),
-            };
-            let check_script = profile["check_script"]
-                .as_str()
-                .unwrap_or("false")
-                .to_string();
-            let install_script_value = profile["install_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let install_script = match install_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(install_script_value),
-            };
-            let remove_script_value = profile["remove_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let remove_script = match remove_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(remove_script_value),
-            };
-            let experimental = profile["experimental"].as_bool().unwrap_or_default();
-            let removable = profile["removable"].as_bool().unwrap_or_default();
-            let veiled = profile["veiled"].as_bool().unwrap_or_default();
-            let priority = profile["priority"].as_i64().unwrap_or_default();
-            // Parse into the Struct
-            let profile_struct = CfhdbPciProfile {
-                codename,
-                i18n_desc,
-                icon_name,
-                license,
-                class_ids,
-                vendor_ids,
-                device_ids,
-                blacklisted_class_ids,
-                blacklisted_vendor_ids,
-                blacklisted_device_ids,
-                packages,
-                check_script,
-                install_script,
-                remove_script,
-                experimental,
-                removable,
-                veiled,
-                priority: priority as i32,
-            };
-            profiles_array.push(profile_struct);
-            profiles_array.sort_by_key(|x| x.priority);
-        }
+    Ok(data)
+}
+
+// Veiled profiles are left out of matching unless asked for
+pub fn get_visible_pci_profiles(show_veiled: bool) -> Vec<CfhdbPciProfile> {
+    let mut profiles = match get_pci_profiles_from_url() {
+        Ok(t) => t,
+        Err(e) => exit_with_error("io_error", e),
+    };
+    if !show_veiled {
+        profiles.retain(|x| !x.veiled);
     }
+    profiles
+}
+
+pub fn get_pci_profiles_from_url() -> Result<Vec<CfhdbPciProfile>, std::io::Error> {
+    let profile_values = match get_local_profile_source(&PCI_PROFILE_JSON_URL) {
+        Some(local_path) => load_profile_source(&local_path)?,
+        None => parse_profile_database(&download_pci_profile_database()?)?,
+    };
+    let locale = get_profile_locale();
+    let mut profiles_array: Vec<CfhdbPciProfile> = profile_values
+        .iter()
+        .map(|profile| CfhdbPciProfile::from_json(profile, &locale))
+        .collect();
+    profiles_array.sort_by_key(|x| x.priority);
     Ok(profiles_array)
 }
@@ -0,0 +1,79 @@
+use crate::output_func::*;
+use colored::Colorize;
+use libcfhdb::post_action::*;
+use users::get_current_username;
+
+const PENDING_ACTION_COLUMNS: [&str; 4] = ["action", "description", "bus", "codename"];
+
+pub fn get_post_action_description(action: &CfhdbPostAction) -> String {
+    match action {
+        CfhdbPostAction::ReloadModule(module) => {
+            t!("post_action_reload_module", module = module).to_string()
+        }
+        t => t!(format!("post_action_{}", t.get_kind())).to_string(),
+    }
+}
+
+// Transaction summary, printed after a profile was installed or removed.
+// Unprivileged transaction scripts record the actions themselves through the helper.
+pub fn record_post_actions(bus: &str, codename: &str, actions: &[CfhdbPostAction]) {
+    if actions.is_empty() {
+        return;
+    }
+    // The transaction itself went through, not remembering its post-actions is only worth a warning
+    if get_current_username().is_some_and(|x| x == "root") {
+        if let Err(e) = add_pending_actions(bus, codename, actions) {
+            eprintln!("[{}] {}", t!("warn").bright_yellow(), e);
+        }
+    }
+    eprintln!(
+        "[{}] {}",
+        t!("info").bright_green(),
+        t!("post_actions_required", codename = codename)
+    );
+    for action in actions {
+        eprintln!("  - {}", get_post_action_description(action));
+    }
+}
+
+pub fn display_pending_actions(json: bool, clear: bool) {
+    let mut actions = match load_pending_actions() {
+        Ok(t) => t,
+        Err(e) => exit_with_error("io_error", e),
+    };
+    if clear {
+        if let Err(e) = clear_pending_actions() {
+            exit_with_error("io_error", e);
+        }
+        eprintln!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("pending_actions_cleared", count = actions.len())
+        );
+        if json {
+            print_json_result(serde_json::json!({ "cleared": actions.len() }));
+        }
+        return;
+    }
+    // Most disruptive last, matching the order they should be done in
+    actions.sort_by(|a, b| (&a.action, &a.codename).cmp(&(&b.action, &b.codename)));
+    let records: Vec<_> = actions
+        .iter()
+        .map(|x| {
+            let mut record = serde_json::to_value(x).unwrap();
+            record["description"] = get_post_action_description(&x.action).into();
+            record
+        })
+        .collect();
+    if json && !is_record_output() {
+        print_json_result(records);
+    } else if records.is_empty() && !is_record_output() {
+        eprintln!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("no_pending_actions")
+        );
+    } else {
+        print_records(records, &PENDING_ACTION_COLUMNS);
+    }
+}
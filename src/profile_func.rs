@@ -0,0 +1,146 @@
+use crate::{output_func::*, pending_func::record_post_actions, run_in_lock_script};
+use colored::Colorize;
+use libcfhdb::{
+    post_action::{get_add_pending_actions_command, CfhdbPostAction},
+    profile::*,
+};
+use std::{fs, io, path::Path};
+
+// What installing or removing a profile takes, the same for every bus
+pub struct ProfileTransaction {
+    pub installed: bool,
+    pub experimental: bool,
+    pub removable: bool,
+    pub install_commands: Option<String>,
+    pub remove_commands: Option<String>,
+    pub post_actions: Vec<CfhdbPostAction>,
+}
+
+// Each bus looks its profile up by codename, None when no profile has it
+pub type GetProfileTransaction = fn(&str) -> Result<Option<ProfileTransaction>, io::Error>;
+
+// The error code and message when the experimental and removable gates refuse a transaction
+pub fn get_transaction_refusal(
+    codename: &str,
+    transaction: &ProfileTransaction,
+    install: bool,
+    allow: bool,
+) -> Option<(&'static str, String)> {
+    let changed = transaction.installed != install;
+    if install && changed && transaction.experimental && !allow {
+        Some((
+            "profile_experimental",
+            t!("profile_experimental", codename = codename).to_string(),
+        ))
+    } else if !install && changed && !transaction.removable && !allow {
+        Some((
+            "profile_not_removable",
+            t!("profile_not_removable", codename = codename).to_string(),
+        ))
+    } else {
+        None
+    }
+}
+
+fn run_profile_transaction(
+    bus: &str,
+    codename: &str,
+    install: bool,
+    allow: bool,
+    get_transaction: GetProfileTransaction,
+) {
+    let transaction = match get_transaction(codename) {
+        Ok(Some(t)) => t,
+        Ok(None) => exit_with_translated_error("no_matching_profile_codename"),
+        Err(e) => exit_with_error("io_error", e),
+    };
+    if let Some((code, message)) = get_transaction_refusal(codename, &transaction, install, allow) {
+        exit_with_error(code, message);
+    }
+    let changed = transaction.installed != install;
+    if !changed {
+        let message = if install {
+            t!("profile_already_installed")
+        } else {
+            t!("profile_not_installed")
+        };
+        eprintln!("[{}] {}", t!("info").bright_green(), message);
+    } else {
+        let commands = if install {
+            &transaction.install_commands
+        } else {
+            &transaction.remove_commands
+        };
+        if let Some(t) = commands {
+            run_in_lock_script(&format!(
+                "#! /bin/bash\nset -e\n{}\n{}",
+                t,
+                get_add_pending_actions_command(bus, codename, &transaction.post_actions)
+            ));
+        }
+        record_post_actions(bus, codename, &transaction.post_actions);
+    }
+    if is_json_mode() {
+        print_json_result(serde_json::json!({
+            "codename": codename,
+            "installed": install,
+            "changed": changed,
+            "post_actions": if changed { transaction.post_actions } else { vec![] },
+        }));
+    }
+}
+
+pub fn install_profile(
+    bus: &str,
+    codename: &str,
+    allow_experimental: bool,
+    get_transaction: GetProfileTransaction,
+) {
+    run_profile_transaction(bus, codename, true, allow_experimental, get_transaction)
+}
+
+pub fn uninstall_profile(
+    bus: &str,
+    codename: &str,
+    force: bool,
+    get_transaction: GetProfileTransaction,
+) {
+    run_profile_transaction(bus, codename, false, force, get_transaction)
+}
+
+pub fn compile_profiles(source: &str, output: Option<&String>) {
+    let profiles = match load_profile_source(Path::new(source)) {
+        Ok(t) => t,
+        Err(e) => exit_with_error("io_error", e),
+    };
+    let profile_count = profiles.len();
+    let database = compile_profile_database(profiles);
+    match output {
+        Some(output_path) => {
+            let json_pretty = serde_json::to_string_pretty(&database).unwrap();
+            match fs::write(output_path, json_pretty + "\n") {
+                Ok(_) => {
+                    eprintln!(
+                        "[{}] {}",
+                        t!("info").bright_green(),
+                        t!(
+                            "profiles_compiled",
+                            count = profile_count,
+                            path = output_path
+                        )
+                    );
+                    if is_json_mode() {
+                        print_json_result(serde_json::json!({
+                            "path": output_path,
+                            "count": profile_count,
+                        }));
+                    }
+                }
+                Err(e) => exit_with_error("io_error", e),
+            }
+        }
+        // With --json the database is wrapped like any other result, otherwise it is printed as is
+        None if is_json_mode() => print_json_result(database),
+        None => println!("{}", serde_json::to_string_pretty(&database).unwrap()),
+    }
+}
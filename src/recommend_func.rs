@@ -0,0 +1,256 @@
+use crate::{
+    config::*,
+    output_func::*,
+    pending_func::record_post_actions,
+    report_func::{get_matched_devices, MatchedDevices},
+    run_in_lock_script,
+};
+use colored::Colorize;
+use libcfhdb::{
+    bt::CfhdbBtProfile,
+    dmi::CfhdbDmiProfile,
+    pci::CfhdbPciProfile,
+    post_action::{get_add_pending_actions_command, CfhdbPostAction},
+    recommend::{
+        resolve_recommendations, CfhdbDeviceCandidates, CfhdbProfileCandidate, CfhdbRecommendation,
+        CfhdbRecommendationStatus,
+    },
+    usb::CfhdbUsbProfile,
+};
+use std::{collections::HashMap, sync::Arc};
+
+const RECOMMENDATION_COLUMNS: [&str; 5] = ["bus", "id", "name", "codename", "status"];
+
+#[derive(Default)]
+struct RecommendationInput {
+    devices: Vec<CfhdbDeviceCandidates>,
+    // Keyed by bus and codename, status checks run a script so each profile is only checked once
+    statuses: HashMap<(String, String), bool>,
+    install_commands: HashMap<(String, String), Option<String>>,
+    post_actions: HashMap<(String, String), Vec<CfhdbPostAction>>,
+}
+
+// The resolver's view of a profile, along with what installing it takes
+struct ProfileInstallInfo {
+    candidate: CfhdbProfileCandidate,
+    install_commands: Option<String>,
+    post_actions: Vec<CfhdbPostAction>,
+}
+
+impl RecommendationInput {
+    fn add_device<T>(
+        &mut self,
+        bus: &str,
+        id: &str,
+        name: String,
+        profiles: Option<Vec<Arc<T>>>,
+        get_profile: fn(&T) -> ProfileInstallInfo,
+        get_status: fn(&T) -> bool,
+    ) {
+        let profiles = match profiles {
+            Some(t) => t,
+            None => return,
+        };
+        let mut candidates = vec![];
+        for profile in profiles {
+            let ProfileInstallInfo {
+                mut candidate,
+                install_commands,
+                post_actions,
+            } = get_profile(&profile);
+            let key = (bus.to_string(), candidate.codename.clone());
+            candidate.installed = *self
+                .statuses
+                .entry(key.clone())
+                .or_insert_with(|| get_status(&profile));
+            self.install_commands
+                .entry(key.clone())
+                .or_insert(install_commands);
+            self.post_actions.entry(key).or_insert(post_actions);
+            candidates.push(candidate);
+        }
+        self.devices.push(CfhdbDeviceCandidates {
+            bus: bus.to_string(),
+            id: id.to_string(),
+            name,
+            profiles: candidates,
+        });
+    }
+}
+
+fn get_recommendation_input() -> RecommendationInput {
+    let MatchedDevices {
+        dmi_info,
+        pci_devices,
+        usb_devices,
+        bt_devices,
+    } = get_matched_devices();
+    let mut input = RecommendationInput::default();
+    input.add_device(
+        "dmi",
+        &dmi_info.board_name,
+        format!("{} {}", dmi_info.sys_vendor, dmi_info.product_name),
+        dmi_info.available_profiles.0.lock().unwrap().clone(),
+        |x: &CfhdbDmiProfile| ProfileInstallInfo {
+            candidate: CfhdbProfileCandidate {
+                codename: x.codename.clone(),
+                priority: x.priority,
+                experimental: x.experimental,
+                veiled: x.veiled,
+                installed: false,
+                conflicts: x.conflicts.clone(),
+            },
+            install_commands: get_install_commands(&x.packages, &x.install_script),
+            post_actions: x.post_actions.clone(),
+        },
+        CfhdbDmiProfile::get_status,
+    );
+    for i in &pci_devices {
+        input.add_device(
+            "pci",
+            &i.sysfs_busid,
+            format!("{} {}", i.vendor_name, i.device_name),
+            i.available_profiles.0.lock().unwrap().clone(),
+            |x: &CfhdbPciProfile| ProfileInstallInfo {
+                candidate: CfhdbProfileCandidate {
+                    codename: x.codename.clone(),
+                    priority: x.priority,
+                    experimental: x.experimental,
+                    veiled: x.veiled,
+                    installed: false,
+                    conflicts: x.conflicts.clone(),
+                },
+                install_commands: get_install_commands(&x.packages, &x.install_script),
+                post_actions: x.post_actions.clone(),
+            },
+            CfhdbPciProfile::get_status,
+        );
+    }
+    for i in &usb_devices {
+        input.add_device(
+            "usb",
+            &i.sysfs_busid,
+            format!("{} {}", i.manufacturer_string_index, i.product_string_index),
+            i.available_profiles.0.lock().unwrap().clone(),
+            |x: &CfhdbUsbProfile| ProfileInstallInfo {
+                candidate: CfhdbProfileCandidate {
+                    codename: x.codename.clone(),
+                    priority: x.priority,
+                    experimental: x.experimental,
+                    veiled: x.veiled,
+                    installed: false,
+                    conflicts: x.conflicts.clone(),
+                },
+                install_commands: get_install_commands(&x.packages, &x.install_script),
+                post_actions: x.post_actions.clone(),
+            },
+            CfhdbUsbProfile::get_status,
+        );
+    }
+    for i in &bt_devices {
+        input.add_device(
+            "bt",
+            &i.address,
+            i.alias.clone(),
+            i.available_profiles.0.lock().unwrap().clone(),
+            |x: &CfhdbBtProfile| ProfileInstallInfo {
+                candidate: CfhdbProfileCandidate {
+                    codename: x.codename.clone(),
+                    priority: x.priority,
+                    experimental: x.experimental,
+                    veiled: x.veiled,
+                    installed: false,
+                    conflicts: x.conflicts.clone(),
+                },
+                install_commands: get_install_commands(&x.packages, &x.install_script),
+                post_actions: x.post_actions.clone(),
+            },
+            CfhdbBtProfile::get_status,
+        );
+    }
+    input
+}
+
+fn print_recommendations(json: bool, recommendations: &[CfhdbRecommendation]) {
+    let records = recommendations
+        .iter()
+        .map(|x| serde_json::to_value(x).unwrap())
+        .collect();
+    if json && !is_record_output() {
+        print_json_result(recommendations);
+    } else {
+        print_records(records, &RECOMMENDATION_COLUMNS);
+    }
+}
+
+pub fn display_recommendations(json: bool) {
+    let input = get_recommendation_input();
+    let recommendations = resolve_recommendations(&input.devices);
+    if recommendations.is_empty() {
+        exit_with_translated_error("no_profiles_available_for_system");
+    }
+    print_recommendations(json, &recommendations);
+}
+
+// Everything is installed from one script, so there is a single authentication prompt
+pub fn auto_install_profiles(json: bool) {
+    let input = get_recommendation_input();
+    let recommendations = resolve_recommendations(&input.devices);
+    let mut installed_profiles: Vec<(String, String)> = vec![];
+    let mut commands = vec![];
+    for recommendation in &recommendations {
+        let codename = match (&recommendation.codename, recommendation.status) {
+            (Some(t), CfhdbRecommendationStatus::Install) => t,
+            _ => continue,
+        };
+        let key = (recommendation.bus.clone(), codename.clone());
+        if installed_profiles.contains(&key) {
+            continue;
+        }
+        eprintln!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("auto_installing_profile", codename = codename)
+        );
+        if let Some(Some(t)) = input.install_commands.get(&key) {
+            commands.push(t.clone());
+            if let Some(post_actions) = input.post_actions.get(&key) {
+                commands.push(get_add_pending_actions_command(
+                    &key.0,
+                    codename,
+                    post_actions,
+                ));
+            }
+        }
+        installed_profiles.push(key);
+    }
+    if installed_profiles.is_empty() {
+        eprintln!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("no_recommended_profiles_to_install")
+        );
+    } else if !commands.is_empty() {
+        run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", commands.join("\n")));
+    }
+    for (bus, codename) in &installed_profiles {
+        if let Some(post_actions) = input.post_actions.get(&(bus.clone(), codename.clone())) {
+            record_post_actions(bus, codename, post_actions);
+        }
+    }
+    if json && !is_record_output() {
+        print_json_result(serde_json::json!({
+            "installed": installed_profiles
+                .iter()
+                .map(|(bus, codename)| serde_json::json!({
+                    "bus": bus,
+                    "codename": codename,
+                    "post_actions": input.post_actions.get(&(bus.clone(), codename.clone())),
+                }))
+                .collect::<Vec<_>>(),
+            "recommendations": recommendations,
+        }));
+    } else if is_record_output() {
+        print_recommendations(json, &recommendations);
+    }
+}
@@ -0,0 +1,423 @@
+use crate::{
+    bt_func::get_bt_profiles_from_url, dmi_func::get_dmi_profiles_from_url,
+    locale_func::get_profile_locale, output_func::*, pci_func::get_pci_profiles_from_url,
+    usb_func::get_usb_profiles_from_url,
+};
+use colored::Colorize;
+use libcfhdb::{
+    bt::*,
+    dmi::*,
+    pci::*,
+    profile::load_profile_source,
+    report::{diff_reports, CfhdbFieldChange, CfhdbInstalledProfiles, CfhdbReport},
+    usb::*,
+};
+use serde_json::Value;
+use std::{collections::BTreeMap, io, path::Path, sync::Arc};
+
+const REPORT_DIFF_COLUMNS: [&str; 5] = ["status", "bus", "id", "name", "changes"];
+
+const REPORT_DEVICE_COLUMNS: [&str; 6] = [
+    "bus",
+    "id",
+    "name",
+    "kernel_driver",
+    "profiles",
+    "installed",
+];
+
+fn get_codenames<T>(profiles: Option<Vec<Arc<T>>>, codename: fn(&T) -> String) -> Vec<String> {
+    profiles
+        .unwrap_or_default()
+        .iter()
+        .map(|x| codename(x))
+        .collect()
+}
+
+// Devices often share a profile, each one is only checked once
+fn get_installed_codenames<T>(
+    matched: Vec<Option<Vec<Arc<T>>>>,
+    codename: fn(&T) -> String,
+    get_status: fn(&T) -> bool,
+) -> Vec<String> {
+    let mut profiles = BTreeMap::new();
+    for profile in matched.into_iter().flatten().flatten() {
+        profiles.insert(codename(&profile), profile);
+    }
+    profiles
+        .into_iter()
+        .filter(|(_, profile)| get_status(profile))
+        .map(|(codename, _)| codename)
+        .collect()
+}
+
+// A "bus=source" override wins over the configured database, so new profiles can be tried on a report
+fn get_profiles<T>(
+    bus: &str,
+    profile_sources: &[(String, String)],
+    from_url: fn() -> Result<Vec<T>, io::Error>,
+    from_json: fn(&Value, &str) -> T,
+) -> Vec<T> {
+    let profiles = match profile_sources.iter().find(|(x, _)| x == bus) {
+        Some((_, source)) => load_profile_source(Path::new(source)).map(|values| {
+            let locale = get_profile_locale();
+            values.iter().map(|x| from_json(x, &locale)).collect()
+        }),
+        None => from_url(),
+    };
+    match profiles {
+        Ok(t) => t,
+        Err(e) => exit_with_error("io_error", e),
+    }
+}
+
+fn read_report_value(path: &str) -> Value {
+    match CfhdbReport::read_value(Path::new(path)) {
+        Ok(t) => t,
+        Err(e) => exit_with_error(
+            "invalid_report",
+            t!("invalid_report", path = path, error = e),
+        ),
+    }
+}
+
+// "kernel_driver: nouveau -> nvidia", profile lists as "available_profiles: +appeared -gone"
+fn format_field_change(change: &CfhdbFieldChange) -> String {
+    if change.before.is_array() || change.after.is_array() {
+        let before = change.before.as_array().cloned().unwrap_or_default();
+        let after = change.after.as_array().cloned().unwrap_or_default();
+        let mut items: Vec<String> = after
+            .iter()
+            .filter(|x| !before.contains(x))
+            .map(|x| format!("+{}", get_cell_string(x)))
+            .collect();
+        items.extend(
+            before
+                .iter()
+                .filter(|x| !after.contains(x))
+                .map(|x| format!("-{}", get_cell_string(x))),
+        );
+        return format!("{}: {}", change.field, items.join(" "));
+    }
+    let format_value = |value: &Value| match value {
+        Value::Null => "-".to_string(),
+        t => get_cell_string(t),
+    };
+    format!(
+        "{}: {} -> {}",
+        change.field,
+        format_value(&change.before),
+        format_value(&change.after)
+    )
+}
+
+fn report_device_record(
+    bus: &str,
+    id: &str,
+    name: String,
+    kernel_driver: Option<&str>,
+    profiles: Vec<String>,
+    installed_profiles: &[String],
+) -> Value {
+    let installed: Vec<&String> = profiles
+        .iter()
+        .filter(|x| installed_profiles.contains(x))
+        .collect();
+    serde_json::json!({
+        "bus": bus,
+        "id": id,
+        "name": name,
+        "kernel_driver": kernel_driver,
+        "profiles": profiles,
+        "installed": installed,
+    })
+}
+
+pub struct MatchedDevices {
+    pub dmi_info: CfhdbDmiInfo,
+    pub pci_devices: Vec<CfhdbPciDevice>,
+    pub usb_devices: Vec<CfhdbUsbDevice>,
+    pub bt_devices: Vec<CfhdbBtDevice>,
+}
+
+// Scans every bus and matches the configured profiles against the devices
+pub fn get_matched_devices() -> MatchedDevices {
+    let pci_profiles = match get_pci_profiles_from_url() {
+        Ok(t) => t,
+        Err(e) => exit_with_error("io_error", e),
+    };
+    let usb_profiles = match get_usb_profiles_from_url() {
+        Ok(t) => t,
+        Err(e) => exit_with_error("io_error", e),
+    };
+    let dmi_profiles = match get_dmi_profiles_from_url() {
+        Ok(t) => t,
+        Err(e) => exit_with_error("io_error", e),
+    };
+    let bt_profiles = match get_bt_profiles_from_url() {
+        Ok(t) => t,
+        Err(e) => exit_with_error("io_error", e),
+    };
+    let pci_devices = match CfhdbPciDevice::get_devices() {
+        Some(t) => t,
+        None => exit_with_translated_error("failed_to_get_pci_devices"),
+    };
+    for i in &pci_devices {
+        CfhdbPciDevice::set_available_profiles(&pci_profiles, i);
+    }
+    let usb_devices = match CfhdbUsbDevice::get_devices() {
+        Some(t) => t,
+        None => exit_with_translated_error("failed_to_get_usb_devices"),
+    };
+    for i in &usb_devices {
+        CfhdbUsbDevice::set_available_profiles(&usb_profiles, i);
+    }
+    let dmi_info = CfhdbDmiInfo::get_dmi();
+    CfhdbDmiInfo::set_available_profiles(&dmi_profiles, &dmi_info);
+    // Machines without a bluetooth adapter or bluetoothd are still scanned
+    let bt_devices = match CfhdbBtDevice::get_devices() {
+        Some(t) => t,
+        None => {
+            eprintln!(
+                "[{}] {}",
+                t!("warn").bright_yellow(),
+                t!("failed_to_get_bt_devices")
+            );
+            vec![]
+        }
+    };
+    for i in &bt_devices {
+        CfhdbBtDevice::set_available_profiles(&bt_profiles, i);
+    }
+    MatchedDevices {
+        dmi_info,
+        pci_devices,
+        usb_devices,
+        bt_devices,
+    }
+}
+
+pub fn export_report(path: &str) {
+    let MatchedDevices {
+        dmi_info,
+        pci_devices,
+        usb_devices,
+        bt_devices,
+    } = get_matched_devices();
+    let installed_profiles = CfhdbInstalledProfiles {
+        pci: get_installed_codenames(
+            pci_devices
+                .iter()
+                .map(|x| x.available_profiles.0.lock().unwrap().clone())
+                .collect(),
+            |x| x.codename.clone(),
+            CfhdbPciProfile::get_status,
+        ),
+        usb: get_installed_codenames(
+            usb_devices
+                .iter()
+                .map(|x| x.available_profiles.0.lock().unwrap().clone())
+                .collect(),
+            |x| x.codename.clone(),
+            CfhdbUsbProfile::get_status,
+        ),
+        dmi: get_installed_codenames(
+            vec![dmi_info.available_profiles.0.lock().unwrap().clone()],
+            |x| x.codename.clone(),
+            CfhdbDmiProfile::get_status,
+        ),
+        bt: get_installed_codenames(
+            bt_devices
+                .iter()
+                .map(|x| x.available_profiles.0.lock().unwrap().clone())
+                .collect(),
+            |x| x.codename.clone(),
+            CfhdbBtProfile::get_status,
+        ),
+    };
+    let report = CfhdbReport::new(
+        dmi_info,
+        pci_devices,
+        usb_devices,
+        bt_devices,
+        installed_profiles,
+    );
+    match report.write_to_file(Path::new(path)) {
+        Ok(_) => {
+            eprintln!(
+                "[{}] {}",
+                t!("info").bright_green(),
+                t!("report_exported", path = path)
+            );
+            if is_json_mode() {
+                print_json_result(serde_json::json!({
+                    "path": path,
+                    "pci_devices": report.pci_devices.len(),
+                    "usb_devices": report.usb_devices.len(),
+                    "bt_devices": report.bt_devices.len(),
+                }));
+            }
+        }
+        Err(e) => exit_with_error("io_error", e),
+    }
+}
+
+// Matches the current (or overridden) profile databases against the devices of a report,
+// nothing on this machine is scanned
+pub fn import_report(json: bool, path: &str, profile_sources: &[(String, String)]) {
+    let report = match CfhdbReport::from_file(Path::new(path)) {
+        Ok(t) => t,
+        Err(e) => exit_with_error(
+            "invalid_report",
+            t!("invalid_report", path = path, error = e),
+        ),
+    };
+    let pci_profiles = get_profiles(
+        "pci",
+        profile_sources,
+        get_pci_profiles_from_url,
+        CfhdbPciProfile::from_json,
+    );
+    let usb_profiles = get_profiles(
+        "usb",
+        profile_sources,
+        get_usb_profiles_from_url,
+        CfhdbUsbProfile::from_json,
+    );
+    let dmi_profiles = get_profiles(
+        "dmi",
+        profile_sources,
+        get_dmi_profiles_from_url,
+        CfhdbDmiProfile::from_json,
+    );
+    let bt_profiles = get_profiles(
+        "bt",
+        profile_sources,
+        get_bt_profiles_from_url,
+        CfhdbBtProfile::from_json,
+    );
+    let installed = &report.installed_profiles;
+    let mut records = vec![];
+    CfhdbDmiInfo::set_available_profiles(&dmi_profiles, &report.dmi_info);
+    records.push(report_device_record(
+        "dmi",
+        &report.dmi_info.board_name,
+        format!(
+            "{} {}",
+            report.dmi_info.sys_vendor, report.dmi_info.product_name
+        ),
+        None,
+        get_codenames(
+            report.dmi_info.available_profiles.0.lock().unwrap().clone(),
+            |x| x.codename.clone(),
+        ),
+        &installed.dmi,
+    ));
+    for i in &report.pci_devices {
+        CfhdbPciDevice::set_available_profiles(&pci_profiles, i);
+        records.push(report_device_record(
+            "pci",
+            &i.sysfs_busid,
+            format!("{} {}", i.vendor_name, i.device_name),
+            Some(&i.kernel_driver),
+            get_codenames(i.available_profiles.0.lock().unwrap().clone(), |x| {
+                x.codename.clone()
+            }),
+            &installed.pci,
+        ));
+    }
+    for i in &report.usb_devices {
+        CfhdbUsbDevice::set_available_profiles(&usb_profiles, i);
+        records.push(report_device_record(
+            "usb",
+            &i.sysfs_busid,
+            format!("{} {}", i.manufacturer_string_index, i.product_string_index),
+            Some(&i.kernel_driver),
+            get_codenames(i.available_profiles.0.lock().unwrap().clone(), |x| {
+                x.codename.clone()
+            }),
+            &installed.usb,
+        ));
+    }
+    for i in &report.bt_devices {
+        CfhdbBtDevice::set_available_profiles(&bt_profiles, i);
+        records.push(report_device_record(
+            "bt",
+            &i.address,
+            i.alias.clone(),
+            None,
+            get_codenames(i.available_profiles.0.lock().unwrap().clone(), |x| {
+                x.codename.clone()
+            }),
+            &installed.bt,
+        ));
+    }
+    if is_record_output() {
+        print_records(records, &REPORT_DEVICE_COLUMNS);
+    } else if json {
+        print_json_result(serde_json::json!({
+            "report_version": report.report_version,
+            "cfhdb_version": report.cfhdb_version,
+            "created": report.created,
+            "kernel_version": report.kernel_version,
+            "devices": records,
+        }));
+    } else {
+        println!(
+            "{} {}",
+            format!(
+                "{} {}",
+                report.dmi_info.sys_vendor, report.dmi_info.product_name
+            )
+            .bright_green(),
+            t!("report_kernel_version", version = report.kernel_version)
+        );
+        print_records(records, &REPORT_DEVICE_COLUMNS);
+    }
+}
+
+pub fn diff_report_files(json: bool, before_path: &str, after_path: &str) {
+    let diff = diff_reports(
+        &read_report_value(before_path),
+        &read_report_value(after_path),
+    );
+    if json && !is_record_output() {
+        print_json_result(diff);
+        return;
+    }
+    if diff.devices.is_empty() && diff.kernel_version.is_none() {
+        eprintln!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("reports_identical")
+        );
+        return;
+    }
+    let records = diff
+        .devices
+        .iter()
+        .map(|x| {
+            serde_json::json!({
+                "status": x.status,
+                "bus": x.bus,
+                "id": x.id,
+                "name": x.name,
+                "changes": x
+                    .changes
+                    .iter()
+                    .map(format_field_change)
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            })
+        })
+        .collect::<Vec<_>>();
+    if !is_record_output() {
+        if let Some(change) = &diff.kernel_version {
+            println!("{}", format_field_change(change).bright_green());
+        }
+        if records.is_empty() {
+            return;
+        }
+    }
+    print_records(records, &REPORT_DIFF_COLUMNS);
+}
@@ -0,0 +1,27 @@
+use crate::output_func::*;
+use colored::Colorize;
+use libcfhdb::sysfs::get_stopped_devices;
+
+const STOPPED_DEVICE_COLUMNS: [&str; 3] = ["bus", "busid", "driver"];
+
+pub fn display_stopped_devices(json: bool) {
+    let devices = match get_stopped_devices() {
+        Ok(t) => t,
+        Err(e) => exit_with_error("io_error", e),
+    };
+    let records: Vec<_> = devices
+        .iter()
+        .map(|x| serde_json::to_value(x).unwrap())
+        .collect();
+    if json && !is_record_output() {
+        print_json_result(records);
+    } else if records.is_empty() && !is_record_output() {
+        eprintln!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("no_stopped_devices")
+        );
+    } else {
+        print_records(records, &STOPPED_DEVICE_COLUMNS);
+    }
+}
@@ -1,17 +1,49 @@
-use crate::{config::*, get_profile_url_config, run_in_lock_script};
+use crate::{
+    config::*, fix_cache_permissions, get_profile_cache_path, get_profile_url_config,
+    locale_func::get_profile_locale, output_func::*, profile_func::ProfileTransaction,
+};
 use cli_table::{Cell, Color, Style, Table};
 use colored::Colorize;
 use lazy_static::lazy_static;
-use libcfhdb::usb::*;
-use std::{collections::HashMap, fs, ops::Deref, path::Path, process::exit};
+use libcfhdb::{
+    usb::*,
+    profile::{get_local_profile_source, load_profile_source, parse_profile_database},
+    query::{filter_items, QueryExpr},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    ops::Deref,
+};
 
 lazy_static! {
     static ref USB_PROFILE_JSON_URL: String = get_profile_url_config().usb_json_url;
 }
 
+const USB_DEVICE_COLUMNS: [&str; 8] = [
+    "manufacturer_string_index",
+    "product_string_index",
+    "sysfs_busid",
+    "speed",
+    "kernel_driver",
+    "started",
+    "enabled",
+    "candidate_modules",
+];
+
 fn display_usb_devices_print_json(hashmap: HashMap<String, Vec<CfhdbUsbDevice>>) {
-    let json_pretty = serde_json::to_string_pretty(&hashmap).unwrap();
-    println!("{}", json_pretty);
+    // Sorted by class so the output is stable between runs
+    print_json_result(hashmap.into_iter().collect::<BTreeMap<_, _>>());
+}
+fn display_usb_devices_print_records(mut devices: Vec<CfhdbUsbDevice>) {
+    devices.sort_by(|a, b| (&a.class_code, &a.sysfs_busid).cmp(&(&b.class_code, &b.sysfs_busid)));
+    print_records(
+        devices
+            .iter()
+            .map(|x| serde_json::to_value(x).unwrap())
+            .collect(),
+        &USB_DEVICE_COLUMNS,
+    );
 }
 fn display_usb_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbUsbDevice>>) {
     for (class, devices) in hashmap {
@@ -37,6 +69,17 @@ fn display_usb_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbUsbDevi
                         .foreground_color(Some(Color::Yellow)),
                     _ => device.kernel_driver.cell(),
                 },
+                match device.candidate_modules.is_empty() {
+                    true => t!("enabled_na").cell(),
+                    false => {
+                        let candidates = device.candidate_modules.join(", ");
+                        match candidates.char_indices().nth(24) {
+                            None => candidates,
+                            Some((idx, _)) => candidates[..idx].to_string() + "...",
+                        }
+                        .cell()
+                    }
+                },
                 match device.started {
                     Some(t) => {
                         if t {
@@ -67,6 +110,7 @@ fn display_usb_devices_print_cli_table(hashmap: HashMap<String, Vec<CfhdbUsbDevi
                 t!("usb_table_sysfs_bus_id").cell().bold(true),
                 t!("usb_table_speed").cell().bold(true),
                 t!("usb_table_driver").cell().bold(true),
+                t!("usb_table_candidates").cell().bold(true),
                 t!("usb_table_started").cell().bold(true),
                 t!("usb_table_enabled").cell().bold(true),
             ])
@@ -86,14 +130,7 @@ fn display_usb_profiles_print_cli_table(target: &CfhdbUsbDevice) {
     let mut table_struct = vec![];
     let mut profiles = match target.available_profiles.0.lock().unwrap().clone() {
         Some(t) => t,
-        None => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_profiles_available_for_device")
-            );
-            exit(1);
-        }
+        None => exit_with_translated_error("no_profiles_available_for_device"),
     };
     profiles.sort_by_key(|k| k.priority);
     for profile in profiles {
@@ -106,7 +143,10 @@ fn display_usb_profiles_print_cli_table(target: &CfhdbUsbDevice) {
                 Some((idx, _)) => profile.i18n_desc[..idx].to_string() + "...",
             }
             .cell(),
-            profile.license.cell(),
+            profile
+                .license
+                .unwrap_or_else(|| t!("unknown").to_string())
+                .cell(),
             profile.priority.cell(),
             if profile.experimental {
                 t!("enabled_yes").cell().foreground_color(Some(Color::Red))
@@ -140,186 +180,77 @@ fn display_usb_profiles_print_cli_table(target: &CfhdbUsbDevice) {
     println!("{}\n{}", target.sysfs_busid.bright_green(), table_display);
 }
 
-pub fn display_usb_devices(json: bool) {
+pub fn display_usb_devices(json: bool, query: Option<&QueryExpr>, show_veiled: bool) {
     match CfhdbUsbDevice::get_devices() {
         Some(devices) => {
-            let profiles = match get_usb_profiles_from_url() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
+            let profiles = get_visible_usb_profiles(show_veiled);
             for i in &devices {
                 CfhdbUsbDevice::set_available_profiles(&profiles, &i);
             }
-            let hashmap = CfhdbUsbDevice::create_class_hashmap(devices);
-            if json {
-                display_usb_devices_print_json(hashmap)
+            let devices = match query {
+                Some(query) => match filter_items(devices, query) {
+                    Ok(t) => t,
+                    Err(e) => exit_with_error("invalid_query", e),
+                },
+                None => devices,
+            };
+            if is_record_output() {
+                display_usb_devices_print_records(devices)
+            } else if json {
+                display_usb_devices_print_json(CfhdbUsbDevice::create_class_hashmap(devices))
             } else {
-                display_usb_devices_print_cli_table(hashmap)
+                display_usb_devices_print_cli_table(CfhdbUsbDevice::create_class_hashmap(devices))
             }
         }
-        None => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("failed_to_get_usb_devices")
-            );
-            exit(1);
-        }
+        None => exit_with_translated_error("failed_to_get_usb_devices"),
     }
 }
 
-pub fn display_usb_profiles(json: bool, target: &str) {
+pub fn display_usb_profiles(json: bool, target: &str, show_veiled: bool) {
     match CfhdbUsbDevice::get_device_from_busid(target) {
         Ok(target_device) => {
-            let profiles = match get_usb_profiles_from_url() {
-                Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
-            };
+            let profiles = get_visible_usb_profiles(show_veiled);
             CfhdbUsbDevice::set_available_profiles(&profiles, &target_device);
-            if json {
+            if json || is_record_output() {
                 let mut profile_arc =
                     match target_device.available_profiles.0.lock().unwrap().clone() {
                         Some(t) => t,
-                        None => {
-                            eprintln!(
-                                "[{}] {}",
-                                t!("error").red(),
-                                t!("no_profiles_available_for_device")
-                            );
-                            exit(1);
-                        }
+                        None => exit_with_translated_error("no_profiles_available_for_device"),
                     };
                 profile_arc.sort_by_key(|k| k.priority);
                 let profiles = profile_arc
                     .iter()
-                    .map(|s| s.codename.clone())
+                    .map(|s| profile_to_json(s.deref(), s.get_status()))
                     .collect::<Vec<_>>();
-                let json_pretty = serde_json::to_string_pretty(&profiles).unwrap();
-                println!("{}", json_pretty);
+                if is_record_output() {
+                    print_records(profiles, &PROFILE_COLUMNS);
+                } else {
+                    print_json_result(profiles);
+                }
             } else {
                 display_usb_profiles_print_cli_table(&target_device);
             }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_usb_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_usb_device"),
     }
 }
 
-pub fn install_usb_profile(profile_codename: &str) {
-    let profiles = match get_usb_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    match CfhdbUsbProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if target_profile.get_status() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_already_installed")
-                );
-            } else {
-                match target_profile.install_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_installer(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_installer(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
-            }
-        }
-        Err(_) => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
-            );
-            exit(1);
-        }
-    }
-}
-pub fn uninstall_usb_profile(profile_codename: &str) {
-    let profiles = match get_usb_profiles_from_url() {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("[{}] {}", t!("error").red(), e);
-            exit(1);
-        }
-    };
-    match CfhdbUsbProfile::get_profile_from_codename(profile_codename, profiles) {
-        Ok(target_profile) => {
-            if !target_profile.get_status() {
-                println!(
-                    "[{}] {}",
-                    t!("info").bright_green(),
-                    t!("profile_not_installed")
-                );
-            } else {
-                match target_profile.remove_script {
-                    Some(t) => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}\n{}",
-                                distro_packages_uninstaller(&package_list),
-                                t
-                            ));
-                        }
-                        None => {
-                            run_in_lock_script(&format!("#! /bin/bash\nset -e\n{}", t));
-                        }
-                    },
-                    None => match target_profile.packages {
-                        Some(a) => {
-                            let package_list = a.join(" ");
-                            run_in_lock_script(&format!(
-                                "#! /bin/bash\nset -e\n{}",
-                                distro_packages_uninstaller(&package_list)
-                            ));
-                        }
-                        None => {}
-                    },
-                }
-            }
-        }
-        Err(_) => {
-            eprintln!(
-                "[{}] {}",
-                t!("error").red(),
-                t!("no_matching_profile_codename")
-            );
-            exit(1);
-        }
-    }
+pub fn get_usb_profile_transaction(
+    profile_codename: &str,
+) -> Result<Option<ProfileTransaction>, std::io::Error> {
+    let profiles = get_usb_profiles_from_url()?;
+    Ok(
+        CfhdbUsbProfile::get_profile_from_codename(profile_codename, profiles)
+            .ok()
+            .map(|x| ProfileTransaction {
+                installed: x.get_status(),
+                experimental: x.experimental,
+                removable: x.removable,
+                install_commands: get_install_commands(&x.packages, &x.install_script),
+                remove_commands: get_remove_commands(&x.packages, &x.remove_script),
+                post_actions: x.post_actions,
+            }),
+    )
 }
 
 pub fn enable_usb_device(target_sysfs_id: &str) {
@@ -327,16 +258,13 @@ pub fn enable_usb_device(target_sysfs_id: &str) {
         Ok(target_device) => {
             match target_device.enable_device() {
                 Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
+                Err(e) => exit_with_error(e.get_code(), e),
             };
+            if is_json_mode() {
+                print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+            }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_usb_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_usb_device"),
     }
 }
 pub fn disable_usb_device(target_sysfs_id: &str) {
@@ -344,16 +272,13 @@ pub fn disable_usb_device(target_sysfs_id: &str) {
         Ok(target_device) => {
             match target_device.disable_device() {
                 Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
+                Err(e) => exit_with_error(e.get_code(), e),
             };
+            if is_json_mode() {
+                print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+            }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_usb_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_usb_device"),
     }
 }
 
@@ -362,16 +287,13 @@ pub fn start_usb_device(target_sysfs_id: &str) {
         Ok(target_device) => {
             match target_device.start_device() {
                 Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
+                Err(e) => exit_with_error(e.get_code(), e),
             };
+            if is_json_mode() {
+                print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+            }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_usb_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_usb_device"),
     }
 }
 pub fn stop_usb_device(target_sysfs_id: &str) {
@@ -379,22 +301,50 @@ pub fn stop_usb_device(target_sysfs_id: &str) {
         Ok(target_device) => {
             match target_device.stop_device() {
                 Ok(t) => t,
-                Err(e) => {
-                    eprintln!("[{}] {}", t!("error").red(), e);
-                    exit(1);
-                }
+                Err(e) => exit_with_error(e.get_code(), e),
             };
+            if is_json_mode() {
+                print_json_result(serde_json::json!({ "device": target_sysfs_id }));
+            }
         }
-        Err(_) => {
-            eprintln!("[{}] {}", t!("error").red(), t!("no_matching_usb_device"));
-            exit(1);
-        }
+        Err(_) => exit_with_translated_error("no_matching_usb_device"),
     }
 }
 
-fn get_usb_profiles_from_url() -> Result<Vec<CfhdbUsbProfile>, std::io::Error> {
-    let cached_db_path = Path::new("/var/cache/cfhdb/usb.json");
-    println!(
+const USB_DRIVER_COLUMNS: [&str; 6] = [
+    "name",
+    "version",
+    "license",
+    "firmware",
+    "bound",
+    "blacklisted",
+];
+
+pub fn display_usb_drivers(json: bool, target_sysfs_id: &str) {
+    let target_device = match CfhdbUsbDevice::get_device_from_busid(target_sysfs_id) {
+        Ok(t) => t,
+        Err(_) => exit_with_translated_error("no_matching_usb_device"),
+    };
+    let records: Vec<_> = match target_device.get_candidate_modules() {
+        Ok(t) => t.iter().map(|x| serde_json::to_value(x).unwrap()).collect(),
+        Err(e) => exit_with_error("io_error", e),
+    };
+    if json && !is_record_output() {
+        print_json_result(records);
+    } else if records.is_empty() && !is_record_output() {
+        eprintln!(
+            "[{}] {}",
+            t!("info").bright_green(),
+            t!("no_candidate_drivers")
+        );
+    } else {
+        print_records(records, &USB_DRIVER_COLUMNS);
+    }
+}
+
+fn download_usb_profile_database() -> Result<String, std::io::Error> {
+    let cached_db_path = &get_profile_cache_path("usb");
+    eprintln!(
         "[{}] {}",
         t!("info").bright_green(),
         t!("usb_download_starting")
@@ -405,24 +355,25 @@ fn get_usb_profiles_from_url() -> Result<Vec<CfhdbUsbProfile>, std::io::Error> {
         .unwrap();
     let data = match client.get(USB_PROFILE_JSON_URL.clone()).send() {
         Ok(t) => {
-            println!(
+            eprintln!(
                 "[{}] {}",
                 t!("info").bright_green(),
                 t!("usb_download_successful")
             );
             let cache = t.text().unwrap();
+            fix_cache_permissions();
             let _ = fs::File::create(cached_db_path);
             let _ = fs::write(cached_db_path, &cache);
             cache
         }
         Err(_) => {
-            println!(
+            eprintln!(
                 "[{}] {}",
                 t!("warn").bright_yellow(),
                 t!("usb_download_failed")
             );
             if cached_db_path.exists() {
-                println!(
+                eprintln!(
                     "[{}] {}",
                     t!("info").bright_green(),
                     t!("usb_download_cache_found")
@@ -441,140 +392,31 @@ fn get_usb_profiles_from_url() -> Result<Vec<CfhdbUsbProfile>, std::io::Error> {
             }
         }
     };
-    let mut profiles_array = vec![];
-    let res: serde_json::Value = serde_json::from_str(&data).expect("Unable to parse");
-    if let serde_json::Value::Array(profiles) = &res["profiles"] {
-        for profile in profiles {
-            let codename = profile["codename"].as_str().unwrap_or_default().to_string();
-            let i18n_desc =
-                match profile[format!("i18n_desc[{}]", rust_i18n::locale().to_string())].as_str() {
-                    Some(t) => {
-                        if !t.is_empty() {
-                            t.to_string()
-                        } else {
-                            profile["i18n_desc"]
-                                .as_str()
-                                .unwrap_or_default()
-                                .to_string()
-                        }
-                    }
-                    None => profile["i18n_desc"]
-                        .as_str()
-                        .unwrap_or_default()
-                        .to_string(),
-                };
-            let icon_name = profile["icon_name"]
-                .as_str()
-                .unwrap_or("package-x-generic")
-                .to_string();
-            let license = profile["license"]
-                .as_str()
-                .unwrap_or(&t!("unknown"))
-                .to_string();
-            let class_codes: Vec<String> = match profile["class_codes"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let vendor_ids: Vec<String> = match profile["vendor_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let product_ids: Vec<String> = match profile["product_ids"].as_array() {
-                Some(t) => t
-                    .into_iter()
-                    .map(|x| x.as_str().unwrap_or_default().to_string())
-                    .collect(),
-                None => vec![],
-            };
-            let blacklisted_class_codes: Vec<String> =
-                match profile["blacklisted_class_codes"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let blacklisted_vendor_ids: Vec<String> =
-                match profile["blacklisted_vendor_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let blacklisted_product_ids: Vec<String> =
-                match profile["blacklisted_product_ids"].as_array() {
-                    Some(t) => t
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                    None => vec![],
-                };
-            let packages: Option<Vec<String>> = match profile["packages"].as_str() {
-                Some(_) => None,
-                None => Some(
-                    profile["packages"]
-                        .as_array()
-                        .expect("invalid_usb_profile_class_ids")
-                        .into_iter()
-                        .map(|x| x.as_str().unwrap_or_default().to_string())
-                        .collect(),
-                ),
-            };
-            let check_script = profile["check_script"]
-                .as_str()
-                .unwrap_or("false")
-                .to_string();
-            let install_script_value = profile["install_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let install_script = match install_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(install_script_value),
-            };
-            let remove_script_value = profile["remove_script"]
-                .as_str()
-                .unwrap_or_default()
-                .to_string();
-            let remove_script = match remove_script_value.as_str() {
-                "Option::is_none" => None,
-                _ => Some(remove_script_value),
-            };
-            let experimental = profile["experimental"].as_bool().unwrap_or_default();
-            let removable = profile["removable"].as_bool().unwrap_or_default();
-            let veiled = profile["veiled"].as_bool().unwrap_or_default();
-            let priority = profile["priority"].as_i64().unwrap_or_default();
-            // Parse into the Struct
-            let profile_struct = CfhdbUsbProfile {
-                codename,
-                i18n_desc,
-                icon_name,
-                license,
-                class_codes,
-                vendor_ids,
-                product_ids,
-                blacklisted_class_codes,
-                blacklisted_vendor_ids,
-                blacklisted_product_ids,
-                packages,
-                check_script,
-                install_script,
-                remove_script,
-                experimental,
-                removable,
-                veiled,
-                priority: priority as i32,
-            };
-            profiles_array.push(profile_struct);
-            profiles_array.sort_by_key(|x| x.priority);
-        }
+    Ok(data)
+}
+
+// Veiled profiles are left out of matching unless asked for
+pub fn get_visible_usb_profiles(show_veiled: bool) -> Vec<CfhdbUsbProfile> {
+    let mut profiles = match get_usb_profiles_from_url() {
+        Ok(t) => t,
+        Err(e) => exit_with_error("io_error", e),
+    };
+    if !show_veiled {
+        profiles.retain(|x| !x.veiled);
     }
+    profiles
+}
+
+pub fn get_usb_profiles_from_url() -> Result<Vec<CfhdbUsbProfile>, std::io::Error> {
+    let profile_values = match get_local_profile_source(&USB_PROFILE_JSON_URL) {
+        Some(local_path) => load_profile_source(&local_path)?,
+        None => parse_profile_database(&download_usb_profile_database()?)?,
+    };
+    let locale = get_profile_locale();
+    let mut profiles_array: Vec<CfhdbUsbProfile> = profile_values
+        .iter()
+        .map(|profile| CfhdbUsbProfile::from_json(profile, &locale))
+        .collect();
+    profiles_array.sort_by_key(|x| x.priority);
     Ok(profiles_array)
 }